@@ -1,23 +1,222 @@
+use crate::raw::Socket;
 use crate::{Error, Result};
 use nix::sys::socket::{self, AddressFamily, SockAddr, SockFlag, SockType, UnixAddr};
-use std::os::unix::io::FromRawFd;
-use std::os::unix::net::UnixStream;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::io;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
 use std::str::FromStr;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
 
-/// A bus address
-#[derive(Debug, PartialEq)]
-pub(crate) enum Address {
+use crate::{Guid, OwnedFd};
+
+/// The length of the nonce used by the nonce-tcp transport.
+pub(crate) const NONCE_LEN: usize = 16;
+
+/// The backlog of listening sockets we create ourselves.
+const LISTEN_BACKLOG: usize = 128;
+
+/// How long the process of a `unixexec` transport has to exit on its own once disconnected.
+const UNIXEXEC_EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A [D-Bus address].
+///
+/// An address is made of a transport name (e.g. `unix` or `tcp`) and a list of key/value options
+/// specific to that transport. It can be parsed from its textual representation with
+/// [`FromStr`], created with an [`AddressBuilder`] and turned back into its textual
+/// representation with [`Display`]. Values are kept unescaped; escaping only happens when the
+/// address is displayed.
+///
+/// Addresses of transports zbus can't connect to, like `autolaunch:` or `launchd:`, are kept as
+/// plain key/value options: they can be parsed and displayed, but connecting to them fails.
+///
+/// ```
+/// use std::str::FromStr;
+/// use zbus::Address;
+///
+/// let address = Address::from_str("unix:path=/tmp/dbus%20socket,guid=0123456789abcdef0123456789abcdef")?;
+/// assert_eq!(address.transport(), "unix");
+/// assert_eq!(address.get("path"), Some("/tmp/dbus socket"));
+/// assert_eq!(address.guid()?.unwrap().as_str(), "0123456789abcdef0123456789abcdef");
+///
+/// let address = Address::builder("tcp")
+///     .set("host", "localhost")
+///     .set("port", "4142")
+///     .build()?;
+/// assert_eq!(address.to_string(), "tcp:host=localhost,port=4142");
+/// # Ok::<(), zbus::Error>(())
+/// ```
+///
+/// [D-Bus address]: https://dbus.freedesktop.org/doc/dbus-specification.html#addresses
+/// [`FromStr`]: https://doc.rust-lang.org/std/str/trait.FromStr.html
+/// [`AddressBuilder`]: struct.AddressBuilder.html
+/// [`Display`]: https://doc.rust-lang.org/std/fmt/trait.Display.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct Address {
+    transport: String,
+    options: Vec<(String, String)>,
+    kind: Transport,
+}
+
+/// A builder for [`Address`].
+///
+/// The options are kept in the order they are set in, so that the resulting address is displayed
+/// in a predictable way.
+///
+/// [`Address`]: struct.Address.html
+#[derive(Clone, Debug)]
+pub struct AddressBuilder {
+    transport: String,
+    options: Vec<(String, String)>,
+}
+
+/// The transport-specific details of an address
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Transport {
     /// A path on the filesystem
     Path(String),
     /// An abstract path (Linux-only)
     Abstract(String),
+    /// A directory in which to create a randomly named socket (listen-only)
+    Dir(String),
+    /// A directory to create a randomly named socket for, preferably abstract (listen-only)
+    TmpDir(String),
+    /// A randomly named socket in `XDG_RUNTIME_DIR` (listen-only)
+    Runtime,
+    /// A TCP address
+    Tcp(TcpAddress),
+    /// A TCP address, protected by a nonce stored in a file
+    NonceTcp {
+        addr: TcpAddress,
+        nonce_file: String,
+    },
+    /// A process to spawn, talking D-Bus over its stdin/stdout
+    UnixExec {
+        path: String,
+        argv0: Option<String>,
+        args: Vec<String>,
+    },
+    /// A transport we don't support, e.g. `autolaunch` or `launchd`, only kept as key/values
+    Other,
+}
+
+/// A TCP transport address
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TcpAddress {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) family: Option<TcpAddressFamily>,
+}
+
+/// The IP family to restrict a TCP address to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum TcpAddressFamily {
+    Ipv4,
+    Ipv6,
 }
 
 impl Address {
-    pub(crate) fn connect(&self) -> Result<UnixStream> {
-        match self {
-            Address::Path(p) => Ok(UnixStream::connect(p)?),
-            Address::Abstract(p) => {
+    /// Create a builder for an address of the given `transport`.
+    pub fn builder(transport: &str) -> AddressBuilder {
+        AddressBuilder::new(transport)
+    }
+
+    fn new(transport: String, options: Vec<(String, String)>) -> Result<Self> {
+        for name in std::iter::once(&transport).chain(options.iter().map(|(k, _)| k)) {
+            if name.is_empty() || name.contains(|c| ":;,=%".contains(c)) {
+                return Err(Error::Address(format!("invalid address key '{}'", name)));
+            }
+        }
+        for (i, (key, _)) in options.iter().enumerate() {
+            if options[..i].iter().any(|(k, _)| k == key) {
+                return Err(Error::Address(format!("duplicate key '{}'", key)));
+            }
+        }
+        let kind = Transport::new(&transport, &options)?;
+
+        Ok(Address {
+            transport,
+            options,
+            kind,
+        })
+    }
+
+    /// Parse a list of addresses separated by `;`, as found in `DBUS_SESSION_BUS_ADDRESS`.
+    ///
+    /// The addresses are returned in order, which is the order in which they should be tried.
+    ///
+    /// Fails if any of the addresses is invalid.
+    pub fn from_list(addresses: &str) -> Result<Vec<Self>> {
+        Self::parse_list(addresses)?
+            .into_iter()
+            .map(|(_, address)| address)
+            .collect()
+    }
+
+    /// Parse a list of addresses separated by `;`, each one on its own.
+    ///
+    /// Each address comes with its textual representation, so that the ones failing to parse can
+    /// be reported while the others are still tried.
+    pub(crate) fn parse_list(addresses: &str) -> Result<Vec<(&str, Result<Self>)>> {
+        let addresses: Vec<_> = addresses
+            .split(';')
+            .filter(|address| !address.is_empty())
+            .map(|address| (address, Address::from_str(address)))
+            .collect();
+        if addresses.is_empty() {
+            return Err(Error::Address("empty address list".into()));
+        }
+
+        Ok(addresses)
+    }
+
+    /// The name of the transport, e.g. `unix` or `tcp`.
+    pub fn transport(&self) -> &str {
+        &self.transport
+    }
+
+    /// The (unescaped) value of the option `key`, if present.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Iterate over all the options of the address, as unescaped key/value pairs.
+    pub fn options(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.options.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// The GUID of the server listening on this address, if given by the `guid` option.
+    ///
+    /// Returns `Err(`[`Error::InvalidGUID`]`)` if the `guid` option is not a well-formed GUID.
+    ///
+    /// [`Error::InvalidGUID`]: enum.Error.html#variant.InvalidGUID
+    pub fn guid(&self) -> Result<Option<Guid>> {
+        self.get("guid").map(Guid::try_from).transpose()
+    }
+
+    /// Connect to the address.
+    ///
+    /// The returned socket is put in non-blocking mode if `nonblocking` is `true`.
+    pub(crate) fn connect(&self, nonblocking: bool) -> Result<Box<dyn Socket>> {
+        match &self.kind {
+            Transport::Path(p) => {
+                let stream = UnixStream::connect(p)?;
+                stream.set_nonblocking(nonblocking)?;
+
+                Ok(Box::new(stream))
+            }
+            Transport::Abstract(p) => {
                 // FIXME: Use std API once std supports abstract sockets:
                 //
                 // https://github.com/rust-lang/rust/issues/42048
@@ -28,50 +227,487 @@ impl Address {
                     SockFlag::empty(),
                     None,
                 )?;
+                let stream = unsafe { UnixStream::from_raw_fd(raw) };
                 socket::connect(raw, &addr)?;
+                stream.set_nonblocking(nonblocking)?;
+
+                Ok(Box::new(stream))
+            }
+            Transport::Dir(_) | Transport::TmpDir(_) | Transport::Runtime => Err(Error::Address(
+                format!("'{}' is a listen-only address", self),
+            )),
+            Transport::Tcp(addr) | Transport::NonceTcp { addr, .. } => {
+                let stream = addr.connect()?;
+                stream.set_nonblocking(nonblocking)?;
+
+                Ok(Box::new(stream))
+            }
+            Transport::UnixExec { path, argv0, args } => {
+                let (stream, child_stream) = UnixStream::pair()?;
+                let child_stdout = child_stream.try_clone()?;
+                let child = Command::new(path)
+                    .arg0(argv0.as_ref().unwrap_or(path))
+                    .args(args)
+                    .stdin(unsafe { Stdio::from_raw_fd(child_stream.into_raw_fd()) })
+                    .stdout(unsafe { Stdio::from_raw_fd(child_stdout.into_raw_fd()) })
+                    .spawn()?;
+                stream.set_nonblocking(nonblocking)?;
+
+                Ok(Box::new(UnixExecStream {
+                    stream,
+                    child: Some(child),
+                }))
+            }
+            Transport::Other => Err(Error::Address(format!(
+                "unsupported transport '{}'",
+                self.transport
+            ))),
+        }
+    }
+
+    /// Bind a socket on the address and start listening on it.
+    ///
+    /// Besides the connectable `unix:path=` and `unix:abstract=` forms, the listen-only
+    /// `unix:dir=`, `unix:tmpdir=` and `unix:runtime=yes` forms are supported. For those, a socket
+    /// with a random name is created, and [`Listener::address`] reports the resulting connectable
+    /// address. Only `unix` addresses can currently be listened on.
+    ///
+    /// [`Listener::address`]: struct.Listener.html#method.address
+    pub fn listen(&self) -> Result<Listener> {
+        let mut builder = Address::builder("unix");
+        let (listener, unlink) = match &self.kind {
+            Transport::Path(p) => {
+                builder = builder.set("path", p);
+
+                (UnixListener::bind(p)?, Some(PathBuf::from(p)))
+            }
+            Transport::Dir(dir) => {
+                let path = Path::new(dir).join(random_socket_name());
+                builder = builder.set("path", &path.to_string_lossy());
+
+                (UnixListener::bind(&path)?, Some(path))
+            }
+            Transport::Runtime => {
+                let dir = env::var("XDG_RUNTIME_DIR")
+                    .map_err(|_| Error::Address("XDG_RUNTIME_DIR is not set".into()))?;
+                let path = Path::new(&dir).join(random_socket_name());
+                builder = builder.set("path", &path.to_string_lossy());
+
+                (UnixListener::bind(&path)?, Some(path))
+            }
+            Transport::Abstract(p) => {
+                builder = builder.set("abstract", p);
+
+                (bind_abstract(p)?, None)
+            }
+            Transport::TmpDir(dir) => {
+                // Like the reference implementation on Linux, we use an abstract socket so that
+                // nothing needs to be cleaned up on the filesystem.
+                let path = Path::new(dir).join(random_socket_name());
+                let path = path.to_string_lossy();
+                builder = builder.set("abstract", &path);
+
+                (bind_abstract(&path)?, None)
+            }
+            _ => {
+                return Err(Error::Address(format!(
+                    "can not listen on '{}', only unix addresses are supported",
+                    self
+                )))
+            }
+        };
+        if let Some(guid) = self.get("guid") {
+            builder = builder.set("guid", guid);
+        }
+
+        Ok(Listener {
+            listener,
+            address: builder.build()?,
+            unlink,
+        })
+    }
+
+    /// Read the nonce that needs to be sent before the authentication, if this address has one.
+    pub(crate) fn read_nonce(&self) -> Result<Option<Vec<u8>>> {
+        match &self.kind {
+            Transport::NonceTcp { nonce_file, .. } => {
+                let nonce = std::fs::read(nonce_file)?;
+                if nonce.len() != NONCE_LEN {
+                    return Err(Error::Address(format!(
+                        "nonce file '{}' has an invalid size",
+                        nonce_file
+                    )));
+                }
+
+                Ok(Some(nonce))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl TcpAddress {
+    fn from_options(mut options: HashMap<&str, String>) -> Result<Self> {
+        // Like the reference implementation, we default to connecting to the local host.
+        let host = options
+            .remove("host")
+            .unwrap_or_else(|| "localhost".to_owned());
+        let port = options
+            .remove("port")
+            .ok_or_else(|| Error::Address("tcp address is missing port".into()))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| Error::Address(format!("invalid tcp port '{}'", port)))?;
+        let family = match options.remove("family").as_deref() {
+            Some("ipv4") => Some(TcpAddressFamily::Ipv4),
+            Some("ipv6") => Some(TcpAddressFamily::Ipv6),
+            Some(family) => {
+                return Err(Error::Address(format!(
+                    "invalid tcp address family '{}'",
+                    family
+                )))
+            }
+            None => None,
+        };
+
+        Ok(TcpAddress { host, port, family })
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let addrs = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .filter(|addr| match self.family {
+                Some(TcpAddressFamily::Ipv4) => addr.is_ipv4(),
+                Some(TcpAddressFamily::Ipv6) => addr.is_ipv6(),
+                None => true,
+            });
+
+        // Try all the resolved addresses in turn, and report the last error if none works.
+        let mut last_err = None;
+        for addr in addrs {
+            match TcpStream::connect(addr) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.map(Error::Io).unwrap_or_else(|| {
+            Error::Address(format!("no usable address found for '{}'", self.host))
+        }))
+    }
+}
+
+/// A listening socket, as created by [`Address::listen`].
+///
+/// If the socket was created on the filesystem, it is removed when the `Listener` is dropped.
+///
+/// [`Address::listen`]: struct.Address.html#method.listen
+#[derive(Debug)]
+pub struct Listener {
+    listener: UnixListener,
+    address: Address,
+    unlink: Option<PathBuf>,
+}
+
+impl Listener {
+    /// The address clients can connect to.
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// Accept a new incoming connection.
+    ///
+    /// The returned stream can then be given to [`Connection::new_unix_server`].
+    ///
+    /// [`Connection::new_unix_server`]: struct.Connection.html#method.new_unix_server
+    pub fn accept(&self) -> Result<UnixStream> {
+        self.listener
+            .accept()
+            .map(|(stream, _)| stream)
+            .map_err(Error::Io)
+    }
+
+    /// Move the listening socket into or out of non-blocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.listener
+            .set_nonblocking(nonblocking)
+            .map_err(Error::Io)
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Some(path) = &self.unlink {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn random_socket_name() -> String {
+    format!("dbus-{:08x}{:08x}", fastrand::u32(..), fastrand::u32(..))
+}
+
+fn bind_abstract(path: &str) -> Result<UnixListener> {
+    // FIXME: Use std API once std supports abstract sockets:
+    //
+    // https://github.com/rust-lang/rust/issues/42048
+    let addr = SockAddr::Unix(UnixAddr::new_abstract(path.as_bytes())?);
+    let raw = socket::socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::empty(),
+        None,
+    )?;
+    let listener = unsafe { UnixListener::from_raw_fd(raw) };
+    socket::bind(raw, &addr)?;
+    socket::listen(raw, LISTEN_BACKLOG)?;
+
+    Ok(listener)
+}
+
+/// The socket of a `unixexec` transport.
+///
+/// This is an ordinary `UnixStream`, that also takes care of terminating the spawned process once
+/// the connection is dropped.
+#[derive(Debug)]
+struct UnixExecStream {
+    stream: UnixStream,
+    // Only `None` once dropped
+    child: Option<Child>,
+}
+
+impl Socket for UnixExecStream {
+    fn can_pass_unix_fd(&self) -> bool {
+        self.stream.can_pass_unix_fd()
+    }
+
+    fn recvmsg(&mut self, buffer: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+        self.stream.recvmsg(buffer)
+    }
+
+    fn sendmsg(&mut self, buffer: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        self.stream.sendmsg(buffer, fds)
+    }
+}
+
+impl AsRawFd for UnixExecStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl Drop for UnixExecStream {
+    fn drop(&mut self) {
+        // Let the child know we're gone, and give it a chance to exit cleanly on EOF (e.g. for ssh
+        // to close its session). Then make sure it doesn't linger (or become a zombie).
+        let _ = self.stream.shutdown(Shutdown::Both);
+        let mut child = match self.child.take() {
+            Some(child) => child,
+            None => return,
+        };
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+
+        // Don't block the dropping thread (possibly running an executor) while the child exits.
+        let _ = thread::Builder::new()
+            .name("zbus::unixexec".into())
+            .spawn(move || {
+                let deadline = Instant::now() + UNIXEXEC_EXIT_TIMEOUT;
+                while let Ok(None) = child.try_wait() {
+                    if Instant::now() >= deadline {
+                        let _ = child.kill();
+                        break;
+                    }
+                    sleep(Duration::from_millis(10));
+                }
+                let _ = child.wait();
+            });
+    }
+}
 
-                Ok(unsafe { UnixStream::from_raw_fd(raw) })
+impl Transport {
+    fn new(transport: &str, options: &[(String, String)]) -> Result<Self> {
+        let mut options: HashMap<&str, String> = options
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.clone()))
+            .collect();
+
+        match transport {
+            "unix" => {
+                if let Some(path) = options.remove("path") {
+                    Ok(Transport::Path(path))
+                } else if let Some(abs) = options.remove("abstract") {
+                    Ok(Transport::Abstract(abs))
+                } else if let Some(dir) = options.remove("dir") {
+                    Ok(Transport::Dir(dir))
+                } else if let Some(dir) = options.remove("tmpdir") {
+                    Ok(Transport::TmpDir(dir))
+                } else if let Some(runtime) = options.remove("runtime") {
+                    match runtime.as_str() {
+                        "yes" => Ok(Transport::Runtime),
+                        _ => Err(Error::Address(format!(
+                            "invalid unix runtime value '{}'",
+                            runtime
+                        ))),
+                    }
+                } else {
+                    Err(Error::Address(
+                        "unix address is missing path or abstract".to_owned(),
+                    ))
+                }
+            }
+            "tcp" => TcpAddress::from_options(options).map(Transport::Tcp),
+            "nonce-tcp" => {
+                let nonce_file = options.remove("noncefile").ok_or_else(|| {
+                    Error::Address("nonce-tcp address is missing noncefile".into())
+                })?;
+
+                TcpAddress::from_options(options)
+                    .map(|addr| Transport::NonceTcp { addr, nonce_file })
+            }
+            "unixexec" => {
+                let path = options
+                    .remove("path")
+                    .ok_or_else(|| Error::Address("unixexec address is missing path".into()))?;
+                let argv0 = options.remove("argv0");
+                let mut args = vec![];
+                while let Some(arg) = options.remove(format!("argv{}", args.len() + 1).as_str()) {
+                    args.push(arg);
+                }
+
+                Ok(Transport::UnixExec { path, argv0, args })
             }
+            // Other transports may only be parsed, displayed and passed around.
+            _ => Ok(Transport::Other),
         }
     }
 }
 
+impl AddressBuilder {
+    /// Create a builder for an address of the given `transport`.
+    pub fn new(transport: &str) -> Self {
+        AddressBuilder {
+            transport: transport.to_owned(),
+            options: vec![],
+        }
+    }
+
+    /// Set the option `key` to the (unescaped) `value`, replacing any previous value.
+    pub fn set(mut self, key: &str, value: &str) -> Self {
+        match self.options.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_owned(),
+            None => self.options.push((key.to_owned(), value.to_owned())),
+        }
+
+        self
+    }
+
+    /// Set the `guid` option.
+    pub fn guid(self, guid: &Guid) -> Self {
+        self.set("guid", guid.as_str())
+    }
+
+    /// Build the address.
+    ///
+    /// Returns `Err(`[`Error::Address`]`)` if the options are not valid for the transport.
+    ///
+    /// [`Error::Address`]: enum.Error.html#variant.Address
+    pub fn build(self) -> Result<Address> {
+        Address::new(self.transport, self.options)
+    }
+}
+
 impl FromStr for Address {
     type Err = Error;
 
-    /// Parse a D-BUS address and return its path if we recognize it
+    /// Parse a single D-Bus address
+    ///
+    /// Lists of addresses separated by `;` are rejected, use [`Address::from_list`] for those.
     fn from_str(address: &str) -> Result<Self> {
-        // Options are given separated by commas
-        let first = address.split(',').next().unwrap();
-        let parts = first.split(':').collect::<Vec<&str>>();
-        if parts.len() != 2 {
-            return Err(Error::Address("address has no colon".into()));
+        if address.contains(';') {
+            return Err(Error::Address(
+                "';' separates addresses, use `Address::from_list` to parse a list".into(),
+            ));
         }
-        if parts[0] != "unix" {
-            return Err(Error::Address(format!(
-                "unsupported transport '{}'",
-                parts[0]
-            )));
+        let col = address
+            .find(':')
+            .ok_or_else(|| Error::Address("address has no colon".into()))?;
+
+        // Options are given separated by commas
+        let mut options = vec![];
+        for kv in address[col + 1..].split(',').filter(|kv| !kv.is_empty()) {
+            let eq = kv
+                .find('=')
+                .ok_or_else(|| Error::Address("address is missing '='".into()))?;
+            options.push((kv[..eq].to_owned(), unescape(&kv[eq + 1..])?));
         }
 
-        let pathparts = parts[1].split('=').collect::<Vec<&str>>();
-        if pathparts.len() != 2 {
-            return Err(Error::Address("address is missing '='".into()));
+        Address::new(address[..col].to_owned(), options)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.transport)?;
+        for (i, (key, value)) in self.options.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}=", key)?;
+            for b in value.bytes() {
+                // The set of bytes that may be left unescaped, as per the specification.
+                if b.is_ascii_alphanumeric() || b"-_/.\\*".contains(&b) {
+                    write!(f, "{}", b as char)?;
+                } else {
+                    write!(f, "%{:02x}", b)?;
+                }
+            }
         }
-        match pathparts[0] {
-            "path" => Ok(Address::Path(pathparts[1].to_owned())),
-            "abstract" => Ok(Address::Abstract(pathparts[1].to_owned())),
-            _ => Err(Error::Address(
-                "unix address is missing path or abstract".to_owned(),
-            )),
+
+        Ok(())
+    }
+}
+
+/// Decode the percent-encoded bytes in an address value.
+fn unescape(value: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(b) = iter.next() {
+        if b != b'%' {
+            bytes.push(b);
+            continue;
         }
+
+        let hex = [iter.next(), iter.next()];
+        let decoded = match hex {
+            [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        bytes.push(
+            decoded.ok_or_else(|| {
+                Error::Address(format!("invalid percent-encoding in '{}'", value))
+            })?,
+        );
     }
+
+    String::from_utf8(bytes)
+        .map_err(|_| Error::Address(format!("'{}' is not a valid UTF-8 value", value)))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Address;
-    use crate::Error;
+    use super::{Address, TcpAddress, TcpAddressFamily, Transport};
+    use crate::{Error, Guid};
+    use std::os::unix::net::UnixStream;
     use std::str::FromStr;
 
     #[test]
@@ -81,16 +717,291 @@ mod tests {
             _ => panic!(),
         }
         match Address::from_str("tcp:localhost").unwrap_err() {
-            Error::Address(e) => assert_eq!(e, "unsupported transport 'tcp'"),
+            Error::Address(e) => assert_eq!(e, "address is missing '='"),
+            _ => panic!(),
+        }
+        match Address::from_str("tcp:host=localhost").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "tcp address is missing port"),
+            _ => panic!(),
+        }
+        match Address::from_str("tcp:host=localhost,port=4142,family=ipv5").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "invalid tcp address family 'ipv5'"),
             _ => panic!(),
         }
+        let address = Address::from_str("foo:bar=baz").unwrap();
+        assert_eq!(address.kind, Transport::Other);
+        assert_eq!(address.get("bar"), Some("baz"));
+        match address.connect(false) {
+            Err(Error::Address(e)) => assert_eq!(e, "unsupported transport 'foo'"),
+            _ => panic!(),
+        }
+        assert_eq!(
+            Address::from_str("autolaunch:").unwrap().kind,
+            Transport::Other
+        );
         assert_eq!(
-            Address::Path("/tmp/dbus-foo".into()),
-            Address::from_str("unix:path=/tmp/dbus-foo").unwrap()
+            Transport::Path("/tmp/dbus-foo".into()),
+            Address::from_str("unix:path=/tmp/dbus-foo").unwrap().kind
         );
         assert_eq!(
-            Address::Path("/tmp/dbus-foo".into()),
-            Address::from_str("unix:path=/tmp/dbus-foo,guid=123").unwrap()
+            Transport::Path("/tmp/dbus-foo".into()),
+            Address::from_str("unix:path=/tmp/dbus-foo,guid=123")
+                .unwrap()
+                .kind
         );
+        assert_eq!(
+            Transport::Tcp(TcpAddress {
+                host: "localhost".into(),
+                port: 4142,
+                family: None,
+            }),
+            Address::from_str("tcp:host=localhost,port=4142")
+                .unwrap()
+                .kind
+        );
+        assert_eq!(
+            Transport::Tcp(TcpAddress {
+                host: "127.0.0.1".into(),
+                port: 4142,
+                family: Some(TcpAddressFamily::Ipv4),
+            }),
+            Address::from_str("tcp:host=127.0.0.1,port=4142,family=ipv4")
+                .unwrap()
+                .kind
+        );
+        match Address::from_str("nonce-tcp:host=localhost,port=4142").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "nonce-tcp address is missing noncefile"),
+            _ => panic!(),
+        }
+        assert_eq!(
+            Transport::NonceTcp {
+                addr: TcpAddress {
+                    host: "localhost".into(),
+                    port: 4142,
+                    family: None,
+                },
+                nonce_file: "/tmp/dbus-nonce".into(),
+            },
+            Address::from_str("nonce-tcp:host=localhost,port=4142,noncefile=/tmp/dbus-nonce")
+                .unwrap()
+                .kind
+        );
+        assert_eq!(
+            Transport::Dir("/tmp".into()),
+            Address::from_str("unix:dir=/tmp").unwrap().kind
+        );
+        assert_eq!(
+            Transport::TmpDir("/tmp".into()),
+            Address::from_str("unix:tmpdir=/tmp").unwrap().kind
+        );
+        assert_eq!(
+            Transport::Runtime,
+            Address::from_str("unix:runtime=yes").unwrap().kind
+        );
+        match Address::from_str("unix:runtime=no").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "invalid unix runtime value 'no'"),
+            _ => panic!(),
+        }
+        match Address::from_str("unixexec:argv0=ssh").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "unixexec address is missing path"),
+            _ => panic!(),
+        }
+        assert_eq!(
+            Transport::UnixExec {
+                path: "ssh".into(),
+                argv0: None,
+                args: vec!["host".into(), "systemd-stdio-bridge".into()],
+            },
+            Address::from_str("unixexec:path=ssh,argv1=host,argv2=systemd-stdio-bridge")
+                .unwrap()
+                .kind
+        );
+    }
+
+    #[test]
+    fn unescape_dbus_addresses() {
+        assert_eq!(
+            Transport::Path("/tmp/dbus test,;=".into()),
+            Address::from_str("unix:path=/tmp/dbus%20test%2c%3B%3d")
+                .unwrap()
+                .kind
+        );
+        match Address::from_str("unix:path=/tmp/dbus%2").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "invalid percent-encoding in '/tmp/dbus%2'"),
+            _ => panic!(),
+        }
+        match Address::from_str("unix:path=/tmp/dbus%zz").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "invalid percent-encoding in '/tmp/dbus%zz'"),
+            _ => panic!(),
+        }
+        match Address::from_str("unix:path=%ff").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "'%ff' is not a valid UTF-8 value"),
+            _ => panic!(),
+        }
+        match Address::from_str("unix:path=/a,path=/b").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "duplicate key 'path'"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn parse_dbus_address_lists() {
+        assert_eq!(
+            vec![
+                Transport::Path("/tmp/dbus-a".into()),
+                Transport::Abstract("/tmp/dbus-b".into()),
+            ],
+            Address::from_list("unix:path=/tmp/dbus-a;;unix:abstract=/tmp/dbus-b;")
+                .unwrap()
+                .into_iter()
+                .map(|a| a.kind)
+                .collect::<Vec<_>>()
+        );
+        match Address::from_list(";").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "empty address list"),
+            _ => panic!(),
+        }
+        match Address::from_list("unix:path=/tmp/dbus-a;foo").unwrap_err() {
+            Error::Address(e) => assert_eq!(e, "address has no colon"),
+            _ => panic!(),
+        }
+        let addresses = Address::parse_list("unix:path=/tmp/dbus-a;foo").unwrap();
+        assert_eq!(addresses[0].0, "unix:path=/tmp/dbus-a");
+        assert!(addresses[0].1.is_ok());
+        assert_eq!(addresses[1].0, "foo");
+        assert!(matches!(addresses[1].1, Err(Error::Address(_))));
+        // A list isn't silently parsed as a single address
+        match Address::from_str("unix:path=/tmp/dbus-a;unix:path=/tmp/dbus-b").unwrap_err() {
+            Error::Address(e) => assert_eq!(
+                e,
+                "';' separates addresses, use `Address::from_list` to parse a list"
+            ),
+            _ => panic!(),
+        }
+        assert!(Address::from_str("unix:path=/tmp/dbus-a;").is_err());
+        assert_eq!(
+            Address::from_str("unix:path=/tmp/dbus%3ba").unwrap().kind,
+            Transport::Path("/tmp/dbus;a".into())
+        );
+    }
+
+    #[test]
+    fn display_dbus_addresses() {
+        for address in &[
+            "unix:path=/tmp/dbus-foo",
+            "unix:path=/tmp/dbus%20test%2c%3b%3d%25,guid=0123456789abcdef0123456789abcdef",
+            "nonce-tcp:host=localhost,port=4142,noncefile=/tmp/dbus-nonce",
+            "unixexec:path=ssh,argv1=host,argv2=systemd-stdio-bridge%20--user",
+            "autolaunch:",
+            "launchd:env=DBUS_LAUNCHD_SESSION_BUS_SOCKET",
+        ] {
+            let parsed = Address::from_str(address).unwrap();
+            assert_eq!(parsed.to_string(), *address);
+            assert_eq!(Address::from_str(&parsed.to_string()).unwrap(), parsed);
+        }
+        assert_eq!(
+            Address::from_str("unix:path=/tmp/%64bus-%C3%A9")
+                .unwrap()
+                .to_string(),
+            "unix:path=/tmp/dbus-%c3%a9"
+        );
+    }
+
+    #[test]
+    fn build_dbus_addresses() {
+        let guid = Guid::generate();
+        let address = Address::builder("unix")
+            .set("path", "/tmp/dbus foo")
+            .guid(&guid)
+            .set("path", "/tmp/dbus bar")
+            .build()
+            .unwrap();
+        assert_eq!(address.transport(), "unix");
+        assert_eq!(address.get("path"), Some("/tmp/dbus bar"));
+        assert_eq!(address.get("abstract"), None);
+        assert_eq!(
+            address.options().collect::<Vec<_>>(),
+            vec![("path", "/tmp/dbus bar"), ("guid", guid.as_str())]
+        );
+        assert_eq!(address.guid().unwrap(), Some(guid.clone()));
+        assert_eq!(
+            address.to_string(),
+            format!("unix:path=/tmp/dbus%20bar,guid={}", guid)
+        );
+
+        match Address::builder("tcp")
+            .set("host", "localhost")
+            .build()
+            .unwrap_err()
+        {
+            Error::Address(e) => assert_eq!(e, "tcp address is missing port"),
+            _ => panic!(),
+        }
+        match Address::builder("unix")
+            .set("pa,th", "/tmp")
+            .build()
+            .unwrap_err()
+        {
+            Error::Address(e) => assert_eq!(e, "invalid address key 'pa,th'"),
+            _ => panic!(),
+        }
+        assert!(matches!(
+            Address::from_str("unix:path=/tmp/dbus-foo,guid=123")
+                .unwrap()
+                .guid(),
+            Err(Error::InvalidGUID)
+        ));
+    }
+
+    #[test]
+    fn listen_dbus_addresses() {
+        let dir = std::env::temp_dir();
+        let address = Address::builder("unix")
+            .set("dir", &dir.to_string_lossy())
+            .build()
+            .unwrap();
+        let listener = address.listen().unwrap();
+        let path = listener.address().get("path").unwrap().to_owned();
+        assert!(std::path::Path::new(&path).starts_with(&dir));
+        UnixStream::connect(&path).unwrap();
+        listener.accept().unwrap();
+        drop(listener);
+        assert!(!std::path::Path::new(&path).exists());
+
+        let guid = Guid::generate();
+        let address = Address::builder("unix")
+            .set("tmpdir", "/tmp")
+            .guid(&guid)
+            .build()
+            .unwrap();
+        let listener = address.listen().unwrap();
+        assert!(listener
+            .address()
+            .get("abstract")
+            .unwrap()
+            .starts_with("/tmp/dbus-"));
+        assert_eq!(listener.address().guid().unwrap(), Some(guid));
+        listener.address().connect(false).unwrap();
+        listener.accept().unwrap();
+
+        match Address::from_str("unix:tmpdir=/tmp")
+            .unwrap()
+            .connect(false)
+        {
+            Err(Error::Address(e)) => assert_eq!(e, "'unix:tmpdir=/tmp' is a listen-only address"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn drop_unixexec_stream() {
+        // The process ignores EOF, but dropping its socket doesn't wait for it to be killed
+        let socket = Address::from_str("unixexec:path=/bin/sleep,argv1=10")
+            .unwrap()
+            .connect(false)
+            .unwrap();
+        let start = std::time::Instant::now();
+        drop(socket);
+        assert!(start.elapsed() < super::UNIXEXEC_EXIT_TIMEOUT);
     }
 }
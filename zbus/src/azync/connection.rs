@@ -0,0 +1,868 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Wake, Waker};
+
+use async_io::Async;
+use futures_core::{ready, stream::Stream};
+use futures_util::future::poll_fn;
+use nix::poll::PollFlags;
+use once_cell::sync::OnceCell;
+
+use crate::connection::{
+    closed_error, disconnected_signal, is_disconnection, set_serial, IncomingMessages,
+    DEFAULT_MAX_QUEUED,
+};
+use crate::handshake::{Authenticated, ClientHandshake, ServerHandshake};
+use crate::raw::{Connection as RawConnection, Socket};
+use crate::{Error, Guid, Message, MessageType, OverflowPolicy, Result};
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct ConnectionInner {
+    server_guid: Guid,
+    cap_unix_fd: bool,
+    unique_name: OnceCell<String>,
+
+    // Declared before `raw_conn`, so that it's dropped before the socket gets closed
+    #[derivative(Debug = "ignore")]
+    watch: Async<Watch>,
+    raw_conn: Mutex<RawConnection<Box<dyn Socket>>>,
+    // Serial number for next outgoing message
+    serial: AtomicU32,
+
+    // Incoming messages, and the replies tasks are waiting for
+    incoming: Mutex<IncomingMessages>,
+
+    // The tasks waiting for the socket to be readable or writable. The socket is watched with the
+    // single waker of each set, which wakes them all up: tasks watching it with their own waker
+    // would keep replacing, and thus waking, each other.
+    readers: Waiters,
+    writers: Waiters,
+
+    // Max number of messages to queue
+    max_queued: AtomicUsize,
+
+    closed: AtomicBool,
+    // Whether the `Disconnected` signal was handed out already
+    disconnected: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Waiters {
+    wakers: Arc<Wakers>,
+    // The waker of `wakers`, created once so that the reactor recognizes it as the same waker
+    waker: Waker,
+}
+
+impl Waiters {
+    fn new() -> Self {
+        let wakers = Arc::new(Wakers::default());
+        let waker = Waker::from(wakers.clone());
+
+        Self { wakers, waker }
+    }
+
+    fn wake_all(&self) {
+        self.wakers.wake_all();
+    }
+}
+
+#[derive(Debug, Default)]
+struct Wakers(Mutex<Vec<Waker>>);
+
+impl Wakers {
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.0.lock().expect("lock poisoned");
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    fn wake_all(&self) {
+        let wakers = std::mem::take(&mut *self.0.lock().expect("lock poisoned"));
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Wake for Wakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_all();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_all();
+    }
+}
+
+// The file descriptor of a socket, only used to wait for the socket to be ready.
+//
+// The socket itself is owned by the raw connection or the handshake.
+#[derive(Debug)]
+struct Watch(RawFd);
+
+impl AsRawFd for Watch {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+// Start watching the socket, switching it to non-blocking mode
+fn watch(socket: &dyn Socket) -> Result<Async<Watch>> {
+    Ok(Async::new(Watch(socket.as_raw_fd()))?)
+}
+
+// Register the task to be woken up once the socket is ready for `flags`
+fn poll_ready(watch: &Async<Watch>, flags: PollFlags, cx: &mut Context<'_>) -> Poll<Result<()>> {
+    let ready = if flags.contains(PollFlags::POLLOUT) {
+        watch.poll_writable(cx)
+    } else {
+        watch.poll_readable(cx)
+    };
+
+    ready.map_err(Error::from)
+}
+
+// Call `op` until it doesn't fail with `WouldBlock` anymore, or the socket isn't ready for `flags`
+fn poll_io<T>(
+    watch: &Async<Watch>,
+    flags: PollFlags,
+    cx: &mut Context<'_>,
+    mut op: impl FnMut() -> Result<T>,
+) -> Poll<Result<T>> {
+    loop {
+        match op() {
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                ready!(poll_ready(watch, flags, cx))?;
+            }
+            res => return Poll::Ready(res),
+        }
+    }
+}
+
+async fn client_handshake(
+    mut handshake: ClientHandshake<Box<dyn Socket>>,
+) -> Result<Authenticated<Box<dyn Socket>>> {
+    let watch = watch(handshake.socket())?;
+    poll_fn(|cx| loop {
+        match handshake.advance_handshake() {
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                ready!(poll_ready(&watch, handshake.wait_flags(), cx))?;
+            }
+            res => return Poll::Ready(res),
+        }
+    })
+    .await?;
+
+    Ok(handshake.try_finish().unwrap_or_else(|_| unreachable!()))
+}
+
+async fn server_handshake(
+    mut handshake: ServerHandshake<Box<dyn Socket>>,
+) -> Result<Authenticated<Box<dyn Socket>>> {
+    let watch = watch(handshake.socket())?;
+    poll_fn(|cx| loop {
+        match handshake.advance_handshake() {
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                ready!(poll_ready(&watch, handshake.wait_flags(), cx))?;
+            }
+            res => return Poll::Ready(res),
+        }
+    })
+    .await?;
+
+    Ok(handshake.try_finish().unwrap_or_else(|_| unreachable!()))
+}
+
+/// An asynchronous D-Bus connection.
+///
+/// This is the asynchronous counterpart of [`zbus::Connection`]. Its methods return futures that
+/// can be run on any executor: the socket is watched for readiness by a reactor running in its own
+/// thread.
+///
+/// Incoming messages can be received either through [`receive_message`], or by using the
+/// connection as a [`Stream`] of messages. Replies to method calls made through [`call_method`]
+/// are never returned by either, but handed over to the caller instead, so several method calls
+/// can be awaited at the same time. Replies arriving after their call future was dropped are
+/// dropped as well.
+///
+/// Once the peer closes the connection (or it breaks), the local
+/// `org.freedesktop.DBus.Local.Disconnected` signal is received, and the stream of messages ends.
+///
+/// Unlike `zbus::Connection`, method calls have no timeout (race them against a timer of your
+/// executor instead), there are no message filters, and the connection can't be closed
+/// explicitly: the socket is closed once the last clone of the connection is dropped.
+///
+/// `Connection` implements [`Clone`] and cloning it is a very cheap operation, as the underlying
+/// data is not cloned. It's also `Send` and `Sync`.
+///
+/// # Example
+///
+/// ```no_run
+///# use std::error::Error;
+///#
+/// async_io::block_on(async {
+///     let conn = zbus::azync::Connection::new_session().await?;
+///     let reply = conn
+///         .call_method(
+///             Some("org.freedesktop.DBus"),
+///             "/org/freedesktop/DBus",
+///             Some("org.freedesktop.DBus"),
+///             "GetId",
+///             &(),
+///         )
+///         .await?;
+///     let id: String = reply.body()?;
+///     println!("Bus ID: {}", id);
+///
+///     Ok::<_, Box<dyn Error + Send + Sync>>(())
+/// })?;
+///# Ok::<_, Box<dyn Error + Send + Sync>>(())
+/// ```
+///
+/// [`zbus::Connection`]: ../struct.Connection.html
+/// [`receive_message`]: struct.Connection.html#method.receive_message
+/// [`call_method`]: struct.Connection.html#method.call_method
+/// [`Stream`]: https://docs.rs/futures-core/0.3/futures_core/stream/trait.Stream.html
+/// [`Clone`]: https://doc.rust-lang.org/std/clone/trait.Clone.html
+#[derive(Debug, Clone)]
+pub struct Connection(Arc<ConnectionInner>);
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.watch.get_ref().0
+    }
+}
+
+impl Connection {
+    /// Create and open a D-Bus connection from a `UnixStream`.
+    ///
+    /// The connection may either be set up for a *bus* connection, or not (for peer-to-peer
+    /// communications). The stream is switched to non-blocking mode.
+    pub async fn new_unix_client(stream: UnixStream, bus_connection: bool) -> Result<Self> {
+        let handshake = ClientHandshake::new(Box::new(stream) as Box<dyn Socket>);
+
+        Self::new_client(handshake, bus_connection).await
+    }
+
+    /// Create a `Connection` to the session/user message bus.
+    pub async fn new_session() -> Result<Self> {
+        Self::new_client(ClientHandshake::new_session_nonblock()?, true).await
+    }
+
+    /// Create a `Connection` to the system-wide message bus.
+    pub async fn new_system() -> Result<Self> {
+        Self::new_client(ClientHandshake::new_system_nonblock()?, true).await
+    }
+
+    /// Create a `Connection` for the given [D-Bus address].
+    ///
+    /// [D-Bus address]: https://dbus.freedesktop.org/doc/dbus-specification.html#addresses
+    pub async fn new_for_address(address: &str, bus_connection: bool) -> Result<Self> {
+        let handshake = ClientHandshake::new_for_address_nonblock(address)?;
+
+        Self::new_client(handshake, bus_connection).await
+    }
+
+    /// Create a server `Connection` for the given `UnixStream` and the server `guid`.
+    ///
+    /// The connection will wait for incoming client authentication handshake & negotiation messages,
+    /// for peer-to-peer communications. The stream is switched to non-blocking mode.
+    pub async fn new_unix_server(stream: UnixStream, guid: &Guid) -> Result<Self> {
+        let handshake = ServerHandshake::with_peer_credentials(
+            Box::new(stream) as Box<dyn Socket>,
+            guid.clone(),
+        );
+        let auth = server_handshake(handshake).await?;
+
+        Self::new_authenticated(auth)
+    }
+
+    /// Create a `Connection` from an already authenticated socket.
+    ///
+    /// The socket is switched to non-blocking mode. If the aim is to initialize a client *bus*
+    /// connection, you need to send the [client hello] and assign the resulting unique name using
+    /// [`set_unique_name`] before doing anything else.
+    ///
+    /// [client hello]: ../fdo/struct.DBusProxy.html#method.hello
+    /// [`set_unique_name`]: struct.Connection.html#method.set_unique_name
+    pub fn new_authenticated(auth: Authenticated<Box<dyn Socket>>) -> Result<Self> {
+        Ok(Self(Arc::new(ConnectionInner {
+            watch: watch(auth.conn.socket())?,
+            raw_conn: Mutex::new(auth.conn),
+            server_guid: auth.server_guid,
+            cap_unix_fd: auth.cap_unix_fd,
+            serial: AtomicU32::new(1),
+            unique_name: OnceCell::new(),
+            incoming: Mutex::new(IncomingMessages::default()),
+            readers: Waiters::new(),
+            writers: Waiters::new(),
+            max_queued: AtomicUsize::new(DEFAULT_MAX_QUEUED),
+            closed: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
+        })))
+    }
+
+    /// Max number of messages to queue.
+    pub fn max_queued(&self) -> usize {
+        self.0.max_queued.load(Ordering::SeqCst)
+    }
+
+    /// Set the max number of messages to queue.
+    pub fn set_max_queued(self, max: usize) -> Self {
+        self.0.max_queued.store(max, Ordering::SeqCst);
+
+        self
+    }
+
+    /// What to do with incoming messages once the queue is full.
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.incoming().overflow_policy()
+    }
+
+    /// Set what to do with incoming messages once the queue is full.
+    pub fn set_overflow_policy(self, policy: OverflowPolicy) -> Self {
+        self.incoming().set_overflow_policy(policy);
+
+        self
+    }
+
+    /// The number of incoming messages dropped so far because the queue was full.
+    pub fn dropped_messages(&self) -> u64 {
+        self.incoming().dropped()
+    }
+
+    /// Returns `true` if the connection is closed: the peer closed it, or it broke.
+    ///
+    /// Once closed, the local `org.freedesktop.DBus.Local.Disconnected` signal is received, then
+    /// receiving fails and the [`Stream`] of messages ends.
+    ///
+    /// [`Stream`]: https://docs.rs/futures-core/0.3/futures_core/stream/trait.Stream.html
+    pub fn is_closed(&self) -> bool {
+        self.0.closed.load(Ordering::SeqCst)
+    }
+
+    /// The server's GUID.
+    pub fn server_guid(&self) -> &str {
+        self.0.server_guid.as_str()
+    }
+
+    /// The unique name as assigned by the message bus or `None` if not a message bus connection.
+    pub fn unique_name(&self) -> Option<&str> {
+        self.0.unique_name.get().map(|s| s.as_str())
+    }
+
+    /// Sets the unique name for this connection
+    ///
+    /// This method should only be used when initializing a client *bus* connection with
+    /// [`new_authenticated`]. Setting the unique name to anything other than the return value of
+    /// the bus hello is a protocol violation.
+    ///
+    /// Returns and error if the name has already been set.
+    ///
+    /// [`new_authenticated`]: struct.Connection.html#method.new_authenticated
+    pub fn set_unique_name(&self, name: String) -> std::result::Result<(), String> {
+        self.0.unique_name.set(name)
+    }
+
+    /// Receive the next message from the connection.
+    ///
+    /// If there are pending messages in the queue, the first one from the queue is returned
+    /// instead of reading the connection.
+    pub async fn receive_message(&self) -> Result<Message> {
+        poll_fn(|cx| self.poll_receive(cx, None)).await
+    }
+
+    /// Send `msg` to the peer.
+    ///
+    /// The connection sets a unique serial number on the message before sending it off. The
+    /// returned future resolves once the message has been written to the socket, to the assigned
+    /// serial number.
+    pub async fn send_message(&self, mut msg: Message) -> Result<u32> {
+        let serial = self.assign_serial(&mut msg)?;
+        self.write_message(msg).await?;
+
+        Ok(serial)
+    }
+
+    /// Send a method call.
+    ///
+    /// Create a method-call message, send it over the connection, then wait for the reply. Other
+    /// messages received meanwhile are queued for [`receive_message`].
+    ///
+    /// On succesful reply, an `Ok(Message)` is returned. On error, an `Err` is returned. D-Bus
+    /// error replies are returned as [`MethodError`].
+    ///
+    /// [`receive_message`]: struct.Connection.html#method.receive_message
+    /// [`MethodError`]: ../enum.Error.html#variant.MethodError
+    pub async fn call_method<B>(
+        &self,
+        destination: Option<&str>,
+        path: &str,
+        iface: Option<&str>,
+        method_name: &str,
+        body: &B,
+    ) -> Result<Message>
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+    {
+        let mut m = Message::method(
+            self.unique_name(),
+            destination,
+            path,
+            iface,
+            method_name,
+            body,
+        )?;
+
+        let serial = self.assign_serial(&mut m)?;
+        // Register the call before sending it, so that whichever task reads the reply knows it is
+        // awaited. The registration is removed when dropped, even if this future is.
+        let registration = ReplyRegistration::new(self, serial);
+        self.write_message(m).await?;
+        let reply = poll_fn(|cx| self.poll_receive(cx, Some(serial))).await?;
+        drop(registration);
+
+        match reply.header()?.message_type()? {
+            MessageType::Error => Err(reply.into()),
+            _ => Ok(reply),
+        }
+    }
+
+    /// Emit a signal.
+    ///
+    /// Create a signal message, and send it over the connection.
+    pub async fn emit_signal<B>(
+        &self,
+        destination: Option<&str>,
+        path: &str,
+        iface: &str,
+        signal_name: &str,
+        body: &B,
+    ) -> Result<()>
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+    {
+        let m = Message::signal(
+            self.unique_name(),
+            destination,
+            path,
+            iface,
+            signal_name,
+            body,
+        )?;
+
+        self.send_message(m).await?;
+
+        Ok(())
+    }
+
+    /// Reply to a message.
+    ///
+    /// Given an existing message (likely a method call), send a reply back to the caller with the
+    /// given `body`.
+    ///
+    /// Returns the message serial number.
+    pub async fn reply<B>(&self, call: &Message, body: &B) -> Result<u32>
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+    {
+        let m = Message::method_reply(self.unique_name(), call, body)?;
+        self.send_message(m).await
+    }
+
+    /// Reply an error to a message.
+    ///
+    /// Given an existing message (likely a method call), send an error reply back to the caller
+    /// with the given `error_name` and `body`.
+    ///
+    /// Returns the message serial number.
+    pub async fn reply_error<B>(&self, call: &Message, error_name: &str, body: &B) -> Result<u32>
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+    {
+        let m = Message::method_error(self.unique_name(), call, error_name, body)?;
+        self.send_message(m).await
+    }
+
+    async fn new_client(
+        handshake: ClientHandshake<Box<dyn Socket>>,
+        bus_connection: bool,
+    ) -> Result<Self> {
+        let auth = client_handshake(handshake).await?;
+        let connection = Self::new_authenticated(auth)?;
+
+        if bus_connection {
+            // Now that the server has approved us, we must send the bus Hello, as per specs
+            let name: String = connection
+                .call_method(
+                    Some("org.freedesktop.DBus"),
+                    "/org/freedesktop/DBus",
+                    Some("org.freedesktop.DBus"),
+                    "Hello",
+                    &(),
+                )
+                .await
+                .and_then(|reply| reply.body().map_err(Error::from))
+                .map_err(|e| Error::Handshake(format!("Hello failed: {}", e)))?;
+            connection
+                .0
+                .unique_name
+                .set(name)
+                // programmer (probably our) error if this fails.
+                .expect("Attempted to set unique_name twice");
+        }
+
+        Ok(connection)
+    }
+
+    fn assign_serial(&self, msg: &mut Message) -> Result<u32> {
+        set_serial(msg, self.0.serial.fetch_add(1, Ordering::SeqCst))
+    }
+
+    async fn write_message(&self, msg: Message) -> Result<()> {
+        if !msg.fds().is_empty() && !self.0.cap_unix_fd {
+            return Err(Error::Unsupported);
+        }
+        if self.is_closed() {
+            return Err(closed_error());
+        }
+
+        self.raw_conn().enqueue_message(msg);
+        let res = poll_fn(|cx| {
+            self.poll_socket(&self.0.writers, PollFlags::POLLOUT, cx, || {
+                self.raw_conn().try_flush().map_err(Error::from)
+            })
+        })
+        .await;
+        // Let the next writer flush its message, if it was waiting for ours to be written
+        self.0.writers.wake_all();
+        match res {
+            Err(Error::Io(e)) if is_disconnection(&e) => {
+                self.0.closed.store(true, Ordering::SeqCst);
+
+                Err(Error::Io(e))
+            }
+            res => res,
+        }
+    }
+
+    // Like `poll_io`, waiting for the socket along with the other tasks of `waiters`
+    fn poll_socket<T>(
+        &self,
+        waiters: &Waiters,
+        flags: PollFlags,
+        cx: &mut Context<'_>,
+        op: impl FnMut() -> Result<T>,
+    ) -> Poll<Result<T>> {
+        // Registered before polling, not to miss the socket getting ready in between
+        waiters.wakers.register(cx.waker());
+
+        poll_io(
+            &self.0.watch,
+            flags,
+            &mut Context::from_waker(&waiters.waker),
+            op,
+        )
+    }
+
+    fn raw_conn(&self) -> MutexGuard<'_, RawConnection<Box<dyn Socket>>> {
+        self.0.raw_conn.lock().expect("lock poisoned")
+    }
+
+    fn incoming(&self) -> MutexGuard<'_, IncomingMessages> {
+        self.0.incoming.lock().expect("lock poisoned")
+    }
+
+    // The `Disconnected` signal the first time, and an error afterwards
+    fn disconnected(&self) -> Result<Message> {
+        if self.0.disconnected.swap(true, Ordering::SeqCst) {
+            return Err(closed_error());
+        }
+
+        disconnected_signal()
+    }
+
+    // Poll for the next message from the socket, marking the connection as closed if it's gone
+    fn poll_read(&self, cx: &mut Context<'_>) -> Poll<Result<Message>> {
+        if self.is_closed() {
+            return Poll::Ready(self.disconnected());
+        }
+
+        let msg = ready!(
+            self.poll_socket(&self.0.readers, PollFlags::POLLIN, cx, || {
+                self.raw_conn().try_receive_message()
+            })
+        );
+        // Whatever was read may be for the other readers, or concern them as well
+        self.0.readers.wake_all();
+        match msg {
+            Err(Error::Io(e)) if is_disconnection(&e) => {
+                self.0.closed.store(true, Ordering::SeqCst);
+
+                Poll::Ready(self.disconnected())
+            }
+            msg => Poll::Ready(msg),
+        }
+    }
+
+    // Poll for the next queued message, or the reply to the call `serial`
+    fn poll_receive(&self, cx: &mut Context<'_>, serial: Option<u32>) -> Poll<Result<Message>> {
+        let mut incoming = self.incoming();
+        loop {
+            let msg = match serial {
+                Some(serial) => incoming.take_reply(serial),
+                None => incoming.pop_queued(),
+            };
+            if let Some(msg) = msg {
+                return Poll::Ready(Ok(msg));
+            }
+
+            // The other readers are woken up by `poll_read`, to look for their message once we
+            // are done routing it
+            let msg = ready!(self.poll_read(cx))?;
+            match incoming.route_reply(msg)? {
+                Some(msg) if serial.is_none() => return Poll::Ready(Ok(msg)),
+                Some(msg) => incoming.queue(self.max_queued(), msg)?,
+                None => (),
+            }
+        }
+    }
+}
+
+impl Stream for Connection {
+    type Item = Result<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // The stream ends once the `Disconnected` signal was received
+        match ready!(self.poll_receive(cx, None)) {
+            Err(_) if self.0.disconnected.load(Ordering::SeqCst) => Poll::Ready(None),
+            res => Poll::Ready(Some(res)),
+        }
+    }
+}
+
+// The registration of a method call waiting for its reply
+struct ReplyRegistration<'c> {
+    conn: &'c Connection,
+    serial: u32,
+}
+
+impl<'c> ReplyRegistration<'c> {
+    fn new(conn: &'c Connection, serial: u32) -> Self {
+        conn.incoming().await_reply(serial);
+
+        Self { conn, serial }
+    }
+}
+
+impl Drop for ReplyRegistration<'_> {
+    fn drop(&mut self) {
+        // Drop the reply on arrival if it's not awaited anymore
+        self.conn.incoming().abandon_reply(self.serial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures_util::future::{join_all, poll_fn};
+    use futures_util::stream::StreamExt;
+
+    use super::Connection;
+    use crate::{Guid, Message};
+
+    #[test]
+    fn async_p2p() {
+        async_io::block_on(async {
+            let guid = Guid::generate();
+            let (p0, p1) = UnixStream::pair().unwrap();
+
+            let server = Connection::new_unix_server(p0, &guid);
+            let client = Connection::new_unix_client(p1, false);
+            let (server, client) = futures_util::future::join(server, client).await;
+            let (server, client) = (server.unwrap(), client.unwrap());
+            assert_eq!(client.server_guid(), guid.as_str());
+
+            let server_task = async {
+                let mut stream = server.clone();
+                let mut calls = vec![];
+                while calls.len() < 3 {
+                    calls.push(stream.next().await.unwrap().unwrap());
+                }
+                server
+                    .emit_signal(None, "/", "org.zbus.p2p", "Signal", &())
+                    .await
+                    .unwrap();
+                // Reply in reverse order, so that replies don't arrive in the order of the calls
+                while let Some(call) = calls.pop() {
+                    let i: u32 = call.body().unwrap();
+                    server.reply(&call, &i).await.unwrap();
+                }
+            };
+            let calls = join_all((0..3u32).map(|i| {
+                let client = &client;
+                async move {
+                    let reply = client
+                        .call_method(None, "/", Some("org.zbus.p2p"), "Test", &i)
+                        .await
+                        .unwrap();
+                    let val: u32 = reply.body().unwrap();
+                    assert_eq!(val, i);
+                }
+            }));
+            futures_util::future::join(server_task, calls).await;
+
+            // The signal was queued by whichever call received it
+            let m: Message = client.receive_message().await.unwrap();
+            assert_eq!(m.to_string(), "Signal Signal");
+        });
+    }
+
+    #[test]
+    fn async_abandoned_reply() {
+        async_io::block_on(async {
+            let guid = Guid::generate();
+            let (p0, p1) = UnixStream::pair().unwrap();
+
+            let server = Connection::new_unix_server(p0, &guid);
+            let client = Connection::new_unix_client(p1, false);
+            let (server, client) = futures_util::future::join(server, client).await;
+            let (server, client) = (server.unwrap(), client.unwrap());
+
+            // Drop the call future once the call is sent, before the reply arrives
+            {
+                let call = client.call_method(None, "/", Some("org.zbus.p2p"), "Test", &());
+                futures_util::pin_mut!(call);
+                assert!(futures_util::poll!(call.as_mut()).is_pending());
+            }
+            let call = server.receive_message().await.unwrap();
+            server.reply(&call, &()).await.unwrap();
+            server
+                .emit_signal(None, "/", "org.zbus.p2p", "Signal", &())
+                .await
+                .unwrap();
+
+            // The late reply is dropped, not received
+            let m = client.receive_message().await.unwrap();
+            assert_eq!(m.to_string(), "Signal Signal");
+        });
+    }
+
+    #[test]
+    fn async_concurrent_waiters() {
+        // Run `fut` to completion on its own thread, counting its polls
+        fn spawn_counted<F>(fut: F) -> thread::JoinHandle<(F::Output, usize)>
+        where
+            F: Future + Send + 'static,
+            F::Output: Send,
+        {
+            thread::spawn(move || {
+                let mut polls = 0;
+                futures_util::pin_mut!(fut);
+                let output = async_io::block_on(poll_fn(|cx| {
+                    polls += 1;
+                    fut.as_mut().poll(cx)
+                }));
+
+                (output, polls)
+            })
+        }
+
+        let guid = Guid::generate();
+        let (p0, p1) = UnixStream::pair().unwrap();
+        let (server, client) = async_io::block_on(futures_util::future::join(
+            Connection::new_unix_server(p0, &guid),
+            Connection::new_unix_client(p1, false),
+        ));
+        let (server, client) = (server.unwrap(), client.unwrap());
+
+        // A stream consumer and a method call, waiting on the socket from different tasks
+        let mut stream = client.clone();
+        let next = spawn_counted(async move { stream.next().await });
+        let call = spawn_counted(async move {
+            client
+                .call_method(None, "/", Some("org.zbus.p2p"), "Test", &())
+                .await
+        });
+        async_io::block_on(async {
+            let call = server.receive_message().await.unwrap();
+            async_io::Timer::after(Duration::from_millis(100)).await;
+            server
+                .emit_signal(None, "/", "org.zbus.p2p", "Signal", &())
+                .await
+                .unwrap();
+            server.reply(&call, &()).await.unwrap();
+        });
+
+        let (m, next_polls) = next.join().expect("failed to join stream thread");
+        assert_eq!(m.unwrap().unwrap().to_string(), "Signal Signal");
+        let (reply, call_polls) = call.join().expect("failed to join call thread");
+        reply.unwrap();
+
+        // They are only polled when something happens, instead of waking each other up
+        assert!(next_polls < 20, "{} polls", next_polls);
+        assert!(call_polls < 20, "{} polls", call_polls);
+    }
+
+    #[test]
+    fn async_disconnected() {
+        async_io::block_on(async {
+            let guid = Guid::generate();
+            let (p0, p1) = UnixStream::pair().unwrap();
+
+            let server = Connection::new_unix_server(p0, &guid);
+            let client = Connection::new_unix_client(p1, false);
+            let (server, client) = futures_util::future::join(server, client).await;
+            let (server, mut client) = (server.unwrap(), client.unwrap());
+
+            server
+                .emit_signal(None, "/", "org.zbus.p2p", "Signal", &())
+                .await
+                .unwrap();
+            drop(server);
+
+            // The queued messages, then the `Disconnected` signal, and the stream ends
+            let m = client.next().await.unwrap().unwrap();
+            assert_eq!(m.to_string(), "Signal Signal");
+            let m = client.next().await.unwrap().unwrap();
+            assert!(crate::connection::is_disconnected(&m).unwrap());
+            assert!(client.is_closed());
+            assert!(client.next().await.is_none());
+            assert!(client.receive_message().await.is_err());
+        });
+    }
+
+    #[test]
+    fn async_session() {
+        fn is_send<T: Send>(t: T) -> T {
+            t
+        }
+
+        async_io::block_on(async {
+            let conn = is_send(Connection::new_session()).await.unwrap();
+            assert!(conn.unique_name().unwrap().starts_with(':'));
+
+            let reply = is_send(conn.call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "GetNameOwner",
+                &"org.freedesktop.DBus",
+            ))
+            .await
+            .unwrap();
+            let owner: String = reply.body().unwrap();
+            assert_eq!(owner, "org.freedesktop.DBus");
+        });
+    }
+}
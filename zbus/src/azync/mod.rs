@@ -0,0 +1,6 @@
+//! The asynchronous API.
+//!
+//! This API is runtime-agnostic: the futures it provides can be run on any executor.
+
+mod connection;
+pub use connection::*;
@@ -1,19 +1,27 @@
-use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixStream;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
+use enumflags2::BitFlags;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::poll::PollFlags;
-use once_cell::unsync::OnceCell;
+use once_cell::sync::OnceCell;
 
 use crate::handshake::{Authenticated, ClientHandshake, ServerHandshake};
-use crate::raw::Connection as RawConnection;
-use crate::utils::wait_on;
-use crate::{fdo, Error, Guid, Message, MessageType, Result};
+use crate::raw::{Connection as RawConnection, Socket};
+use crate::utils::{wait_on, wait_on_timeout};
+use crate::{fdo, ConnectionCredentials, Error, Guid, Message, MessageFlags, MessageType, Result};
 
-type MessageHandlerFn = Box<dyn FnMut(Message) -> Option<Message>>;
+type MessageHandlerFn = Box<dyn FnMut(Message) -> Option<Message> + Send>;
 
-const DEFAULT_MAX_QUEUED: usize = 32;
+pub(crate) const DEFAULT_MAX_QUEUED: usize = 32;
+// The default method call timeout of the reference implementation.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(25);
+// How many abandoned calls to remember, to drop their late replies.
+const MAX_ABANDONED: usize = 256;
 
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
@@ -21,19 +29,229 @@ struct ConnectionInner {
     server_guid: Guid,
     cap_unix_fd: bool,
     unique_name: OnceCell<String>,
+    peer_credentials: ConnectionCredentials,
 
-    raw_conn: RefCell<RawConnection<UnixStream>>,
+    raw_fd: RawFd,
+    raw_conn: Mutex<RawConnection<Box<dyn Socket>>>,
     // Serial number for next outgoing message
-    serial: Cell<u32>,
+    serial: AtomicU32,
 
-    // Queue of incoming messages
-    incoming_queue: RefCell<Vec<Message>>,
+    // Incoming messages, and the replies threads are waiting for
+    incoming: Mutex<Incoming>,
+    // Signaled whenever a message has been read, or the reading thread is done
+    incoming_cond: Condvar,
 
     // Max number of messages to queue
-    max_queued: Cell<usize>,
+    max_queued: AtomicUsize,
+
+    default_timeout: Mutex<Option<Duration>>,
+
+    // The message filters, in the order they are run
+    filters: Mutex<Filters>,
+
+    state: Mutex<State>,
+}
+
+#[derive(derivative::Derivative, Default)]
+#[derivative(Debug)]
+struct State {
+    closed: bool,
+    // Whether the `Disconnected` signal was handed out already
+    disconnected: bool,
+    #[derivative(Debug = "ignore")]
+    close_handlers: Vec<Box<dyn FnOnce() + Send>>,
+}
+
+#[derive(Debug, Default)]
+struct Filters {
+    next_id: u64,
+    chain: Vec<Filter>,
+}
 
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct Filter {
+    id: FilterId,
+    direction: MessageDirection,
+    // Filters are run without holding the `filters` lock, so that they can add or remove filters
     #[derivative(Debug = "ignore")]
-    default_msg_handler: RefCell<Option<MessageHandlerFn>>,
+    func: Arc<Mutex<MessageHandlerFn>>,
+}
+
+/// The direction of a message, relative to a connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MessageDirection {
+    /// A message received from the peer.
+    Incoming,
+    /// A message sent to the peer.
+    Outgoing,
+}
+
+/// The registration handle of a message filter.
+///
+/// Returned by [`Connection::add_filter`], to remove the filter with [`Connection::remove_filter`].
+///
+/// [`Connection::add_filter`]: struct.Connection.html#method.add_filter
+/// [`Connection::remove_filter`]: struct.Connection.html#method.remove_filter
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct FilterId(u64);
+
+#[derive(Debug, Default)]
+struct Incoming {
+    messages: IncomingMessages,
+    // Whether a thread is currently reading from the socket
+    reading: bool,
+}
+
+// The incoming messages of a connection, shared by the blocking and asynchronous connections: the
+// queue of messages, and the replies to the method calls in flight.
+#[derive(Debug, Default)]
+pub(crate) struct IncomingMessages {
+    // Queue of incoming messages
+    queue: VecDeque<Message>,
+    // Replies to method calls, by the serial of the call, for the calls still waiting for one
+    replies: HashMap<u32, Option<Message>>,
+    // The serials of the latest calls that timed out or whose reply is not awaited anymore, whose
+    // replies are to be dropped
+    abandoned: VecDeque<u32>,
+    // What to do with messages once the queue is full, and how many were dropped
+    overflow: OverflowPolicy,
+    dropped: u64,
+}
+
+impl IncomingMessages {
+    pub(crate) fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
+    pub(crate) fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow = policy;
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub(crate) fn pop_queued(&mut self) -> Option<Message> {
+        self.queue.pop_front()
+    }
+
+    // Register the call `serial` as waiting for its reply
+    pub(crate) fn await_reply(&mut self, serial: u32) {
+        self.replies.insert(serial, None);
+    }
+
+    // Whether the call `serial` is still waiting for its reply
+    pub(crate) fn awaits_reply(&self, serial: u32) -> bool {
+        matches!(self.replies.get(&serial), Some(None))
+    }
+
+    // Take the reply to the call `serial` if it arrived, unregistering the call
+    pub(crate) fn take_reply(&mut self, serial: u32) -> Option<Message> {
+        let reply = self.replies.get_mut(&serial).and_then(Option::take);
+        if reply.is_some() {
+            self.replies.remove(&serial);
+        }
+
+        reply
+    }
+
+    // Unregister the call `serial`. If it's still waiting for its reply, remember it to drop the
+    // reply on arrival.
+    pub(crate) fn abandon_reply(&mut self, serial: u32) {
+        if let Some(None) = self.replies.remove(&serial) {
+            if self.abandoned.len() == MAX_ABANDONED {
+                self.abandoned.pop_front();
+            }
+            self.abandoned.push_back(serial);
+        }
+    }
+
+    // Keep `msg` for the call waiting for it, if it's such a reply, or drop it if it's the late
+    // reply to an abandoned call. Other messages are returned.
+    pub(crate) fn route_reply(&mut self, msg: Message) -> Result<Option<Message>> {
+        let h = msg.header()?;
+        match h.message_type()? {
+            MessageType::MethodReturn | MessageType::Error => (),
+            _ => return Ok(Some(msg)),
+        }
+        let serial = match h.reply_serial()? {
+            Some(serial) => serial,
+            None => return Ok(Some(msg)),
+        };
+        if let Some(reply) = self.replies.get_mut(&serial) {
+            reply.replace(msg);
+
+            return Ok(None);
+        }
+        // Drop the late replies to abandoned calls
+        if let Some(i) = self.abandoned.iter().position(|s| *s == serial) {
+            self.abandoned.remove(i);
+
+            return Ok(None);
+        }
+
+        Ok(Some(msg))
+    }
+
+    // Queue `msg`, holding at most `max` messages unless the overflow policy says otherwise
+    pub(crate) fn queue(&mut self, max: usize, msg: Message) -> Result<()> {
+        let res = self.overflow.queue(&mut self.queue, max, msg);
+        if !matches!(res, Ok(false)) {
+            self.dropped += 1;
+        }
+
+        res.map(|_| ())
+    }
+}
+
+/// What to do with incoming messages once the queue of a connection is full.
+///
+/// See [`Connection::set_overflow_policy`].
+///
+/// [`Connection::set_overflow_policy`]: struct.Connection.html#method.set_overflow_policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drop the message that just arrived. This is the default.
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued message, to make room for the one that just arrived.
+    DropOldest,
+    /// Queue the message anyway, ignoring the max number of queued messages.
+    Unbounded,
+    /// Drop the message that just arrived, and fail the method call it arrived during with
+    /// [`Error::QueueFull`].
+    ///
+    /// [`Error::QueueFull`]: enum.Error.html#variant.QueueFull
+    Error,
+}
+
+impl OverflowPolicy {
+    // Queue `msg`, if `queue` holds less than `max` messages or as the policy says otherwise.
+    // Returns whether a message was dropped.
+    pub(crate) fn queue(
+        self,
+        queue: &mut VecDeque<Message>,
+        max: usize,
+        msg: Message,
+    ) -> Result<bool> {
+        if queue.len() < max || self == OverflowPolicy::Unbounded {
+            queue.push_back(msg);
+
+            return Ok(false);
+        }
+
+        match self {
+            OverflowPolicy::DropOldest if max > 0 => {
+                queue.pop_front();
+                queue.push_back(msg);
+            }
+            OverflowPolicy::Error => return Err(Error::QueueFull),
+            _ => (),
+        }
+
+        Ok(true)
+    }
 }
 
 /// A D-Bus connection.
@@ -52,19 +270,33 @@ struct ConnectionInner {
 ///
 /// Typically, a connection is made to the session bus with [`new_session`], or to the system bus
 /// with [`new_system`]. Then the connection is shared with the [`Proxy`] and [`ObjectServer`]
-/// instances.
+/// instances. For more control over how the connection is set up, use a [`ConnectionBuilder`].
 ///
 /// `Connection` implements [`Clone`] and cloning it is a very cheap operation, as the underlying
 /// data is not cloned. This makes it very convenient to share the connection between different
-/// parts of your code. Please note however, that sharing or sending of a connection instance
-/// across threads is not supported. If you've a valid use cas for that, please [file an issue]
-/// about it and we'll consider adding this feature.
+/// parts of your code. `Connection` is also `Send` and `Sync`, so it can be shared between
+/// threads: several threads can wait in [`call_method`] at the same time, each of them getting the
+/// reply to its own call. Only one thread reads from the socket at any given time, handing the
+/// messages it reads over to the threads waiting for them.
 ///
 /// Since there are times when important messages arrive between a method call message is sent and
 /// its reply is received, `Connection` keeps an internal queue of incoming messages so that these
 /// messages are not lost and subsequent calls to [`receive_message`] will retreive messages from
 /// this queue first. The size of this queue is configurable through the [`set_max_queued`] method.
-/// The default size is 32. All messages that are received after the queue is full, are dropped.
+/// The default size is 32. By default, all messages that are received after the queue is full, are
+/// dropped. This can be changed with [`set_overflow_policy`], and the number of dropped messages
+/// is available through [`dropped_messages`].
+///
+/// Method calls time out after 25 seconds by default, like with the reference implementation. This
+/// can be changed with [`set_default_timeout`], or for a single call with
+/// [`call_method_with_timeout`]. Replies arriving after the call timed out are dropped.
+///
+/// To have several method calls in flight at the same time without using threads, send them with
+/// [`send_method_call`] and collect their replies later on, in any order.
+///
+/// Once the peer closes the connection (or it breaks), the connection is [closed] and the local
+/// `org.freedesktop.DBus.Local.Disconnected` signal is received, like with the reference
+/// implementation. Reading from the connection fails afterwards.
 ///
 /// [method calls]: struct.Connection.html#method.call_method
 /// [signals]: struct.Connection.html#method.emit_signal
@@ -73,18 +305,25 @@ struct ConnectionInner {
 /// [`new_authenticated_unix`]: struct.Connection.html#method.new_authenticated_unix
 /// [`Proxy`]: struct.Proxy.html
 /// [`ObjectServer`]: struct.ObjectServer.html
+/// [`ConnectionBuilder`]: struct.ConnectionBuilder.html
 /// [`dbus_proxy`]: attr.dbus_proxy.html
 /// [`dbus_interface`]: attr.dbus_interface.html
 /// [`Clone`]: https://doc.rust-lang.org/std/clone/trait.Clone.html
-/// [file an issue]: https://gitlab.freedesktop.org/zeenix/zbus/-/issues/new
+/// [`call_method`]: struct.Connection.html#method.call_method
 /// [`receive_message`]: struct.Connection.html#method.receive_message
 /// [`set_max_queued`]: struct.Connection.html#method.set_max_queued
+/// [`set_overflow_policy`]: struct.Connection.html#method.set_overflow_policy
+/// [`dropped_messages`]: struct.Connection.html#method.dropped_messages
+/// [`set_default_timeout`]: struct.Connection.html#method.set_default_timeout
+/// [`call_method_with_timeout`]: struct.Connection.html#method.call_method_with_timeout
+/// [`send_method_call`]: struct.Connection.html#method.send_method_call
+/// [closed]: struct.Connection.html#method.is_closed
 #[derive(Debug, Clone)]
-pub struct Connection(Rc<ConnectionInner>);
+pub struct Connection(Arc<ConnectionInner>);
 
 impl AsRawFd for Connection {
     fn as_raw_fd(&self) -> RawFd {
-        self.0.raw_conn.borrow().socket().as_raw_fd()
+        self.0.raw_fd
     }
 }
 
@@ -98,12 +337,12 @@ impl Connection {
     /// can be sent and received.
     pub fn new_unix_client(stream: UnixStream, bus_connection: bool) -> Result<Self> {
         // SASL Handshake
-        let auth = ClientHandshake::new(stream).blocking_finish()?;
+        let auth = ClientHandshake::new(stream).blocking_finish()?.into_boxed();
 
         if bus_connection {
-            Connection::new_authenticated_unix_bus(auth)
+            Connection::new_authenticated_bus(auth)
         } else {
-            Ok(Connection::new_authenticated_unix(auth))
+            Ok(Connection::new_authenticated(auth))
         }
     }
 
@@ -111,26 +350,30 @@ impl Connection {
     pub fn new_session() -> Result<Self> {
         ClientHandshake::new_session()?
             .blocking_finish()
-            .and_then(Self::new_authenticated_unix_bus)
+            .and_then(Self::new_authenticated_bus)
     }
 
     /// Create a `Connection` to the system-wide message bus.
     pub fn new_system() -> Result<Self> {
         ClientHandshake::new_system()?
             .blocking_finish()
-            .and_then(Self::new_authenticated_unix_bus)
+            .and_then(Self::new_authenticated_bus)
     }
 
     /// Create a `Connection` for the given [D-Bus address].
     ///
+    /// The `unix`, `unixexec`, `tcp` and `nonce-tcp` transports are supported. Note that file
+    /// descriptors can not be passed over a TCP connection. If the address has a `guid` option, the
+    /// server's GUID is checked against it.
+    ///
     /// [D-Bus address]: https://dbus.freedesktop.org/doc/dbus-specification.html#addresses
     pub fn new_for_address(address: &str, bus_connection: bool) -> Result<Self> {
         let auth = ClientHandshake::new_for_address(address)?.blocking_finish()?;
 
         if bus_connection {
-            Connection::new_authenticated_unix_bus(auth)
+            Connection::new_authenticated_bus(auth)
         } else {
-            Ok(Connection::new_authenticated_unix(auth))
+            Ok(Connection::new_authenticated(auth))
         }
     }
 
@@ -155,7 +398,7 @@ impl Connection {
 
     /// Max number of messages to queue.
     pub fn max_queued(&self) -> usize {
-        self.0.max_queued.get()
+        self.0.max_queued.load(Ordering::SeqCst)
     }
 
     /// Set the max number of messages to queue.
@@ -176,7 +419,48 @@ impl Connection {
     ///# Ok::<_, Box<dyn Error + Send + Sync>>(())
     /// ```
     pub fn set_max_queued(self, max: usize) -> Self {
-        self.0.max_queued.replace(max);
+        self.0.max_queued.store(max, Ordering::SeqCst);
+
+        self
+    }
+
+    /// What to do with incoming messages once the queue is full.
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.incoming().messages.overflow_policy()
+    }
+
+    /// Set what to do with incoming messages once the queue is full.
+    ///
+    /// Like [`set_max_queued`], this method takes ownership of `self` so you can use the builder
+    /// pattern to set the value.
+    ///
+    /// [`set_max_queued`]: struct.Connection.html#method.set_max_queued
+    pub fn set_overflow_policy(self, policy: OverflowPolicy) -> Self {
+        self.incoming().messages.set_overflow_policy(policy);
+
+        self
+    }
+
+    /// The number of incoming messages dropped so far because the queue was full.
+    pub fn dropped_messages(&self) -> u64 {
+        self.incoming().messages.dropped()
+    }
+
+    /// The default timeout of method calls.
+    ///
+    /// `None` means method calls never time out.
+    pub fn default_timeout(&self) -> Option<Duration> {
+        *self.0.default_timeout.lock().expect("lock poisoned")
+    }
+
+    /// Set the default timeout of method calls.
+    ///
+    /// `None` means method calls never time out. Like [`set_max_queued`], this method takes
+    /// ownership of `self` so you can use the builder pattern to set the value.
+    ///
+    /// [`set_max_queued`]: struct.Connection.html#method.set_max_queued
+    pub fn set_default_timeout(self, timeout: Option<Duration>) -> Self {
+        *self.0.default_timeout.lock().expect("lock poisoned") = timeout;
 
         self
     }
@@ -186,6 +470,18 @@ impl Connection {
         self.0.server_guid.as_str()
     }
 
+    /// The credentials of the process on the other end of the connection.
+    ///
+    /// They are read from the socket during the handshake, so they are those of the process that
+    /// made the connection even if it exited since. On a bus connection, these are the credentials
+    /// of the bus itself: use [`fdo::DBusProxy::connection_credentials`] for those of other bus
+    /// peers.
+    ///
+    /// [`fdo::DBusProxy::connection_credentials`]: fdo/struct.DBusProxy.html#method.connection_credentials
+    pub fn peer_credentials(&self) -> &ConnectionCredentials {
+        &self.0.peer_credentials
+    }
+
     /// The unique name as assigned by the message bus or `None` if not a message bus connection.
     pub fn unique_name(&self) -> Option<&str> {
         self.0.unique_name.get().map(|s| s.as_str())
@@ -196,32 +492,34 @@ impl Connection {
     /// Read from the connection until a message is received or an error is reached. Return the
     /// message on success. If the connection is in non-blocking mode, this will return a
     /// `WouldBlock` error instead of blocking. If there are pending messages in the queue, the
-    /// first one from the queue is returned instead of attempting to read the connection.
+    /// first one from the queue is returned instead of attempting to read the connection. If
+    /// another thread is currently reading from the connection, this waits for it to hand over a
+    /// message instead.
     ///
-    /// If a default message handler has been registered on this connection through
-    /// [`set_default_message_handler`], it will first get to decide the fate of the received
-    /// message.
+    /// Replies to method calls that other threads are waiting for in [`call_method`] are never
+    /// returned by this method.
     ///
-    /// [`set_default_message_handler`]: struct.Connection.html#method.set_default_message_handler
+    /// If incoming message filters have been added to this connection through [`add_filter`], they
+    /// first get to decide the fate of the received message.
+    ///
+    /// [`call_method`]: struct.Connection.html#method.call_method
+    /// [`add_filter`]: struct.Connection.html#method.add_filter
     pub fn receive_message(&self) -> Result<Message> {
-        let mut queue = self.0.incoming_queue.borrow_mut();
-        if let Some(msg) = queue.pop() {
-            return Ok(msg);
-        }
-
+        let mut incoming = self.incoming();
         loop {
-            let incoming = self.0.raw_conn.borrow_mut().try_receive_message()?;
-
-            if let Some(ref mut handler) = &mut *self.0.default_msg_handler.borrow_mut() {
-                // Let's see if the default handler wants the message first
-                match handler(incoming) {
-                    // Message was returned to us so we can return that
-                    Some(m) => return Ok(m),
-                    None => continue,
-                }
+            if let Some(msg) = incoming.messages.pop_queued() {
+                return Ok(msg);
+            }
+            if incoming.reading {
+                incoming = self.wait_incoming(incoming, None)?;
+                continue;
             }
 
-            return Ok(incoming);
+            let (i, msg) = self.read_message(incoming, None);
+            incoming = i;
+            if let Some(msg) = msg? {
+                return Ok(msg);
+            }
         }
     }
 
@@ -237,27 +535,45 @@ impl Connection {
     ///
     /// [`flush`]: struct.Connection.html#method.flush
     pub fn send_message(&self, mut msg: Message) -> Result<u32> {
-        if !msg.fds().is_empty() && !self.0.cap_unix_fd {
-            return Err(Error::Unsupported);
-        }
+        let serial = self.assign_serial(&mut msg)?;
+        self.write_message(msg)?;
 
-        let serial = self.next_serial();
-        msg.modify_primary_header(|primary| {
-            primary.set_serial_num(serial);
+        Ok(serial)
+    }
 
-            Ok(())
-        })?;
+    // Set a new serial number on `msg`, and return it
+    fn assign_serial(&self, msg: &mut Message) -> Result<u32> {
+        set_serial(msg, self.next_serial())
+    }
 
-        let mut conn = self.0.raw_conn.borrow_mut();
-        conn.enqueue_message(msg);
+    fn write_message(&self, msg: Message) -> Result<()> {
+        if !msg.fds().is_empty() && !self.0.cap_unix_fd {
+            return Err(Error::Unsupported);
+        }
+        if self.is_closed() {
+            return Err(closed_error());
+        }
+        // The object server doesn't reply to the calls not expecting any
+        if crate::object_server::is_unexpected_reply(&msg) {
+            return Ok(());
+        }
+        let msg = match self.filter_message(MessageDirection::Outgoing, msg) {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
+
+        let res = {
+            let mut conn = self.raw_conn();
+            conn.enqueue_message(msg);
+            conn.try_flush()
+        };
         // Swallow a potential WouldBLock error, but propagate the others
-        if let Err(e) = conn.try_flush() {
-            if e.kind() != std::io::ErrorKind::WouldBlock {
-                return Err(e.into());
+        match res {
+            Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => {
+                Err(self.check_disconnection(e.into()))
             }
+            _ => Ok(()),
         }
-
-        Ok(serial)
     }
 
     /// Flush pending outgoing messages to the server
@@ -271,24 +587,73 @@ impl Connection {
     ///
     /// If the connection is in blocking mode, this will return `Ok(())` and do nothing.
     pub fn flush(&self) -> Result<()> {
-        self.0.raw_conn.borrow_mut().try_flush()?;
-        Ok(())
+        let res = self.raw_conn().try_flush();
+
+        res.map_err(|e| self.check_disconnection(e.into()))
+    }
+
+    /// Whether the connection is closed.
+    ///
+    /// A connection is closed by [`close`], or once the peer closed it or it broke. It can't be
+    /// used anymore then.
+    ///
+    /// [`close`]: struct.Connection.html#method.close
+    pub fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+
+    /// Run `handler` once the connection is closed.
+    ///
+    /// If the connection is already closed, `handler` is run right away.
+    pub fn on_close<F>(&self, handler: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.state();
+        if state.closed {
+            drop(state);
+            handler();
+        } else {
+            state.close_handlers.push(Box::new(handler));
+        }
+    }
+
+    /// Close the connection.
+    ///
+    /// Pending outgoing messages are flushed first, even if the connection is in non-blocking mode,
+    /// then the socket is shut down. This does nothing if the connection is already closed.
+    pub fn close(&self) -> Result<()> {
+        if self.is_closed() {
+            return Ok(());
+        }
+
+        let flushed = self.flush_until(None);
+        let closed = self.raw_conn().close();
+        self.set_closed();
+        flushed?;
+
+        closed.map_err(Error::Io)
     }
 
     /// Send a method call.
     ///
     /// Create a method-call message, send it over the connection, then wait for the reply. Incoming
-    /// messages are received through [`receive_message`] (and by the default message handler)
-    /// until the matching method reply (error or return) is received.
+    /// messages are received (and run through the incoming filters) until the matching method
+    /// reply (error or return) is received. Other messages are queued for [`receive_message`],
+    /// while replies to the calls of other threads are handed over to them.
     ///
     /// On succesful reply, an `Ok(Message)` is returned. On error, an `Err` is returned. D-Bus
     /// error replies are returned as [`MethodError`].
     ///
+    /// If no reply is received before the [default timeout], [`Error::Timeout`] is returned.
+    ///
     /// *Note:* This method will block until the response is received even if the connection is
     /// in non-blocking mode. If you don't want to block like this, use [`send_message`].
     ///
     /// [`receive_message`]: struct.Connection.html#method.receive_message
     /// [`MethodError`]: enum.Error.html#variant.MethodError
+    /// [default timeout]: struct.Connection.html#method.default_timeout
+    /// [`Error::Timeout`]: enum.Error.html#variant.Timeout
     /// [`sent_message`]: struct.Connection.html#method.send_message
     pub fn call_method<B>(
         &self,
@@ -298,6 +663,112 @@ impl Connection {
         method_name: &str,
         body: &B,
     ) -> Result<Message>
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+    {
+        self.call_method_with_timeout(
+            destination,
+            path,
+            iface,
+            method_name,
+            body,
+            self.default_timeout(),
+        )
+    }
+
+    /// Send a method call, with a specific timeout.
+    ///
+    /// Same as [`call_method`], except that `timeout` is used instead of the [default timeout].
+    /// `None` means waiting for the reply forever.
+    ///
+    /// [`call_method`]: struct.Connection.html#method.call_method
+    /// [default timeout]: struct.Connection.html#method.default_timeout
+    pub fn call_method_with_timeout<B>(
+        &self,
+        destination: Option<&str>,
+        path: &str,
+        iface: Option<&str>,
+        method_name: &str,
+        body: &B,
+        timeout: Option<Duration>,
+    ) -> Result<Message>
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+    {
+        let m = Message::method(
+            self.unique_name(),
+            destination,
+            path,
+            iface,
+            method_name,
+            body,
+        )?;
+
+        self.send_call(m, timeout)?.wait()
+    }
+
+    /// Send a method call, with the given header `flags`.
+    ///
+    /// Same as [`call_method`], except that the call is sent with `flags`, such as
+    /// [`MessageFlags::NoAutoStart`] or [`MessageFlags::AllowInteractiveAuth`]. If `flags` contains
+    /// [`MessageFlags::NoReplyExpected`], the call is only sent and `None` is returned, without
+    /// waiting for a reply.
+    ///
+    /// [`call_method`]: struct.Connection.html#method.call_method
+    /// [`MessageFlags::NoAutoStart`]: enum.MessageFlags.html#variant.NoAutoStart
+    /// [`MessageFlags::AllowInteractiveAuth`]: enum.MessageFlags.html#variant.AllowInteractiveAuth
+    /// [`MessageFlags::NoReplyExpected`]: enum.MessageFlags.html#variant.NoReplyExpected
+    pub fn call_method_with_flags<B>(
+        &self,
+        destination: Option<&str>,
+        path: &str,
+        iface: Option<&str>,
+        method_name: &str,
+        flags: BitFlags<MessageFlags>,
+        body: &B,
+    ) -> Result<Option<Message>>
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+    {
+        let mut m = Message::method(
+            self.unique_name(),
+            destination,
+            path,
+            iface,
+            method_name,
+            body,
+        )?;
+        m.modify_primary_header(|primary| {
+            primary.set_flags(flags);
+
+            Ok(())
+        })?;
+
+        if flags.contains(MessageFlags::NoReplyExpected) {
+            self.send_message(m)?;
+
+            return Ok(None);
+        }
+
+        self.send_call(m, self.default_timeout())?.wait().map(Some)
+    }
+
+    /// Send a method call, without waiting for its reply.
+    ///
+    /// Returns a [`PendingReply`] handle, to get the reply later on. This allows to have many
+    /// method calls in flight at the same time, and to collect their replies in any order. The
+    /// [default timeout] applies from the time the call is sent.
+    ///
+    /// [`PendingReply`]: struct.PendingReply.html
+    /// [default timeout]: struct.Connection.html#method.default_timeout
+    pub fn send_method_call<B>(
+        &self,
+        destination: Option<&str>,
+        path: &str,
+        iface: Option<&str>,
+        method_name: &str,
+        body: &B,
+    ) -> Result<PendingReply>
     where
         B: serde::ser::Serialize + zvariant::Type,
     {
@@ -310,51 +781,37 @@ impl Connection {
             body,
         )?;
 
-        let serial = self.send_message(m)?;
-        // loop & sleep until the message is completely sent
+        self.send_call(m, self.default_timeout())
+    }
+
+    fn send_call(&self, mut msg: Message, timeout: Option<Duration>) -> Result<PendingReply> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let serial = self.assign_serial(&mut msg)?;
+        // Register the call before sending it, so that whichever thread reads the reply knows it
+        // is awaited.
+        self.incoming().messages.await_reply(serial);
+        let pending = PendingReply {
+            conn: self.clone(),
+            serial,
+            deadline,
+        };
+        self.write_message(msg)?;
+
+        Ok(pending)
+    }
+
+    // Flush the outgoing messages, failing with `Error::Timeout` past `deadline`
+    fn flush_until(&self, deadline: Option<Instant>) -> Result<()> {
+        // loop & sleep until the messages are completely sent
         loop {
             match self.flush() {
-                Ok(()) => break,
+                Ok(()) => return Ok(()),
                 Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    wait_on(self.as_raw_fd(), PollFlags::POLLOUT)?;
+                    self.wait_on(PollFlags::POLLOUT, deadline)?;
                 }
                 Err(e) => return Err(e),
             }
         }
-        let mut tmp_queue = vec![];
-
-        loop {
-            let m = loop {
-                match self.receive_message() {
-                    Ok(m) => break m,
-                    Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        wait_on(self.as_raw_fd(), PollFlags::POLLIN)?;
-                    }
-                    Err(e) => return Err(e),
-                }
-            };
-            let h = m.header()?;
-
-            if h.reply_serial()? != Some(serial) {
-                let queue = self.0.incoming_queue.borrow();
-                if queue.len() + tmp_queue.len() < self.0.max_queued.get() {
-                    // We first push to a temporary queue as otherwise it'll create an infinite loop
-                    // since subsequent `receive_message` call will pick up the message from the main
-                    // queue.
-                    tmp_queue.push(m);
-                }
-
-                continue;
-            } else {
-                self.0.incoming_queue.borrow_mut().append(&mut tmp_queue);
-            }
-
-            match h.message_type()? {
-                MessageType::Error => return Err(m.into()),
-                MessageType::MethodReturn => return Ok(m),
-                _ => (),
-            }
-        }
     }
 
     /// Emit a signal.
@@ -413,22 +870,41 @@ impl Connection {
         self.send_message(m)
     }
 
-    /// Set a default handler for incoming messages on this connection.
+    /// Add a message filter to this connection.
+    ///
+    /// Filters are run in the order they were added, on all the messages going in `direction`: on
+    /// incoming messages as soon as they are read (so before [`receive_message`] returns them or
+    /// replies are handed over to [`call_method`]), and on outgoing messages right before they are
+    /// written to the socket. Each filter gets the message returned by the previous one, and can
+    /// pass it on unchanged, transform it (returning a different message), or consume it (returning
+    /// `None`), in which case the message is dropped and the following filters are not run.
     ///
-    /// This is the handler that will be called on all messages received during [`receive_message`]
-    /// call. If the handler callback returns a message (which could be a different message than it
-    /// was given), `receive_message` will return it to its caller.
+    /// Outgoing messages already have their serial number set, and filters should keep it. Note
+    /// that consuming a method call or its reply makes the call time out.
+    ///
+    /// Returns the handle of the filter, to remove it with [`remove_filter`].
     ///
     /// [`receive_message`]: struct.Connection.html#method.receive_message
-    pub fn set_default_message_handler(&mut self, handler: MessageHandlerFn) {
-        self.0.default_msg_handler.borrow_mut().replace(handler);
+    /// [`call_method`]: struct.Connection.html#method.call_method
+    /// [`remove_filter`]: struct.Connection.html#method.remove_filter
+    pub fn add_filter<F>(&self, direction: MessageDirection, filter: F) -> FilterId
+    where
+        F: FnMut(Message) -> Option<Message> + Send + 'static,
+    {
+        Self::push_filter(&mut self.filters(), direction, Box::new(filter))
     }
 
-    /// Reset the default message handler.
+    /// Remove a message filter added with [`add_filter`].
+    ///
+    /// Returns `false` if the filter had already been removed.
     ///
-    /// Remove the previously set message handler from `set_default_message_handler`.
-    pub fn reset_default_message_handler(&mut self) {
-        self.0.default_msg_handler.borrow_mut().take();
+    /// [`add_filter`]: struct.Connection.html#method.add_filter
+    pub fn remove_filter(&self, id: FilterId) -> bool {
+        let mut filters = self.filters();
+        let len = filters.chain.len();
+        filters.chain.retain(|f| f.id != id);
+
+        filters.chain.len() != len
     }
 
     /// Create a `Connection` from an already authenticated unix socket
@@ -446,15 +922,31 @@ impl Connection {
     /// [client hello]: ./fdo/struct.DBusProxy.html#method.hello
     /// [`set_unique_name`]: struct.Connection.html#method.set_unique_name
     pub fn new_authenticated_unix(auth: Authenticated<UnixStream>) -> Self {
-        Self(Rc::new(ConnectionInner {
-            raw_conn: RefCell::new(auth.conn),
+        Self::new_authenticated(auth.into_boxed())
+    }
+
+    /// Create a `Connection` from an already authenticated socket of any kind.
+    ///
+    /// This is the transport-agnostic variant of [`new_authenticated_unix`], to be used for sockets
+    /// created by [`ClientHandshake::new_for_address`] and friends.
+    ///
+    /// [`new_authenticated_unix`]: struct.Connection.html#method.new_authenticated_unix
+    /// [`ClientHandshake::new_for_address`]: ./handshake/struct.ClientHandshake.html#method.new_for_address
+    pub fn new_authenticated(auth: Authenticated<Box<dyn Socket>>) -> Self {
+        Self(Arc::new(ConnectionInner {
+            raw_fd: auth.conn.socket().as_raw_fd(),
+            raw_conn: Mutex::new(auth.conn),
             server_guid: auth.server_guid,
             cap_unix_fd: auth.cap_unix_fd,
-            serial: Cell::new(1),
+            serial: AtomicU32::new(1),
             unique_name: OnceCell::new(),
-            incoming_queue: RefCell::new(vec![]),
-            max_queued: Cell::new(DEFAULT_MAX_QUEUED),
-            default_msg_handler: RefCell::new(None),
+            peer_credentials: auth.peer_credentials,
+            incoming: Mutex::new(Incoming::default()),
+            incoming_cond: Condvar::new(),
+            max_queued: AtomicUsize::new(DEFAULT_MAX_QUEUED),
+            default_timeout: Mutex::new(Some(DEFAULT_TIMEOUT)),
+            filters: Mutex::new(Filters::default()),
+            state: Mutex::new(State::default()),
         }))
     }
 
@@ -471,50 +963,437 @@ impl Connection {
         self.0.unique_name.set(name)
     }
 
-    fn new_authenticated_unix_bus(auth: Authenticated<UnixStream>) -> Result<Self> {
-        let connection = Connection::new_authenticated_unix(auth);
+    fn new_authenticated_bus(auth: Authenticated<Box<dyn Socket>>) -> Result<Self> {
+        let connection = Connection::new_authenticated(auth);
+        connection.hello()?;
+
+        Ok(connection)
+    }
 
-        // Now that the server has approved us, we must send the bus Hello, as per specs
-        let name = fdo::DBusProxy::new(&connection)?
+    // Now that the server has approved us, we must send the bus Hello, as per specs
+    pub(crate) fn hello(&self) -> Result<()> {
+        let name = fdo::DBusProxy::new(self)?
             .hello()
             .map_err(|e| Error::Handshake(format!("Hello failed: {}", e)))?;
-        connection
-            .0
+        self.0
             .unique_name
             .set(name)
             // programmer (probably our) error if this fails.
             .expect("Attempted to set unique_name twice");
 
-        Ok(connection)
+        Ok(())
     }
 
     fn next_serial(&self) -> u32 {
-        let next = self.0.serial.get() + 1;
+        self.0.serial.fetch_add(1, Ordering::SeqCst)
+    }
 
-        self.0.serial.replace(next)
+    fn raw_conn(&self) -> MutexGuard<'_, RawConnection<Box<dyn Socket>>> {
+        self.0.raw_conn.lock().expect("lock poisoned")
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::os::unix::net::UnixStream;
-    use std::thread;
+    fn incoming(&self) -> MutexGuard<'_, Incoming> {
+        self.0.incoming.lock().expect("lock poisoned")
+    }
 
-    use crate::{Connection, Guid};
+    // Whether `receive_message` would return a message without reading the socket
+    pub(crate) fn has_queued_messages(&self) -> bool {
+        !self.incoming().messages.queue.is_empty()
+    }
 
-    #[test]
-    fn unix_p2p() {
-        let guid = Guid::generate();
+    // Whether some messages still need to be flushed
+    pub(crate) fn has_pending_output(&self) -> bool {
+        self.raw_conn().has_pending_output()
+    }
 
-        let (p0, p1) = UnixStream::pair().unwrap();
+    fn filters(&self) -> MutexGuard<'_, Filters> {
+        self.0.filters.lock().expect("lock poisoned")
+    }
 
-        let server_thread = thread::spawn(move || {
-            let c = Connection::new_unix_server(p0, &guid).unwrap();
-            let reply = c
-                .call_method(None, "/", Some("org.zbus.p2p"), "Test", &())
-                .unwrap();
-            assert_eq!(reply.to_string(), "Method return");
-            let val: String = reply.body().unwrap();
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0.state.lock().expect("lock poisoned")
+    }
+
+    fn set_closed(&self) {
+        let handlers = {
+            let mut state = self.state();
+            if state.closed {
+                return;
+            }
+            state.closed = true;
+
+            std::mem::take(&mut state.close_handlers)
+        };
+
+        for handler in handlers {
+            handler();
+        }
+    }
+
+    // Mark the connection as closed if `err` means that it's gone
+    fn check_disconnection(&self, err: Error) -> Error {
+        if let Error::Io(e) = &err {
+            if is_disconnection(e) {
+                self.set_closed();
+            }
+        }
+
+        err
+    }
+
+    // The `Disconnected` signal the first time, and an error afterwards
+    fn disconnected(&self) -> Result<Message> {
+        let mut state = self.state();
+        if state.disconnected {
+            return Err(closed_error());
+        }
+        state.disconnected = true;
+
+        disconnected_signal()
+    }
+
+    fn push_filter(
+        filters: &mut Filters,
+        direction: MessageDirection,
+        func: MessageHandlerFn,
+    ) -> FilterId {
+        let id = FilterId(filters.next_id);
+        filters.next_id += 1;
+        filters.chain.push(Filter {
+            id,
+            direction,
+            func: Arc::new(Mutex::new(func)),
+        });
+
+        id
+    }
+
+    // Run the filters of `direction` on `msg`, returning what's left of it
+    fn filter_message(&self, direction: MessageDirection, msg: Message) -> Option<Message> {
+        let chain: Vec<_> = self
+            .filters()
+            .chain
+            .iter()
+            .filter(|f| f.direction == direction)
+            .map(|f| f.func.clone())
+            .collect();
+
+        chain.iter().try_fold(msg, |msg, func| {
+            let mut func = func.lock().expect("lock poisoned");
+            (*func)(msg)
+        })
+    }
+
+    // Wait for another thread to be done reading, failing with `Error::Timeout` past `deadline`
+    fn wait_incoming<'i>(
+        &self,
+        incoming: MutexGuard<'i, Incoming>,
+        deadline: Option<Instant>,
+    ) -> Result<MutexGuard<'i, Incoming>> {
+        let cond = &self.0.incoming_cond;
+        match deadline {
+            Some(deadline) => {
+                let timeout = remaining(deadline)?;
+                let (incoming, _) = cond.wait_timeout(incoming, timeout).expect("lock poisoned");
+
+                Ok(incoming)
+            }
+            None => Ok(cond.wait(incoming).expect("lock poisoned")),
+        }
+    }
+
+    // Wait for the socket to be ready, failing with `Error::Timeout` past `deadline`
+    fn wait_on(&self, flags: PollFlags, deadline: Option<Instant>) -> Result<()> {
+        match deadline {
+            Some(deadline) => {
+                if !wait_on_timeout(self.0.raw_fd, flags, remaining(deadline)?)? {
+                    return Err(Error::Timeout);
+                }
+            }
+            None => wait_on(self.0.raw_fd, flags)?,
+        }
+
+        Ok(())
+    }
+
+    // Read the next message from the socket, as the thread in charge of reading.
+    //
+    // The `incoming` lock is released while reading, so that other threads can use the connection
+    // meanwhile. Replies other threads are waiting for are handed over to them, and `None` is
+    // returned instead.
+    fn read_message<'i>(
+        &'i self,
+        mut incoming: MutexGuard<'i, Incoming>,
+        deadline: Option<Instant>,
+    ) -> (MutexGuard<'i, Incoming>, Result<Option<Message>>) {
+        incoming.reading = true;
+        drop(incoming);
+
+        // Let's see if the filters want the message first
+        let msg = self
+            .read_raw_message(deadline)
+            .map(|msg| self.filter_message(MessageDirection::Incoming, msg));
+
+        let mut incoming = self.incoming();
+        incoming.reading = false;
+        self.0.incoming_cond.notify_all();
+        // Hand replies over to the threads waiting for them
+        let msg = match msg {
+            Ok(Some(msg)) => incoming.messages.route_reply(msg),
+            msg => msg,
+        };
+
+        (incoming, msg)
+    }
+
+    // Queue a message read while waiting for a reply, according to the overflow policy
+    fn queue_message(&self, incoming: &mut Incoming, msg: Option<Message>) -> Result<()> {
+        match msg {
+            Some(msg) => incoming.messages.queue(self.max_queued(), msg),
+            None => Ok(()),
+        }
+    }
+
+    fn read_raw_message(&self, deadline: Option<Instant>) -> Result<Message> {
+        // In blocking mode, don't hold the socket lock while waiting for a message, so that other
+        // threads can send messages meanwhile.
+        if self.is_closed() {
+            return self.disconnected();
+        }
+        let flags = OFlag::from_bits_truncate(fcntl(self.0.raw_fd, FcntlArg::F_GETFL)?);
+        if !flags.contains(OFlag::O_NONBLOCK) {
+            self.wait_on(PollFlags::POLLIN, deadline)?;
+        }
+
+        let res = self.raw_conn().try_receive_message();
+        match res {
+            Err(Error::Io(e)) if is_disconnection(&e) => {
+                self.set_closed();
+
+                self.disconnected()
+            }
+            res => res,
+        }
+    }
+}
+
+// Set `serial` as the serial number of `msg`, and return it
+pub(crate) fn set_serial(msg: &mut Message, serial: u32) -> Result<u32> {
+    msg.modify_primary_header(|primary| {
+        primary.set_serial_num(serial);
+
+        Ok(())
+    })?;
+
+    Ok(serial)
+}
+
+/// A method call awaiting its reply.
+///
+/// Returned by [`Connection::send_method_call`], to get the reply of the call later on, with
+/// [`wait`]. [`is_ready`] can be used to check if the reply arrived without blocking, such as after
+/// polling the connection's file descriptor for input.
+///
+/// Replies are routed to their `PendingReply` by the serial of the call, so any number of calls
+/// can be in flight at the same time and their replies collected in any order. Messages other than
+/// replies that are read meanwhile are queued, to be retrieved with [`Connection::receive_message`].
+///
+/// If a `PendingReply` is dropped before its reply arrived, the reply is dropped on arrival.
+///
+/// [`Connection::send_method_call`]: struct.Connection.html#method.send_method_call
+/// [`Connection::receive_message`]: struct.Connection.html#method.receive_message
+/// [`wait`]: struct.PendingReply.html#method.wait
+/// [`is_ready`]: struct.PendingReply.html#method.is_ready
+#[derive(Debug)]
+pub struct PendingReply {
+    conn: Connection,
+    serial: u32,
+    deadline: Option<Instant>,
+}
+
+impl PendingReply {
+    /// The serial number of the method call.
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Check if [`wait`] would return without blocking.
+    ///
+    /// This is the case once the reply arrived, or once the call timed out. Messages that are
+    /// available on the socket are read in the process, unless another thread is already reading.
+    ///
+    /// [`wait`]: struct.PendingReply.html#method.wait
+    pub fn is_ready(&self) -> Result<bool> {
+        match self.conn.flush() {
+            Ok(()) => (),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e),
+        }
+
+        let mut incoming = self.conn.incoming();
+        loop {
+            if !incoming.messages.awaits_reply(self.serial) {
+                return Ok(true);
+            }
+            if let Some(deadline) = self.deadline {
+                if Instant::now() >= deadline {
+                    return Ok(true);
+                }
+            }
+            if incoming.reading
+                || !wait_on_timeout(
+                    self.conn.0.raw_fd,
+                    PollFlags::POLLIN,
+                    Duration::from_secs(0),
+                )?
+            {
+                return Ok(false);
+            }
+
+            let (i, msg) = self.conn.read_message(incoming, self.deadline);
+            incoming = i;
+            match msg {
+                Ok(msg) => self.conn.queue_message(&mut incoming, msg)?,
+                Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Ok(false)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Wait for the reply.
+    ///
+    /// If the reply is an error, it is returned as an [`Error::MethodError`]. If no reply arrived
+    /// before the timeout of the call, [`Error::Timeout`] is returned.
+    ///
+    /// [`Error::MethodError`]: enum.Error.html#variant.MethodError
+    /// [`Error::Timeout`]: enum.Error.html#variant.Timeout
+    pub fn wait(self) -> Result<Message> {
+        let conn = &self.conn;
+        conn.flush_until(self.deadline)?;
+
+        let mut incoming = conn.incoming();
+        let reply = loop {
+            if let Some(reply) = incoming.messages.take_reply(self.serial) {
+                break reply;
+            }
+            if incoming.reading {
+                incoming = conn.wait_incoming(incoming, self.deadline)?;
+                continue;
+            }
+
+            let (i, msg) = conn.read_message(incoming, self.deadline);
+            incoming = i;
+            match msg {
+                Ok(msg) => conn.queue_message(&mut incoming, msg)?,
+                Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    drop(incoming);
+                    conn.wait_on(PollFlags::POLLIN, self.deadline)?;
+                    incoming = conn.incoming();
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        drop(incoming);
+
+        match reply.header()?.message_type()? {
+            MessageType::Error => Err(reply.into()),
+            _ => Ok(reply),
+        }
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        self.conn.incoming().messages.abandon_reply(self.serial);
+    }
+}
+
+// Whether `e` means that the connection is gone
+pub(crate) fn is_disconnection(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+
+    matches!(
+        e.kind(),
+        UnexpectedEof | BrokenPipe | ConnectionReset | ConnectionAborted | NotConnected
+    )
+}
+
+// Whether `msg` is the local `Disconnected` signal, marking the end of the messages
+pub(crate) fn is_disconnected(msg: &Message) -> Result<bool> {
+    let h = msg.header()?;
+
+    Ok(h.message_type()? == MessageType::Signal
+        && h.interface()? == Some(fdo::LOCAL_INTERFACE)
+        && h.member()? == Some("Disconnected"))
+}
+
+// The local `Disconnected` signal, received once the connection is closed
+pub(crate) fn disconnected_signal() -> Result<Message> {
+    Message::signal(
+        None,
+        None,
+        fdo::LOCAL_PATH,
+        fdo::LOCAL_INTERFACE,
+        "Disconnected",
+        &(),
+    )
+    .map_err(Error::from)
+}
+
+pub(crate) fn closed_error() -> Error {
+    Error::Io(std::io::Error::new(
+        std::io::ErrorKind::NotConnected,
+        "connection closed",
+    ))
+}
+
+// The time left until `deadline`, or `Error::Timeout` if it's past
+fn remaining(deadline: Instant) -> Result<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(Error::Timeout);
+    }
+
+    Ok(deadline - now)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    use nix::unistd::Uid;
+
+    use crate::handshake::ServerHandshake;
+    use crate::raw::Socket;
+    use crate::{
+        fdo, Address, Connection, Error, Guid, Message, MessageDirection, MessageFlags,
+        OverflowPolicy,
+    };
+
+    #[test]
+    fn unix_p2p() {
+        let guid = Guid::generate();
+
+        let (p0, p1) = UnixStream::pair().unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let c = Connection::new_unix_server(p0, &guid).unwrap();
+            let reply = c
+                .call_method(None, "/", Some("org.zbus.p2p"), "Test", &())
+                .unwrap();
+            assert_eq!(reply.to_string(), "Method return");
+            let val: String = reply.body().unwrap();
             val
         });
 
@@ -527,6 +1406,529 @@ mod tests {
         assert_eq!(val, "yay");
     }
 
+    #[test]
+    fn tcp_p2p() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server_thread = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let stream = Box::new(stream) as Box<dyn Socket>;
+            let auth = ServerHandshake::new(stream, Guid::generate(), Uid::current().into())
+                .blocking_finish()
+                .unwrap();
+            let c = Connection::new_authenticated(auth);
+            let reply = c
+                .call_method(None, "/", Some("org.zbus.p2p"), "Test", &())
+                .unwrap();
+            let val: String = reply.body().unwrap();
+            val
+        });
+
+        let address = format!("tcp:host=127.0.0.1,port={},family=ipv4", port);
+        let c = Connection::new_for_address(&address, false).unwrap();
+        let m = c.receive_message().unwrap();
+        assert_eq!(m.to_string(), "Method call Test");
+
+        // FD passing is not negotiated on TCP
+        let stdout = std::io::stdout();
+        let fd_msg =
+            Message::method(None, None, "/", None, "Fd", &zvariant::Fd::from(&stdout)).unwrap();
+        assert!(matches!(c.send_message(fd_msg), Err(Error::Unsupported)));
+
+        c.reply(&m, &("yay")).unwrap();
+
+        let val = server_thread.join().expect("failed to join server thread");
+        assert_eq!(val, "yay");
+    }
+
+    #[test]
+    fn p2p_server_guid() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let guid = Guid::generate();
+
+        let server_guid = guid.clone();
+        let server_thread = thread::spawn(move || {
+            // The first client hangs up on us right after our OK, so only pretend to be a server.
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = vec![];
+            while !buf.ends_with(b"\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).unwrap();
+                buf.push(byte[0]);
+            }
+            stream
+                .write_all(format!("OK {}\r\n", server_guid).as_bytes())
+                .unwrap();
+
+            let (stream, _) = listener.accept().unwrap();
+            let stream = Box::new(stream) as Box<dyn Socket>;
+            ServerHandshake::new(stream, server_guid, Uid::current().into())
+                .blocking_finish()
+                .unwrap();
+        });
+
+        let address = Address::builder("tcp")
+            .set("host", "127.0.0.1")
+            .set("port", &port.to_string())
+            .guid(&Guid::generate())
+            .build()
+            .unwrap();
+        match Connection::new_for_address(&address.to_string(), false) {
+            Err(Error::Handshake(e)) => assert!(e.starts_with("Server GUID mismatch")),
+            _ => panic!("GUID mismatch not detected"),
+        }
+
+        let address = Address::builder("tcp")
+            .set("host", "127.0.0.1")
+            .set("port", &port.to_string())
+            .guid(&guid)
+            .build()
+            .unwrap();
+        let c = Connection::new_for_address(&address.to_string(), false).unwrap();
+        assert_eq!(c.server_guid(), guid.as_str());
+
+        server_thread.join().expect("failed to join server thread");
+    }
+
+    #[test]
+    fn listen_p2p() {
+        let listener = Address::from_str("unix:tmpdir=/tmp")
+            .unwrap()
+            .listen()
+            .unwrap();
+        let address = listener.address().to_string();
+        let guid = Guid::generate();
+
+        let server_thread = thread::spawn(move || {
+            let stream = listener.accept().unwrap();
+            let c = Connection::new_unix_server(stream, &guid).unwrap();
+            let reply = c
+                .call_method(None, "/", Some("org.zbus.p2p"), "Test", &())
+                .unwrap();
+            let val: String = reply.body().unwrap();
+            val
+        });
+
+        let c = Connection::new_for_address(&address, false).unwrap();
+        let m = c.receive_message().unwrap();
+        assert_eq!(m.to_string(), "Method call Test");
+        c.reply(&m, &("yay")).unwrap();
+
+        let val = server_thread.join().expect("failed to join server thread");
+        assert_eq!(val, "yay");
+    }
+
+    #[test]
+    fn nonce_tcp_p2p() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let nonce: Vec<u8> = std::iter::repeat_with(|| fastrand::u8(..))
+            .take(16)
+            .collect();
+        let nonce_file = std::env::temp_dir().join(format!("zbus-nonce-{}", Guid::generate()));
+        std::fs::write(&nonce_file, &nonce).unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = [0; 16];
+            stream.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..], &nonce[..]);
+
+            let stream = Box::new(stream) as Box<dyn Socket>;
+            let auth = ServerHandshake::new(stream, Guid::generate(), Uid::current().into())
+                .blocking_finish()
+                .unwrap();
+            let c = Connection::new_authenticated(auth);
+            let m = c.receive_message().unwrap();
+            c.reply(&m, &("yay")).unwrap();
+        });
+
+        let address = format!(
+            "nonce-tcp:host=127.0.0.1,port={},noncefile={}",
+            port,
+            nonce_file.display()
+        );
+        let c = Connection::new_for_address(&address, false).unwrap();
+        let reply = c
+            .call_method(None, "/", Some("org.zbus.p2p"), "Test", &())
+            .unwrap();
+        let val: String = reply.body().unwrap();
+        assert_eq!(val, "yay");
+
+        server_thread.join().expect("failed to join server thread");
+        std::fs::remove_file(nonce_file).unwrap();
+    }
+
+    #[test]
+    fn unixexec_p2p() {
+        // The child bridges its stdio to a socket it inherits from us, the other end of which is
+        // served by us. This only needs `sh` and `cat`.
+        let (server, bridge) = UnixStream::pair().unwrap();
+        fcntl(bridge.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+        let script = format!("cat <&{0} & exec cat >&{0}", bridge.as_raw_fd());
+        let address = Address::builder("unixexec")
+            .set("path", "/bin/sh")
+            .set("argv0", "zbus=bridge")
+            .set("argv1", "-c")
+            .set("argv2", &script)
+            .build()
+            .unwrap();
+        assert!(address.to_string().contains("argv0=zbus%3dbridge"));
+
+        let guid = Guid::generate();
+        let server_thread = thread::spawn(move || {
+            let c = Connection::new_unix_server(server, &guid).unwrap();
+            let m = c.receive_message().unwrap();
+            c.reply(&m, &("yay")).unwrap();
+        });
+
+        let c = Connection::new_for_address(&address.to_string(), false).unwrap();
+        drop(bridge);
+        let reply = c
+            .call_method(None, "/", Some("org.zbus.p2p"), "Test", &())
+            .unwrap();
+        let val: String = reply.body().unwrap();
+        assert_eq!(val, "yay");
+
+        server_thread.join().expect("failed to join server thread");
+    }
+
+    #[test]
+    fn address_list_fallback() {
+        let session = std::env::var("DBUS_SESSION_BUS_ADDRESS").unwrap();
+        let c = Connection::new_for_address(
+            &format!("unix:path=/nonexistent/zbus-test;foo;{}", session),
+            true,
+        )
+        .unwrap();
+        assert!(c.unique_name().is_some());
+
+        match Connection::new_for_address(
+            "unix:path=/nonexistent/zbus-test;foo;tcp:host=127.0.0.1,port=0",
+            false,
+        )
+        .unwrap_err()
+        {
+            Error::Address(e) => {
+                assert!(e.starts_with("failed to connect to any of the addresses: "));
+                assert_eq!(e.matches("; ").count(), 2);
+                assert!(e.contains("; foo: "));
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn concurrent_calls() {
+        fn is_send_sync<T: Send + Sync>(_: &T) {}
+
+        let guid = Guid::generate();
+        let (p0, p1) = UnixStream::pair().unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let c = Connection::new_unix_server(p0, &guid).unwrap();
+            let mut calls: Vec<_> = (0..4).map(|_| c.receive_message().unwrap()).collect();
+            c.emit_signal(None, "/", "org.zbus.p2p", "Signal", &())
+                .unwrap();
+            // Reply in reverse order, so that replies don't arrive in the order of the calls
+            while let Some(call) = calls.pop() {
+                let i: u32 = call.body().unwrap();
+                c.reply(&call, &i).unwrap();
+            }
+        });
+
+        let c = Connection::new_unix_client(p1, false).unwrap();
+        is_send_sync(&c);
+        let threads: Vec<_> = (0..4u32)
+            .map(|i| {
+                let c = c.clone();
+                thread::spawn(move || {
+                    let reply = c
+                        .call_method(None, "/", Some("org.zbus.p2p"), "Test", &i)
+                        .unwrap();
+                    let val: u32 = reply.body().unwrap();
+                    assert_eq!(val, i);
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("failed to join calling thread");
+        }
+
+        // The signal was queued by whichever thread received it
+        let m = c.receive_message().unwrap();
+        assert_eq!(m.to_string(), "Signal Signal");
+
+        server_thread.join().expect("failed to join server thread");
+    }
+
+    #[test]
+    fn call_timeout() {
+        let guid = Guid::generate();
+        let (p0, p1) = UnixStream::pair().unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let c = Connection::new_unix_server(p0, &guid).unwrap();
+            let call = c.receive_message().unwrap();
+            // Reply once the caller gave up
+            thread::sleep(Duration::from_millis(200));
+            c.reply(&call, &()).unwrap();
+            c.emit_signal(None, "/", "org.zbus.p2p", "Signal", &())
+                .unwrap();
+        });
+
+        let c = Connection::new_unix_client(p1, false)
+            .unwrap()
+            .set_default_timeout(Some(Duration::from_millis(50)));
+        assert_eq!(c.default_timeout(), Some(Duration::from_millis(50)));
+        let res = c.call_method(None, "/", Some("org.zbus.p2p"), "Test", &());
+        assert!(matches!(res, Err(Error::Timeout)));
+
+        // The late reply is dropped
+        let m = c.receive_message().unwrap();
+        assert_eq!(m.to_string(), "Signal Signal");
+
+        server_thread.join().expect("failed to join server thread");
+    }
+
+    #[test]
+    fn pending_replies() {
+        let guid = Guid::generate();
+        let (p0, p1) = UnixStream::pair().unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let c = Connection::new_unix_server(p0, &guid).unwrap();
+            let mut calls: Vec<_> = (0..8).map(|_| c.receive_message().unwrap()).collect();
+            // Wait for the client to be done checking that no reply is ready
+            assert_eq!(c.receive_message().unwrap().to_string(), "Signal Go");
+            c.emit_signal(None, "/", "org.zbus.p2p", "Signal", &())
+                .unwrap();
+            while let Some(call) = calls.pop() {
+                let i: u32 = call.body().unwrap();
+                c.reply(&call, &i).unwrap();
+            }
+        });
+
+        let c = Connection::new_unix_client(p1, false).unwrap();
+        let mut pending: Vec<_> = (0..8u32)
+            .map(|i| {
+                c.send_method_call(None, "/", Some("org.zbus.p2p"), "Test", &i)
+                    .unwrap()
+            })
+            .collect();
+        // Nobody awaits the reply to this one anymore
+        pending.pop();
+        assert!(!pending[0].is_ready().unwrap());
+        c.emit_signal(None, "/", "org.zbus.p2p", "Go", &()).unwrap();
+
+        // Replies arrive in reverse order, collect them in order
+        for (i, p) in pending.into_iter().enumerate() {
+            let val: u32 = p.wait().unwrap().body().unwrap();
+            assert_eq!(val, i as u32);
+        }
+
+        // The signal was queued, and the reply to the dropped call dropped
+        let m = c.receive_message().unwrap();
+        assert_eq!(m.to_string(), "Signal Signal");
+        assert!(c.0.incoming.lock().unwrap().messages.queue.is_empty());
+
+        server_thread.join().expect("failed to join server thread");
+    }
+
+    #[test]
+    fn call_flags() {
+        let guid = Guid::generate();
+        let (p0, p1) = UnixStream::pair().unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let c = Connection::new_unix_server(p0, &guid).unwrap();
+            let call = c.receive_message().unwrap();
+            let flags = call.primary_header().unwrap().flags();
+            assert_eq!(
+                flags,
+                MessageFlags::NoReplyExpected | MessageFlags::NoAutoStart
+            );
+
+            let call = c.receive_message().unwrap();
+            let flags = call.primary_header().unwrap().flags();
+            assert_eq!(flags, MessageFlags::AllowInteractiveAuth);
+            assert_ne!(c.reply(&call, &()).unwrap(), 0);
+        });
+
+        let c = Connection::new_unix_client(p1, false).unwrap();
+        let flags = MessageFlags::NoReplyExpected | MessageFlags::NoAutoStart;
+        let reply = c
+            .call_method_with_flags(None, "/", Some("org.zbus.p2p"), "Test", flags, &())
+            .unwrap();
+        assert!(reply.is_none());
+        let flags = MessageFlags::AllowInteractiveAuth.into();
+        let reply = c
+            .call_method_with_flags(None, "/", Some("org.zbus.p2p"), "Test", flags, &())
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.to_string(), "Method return");
+        // Nothing was awaited for the first call
+        assert!(c.0.incoming.lock().unwrap().messages.queue.is_empty());
+
+        server_thread.join().expect("failed to join server thread");
+    }
+
+    #[test]
+    fn filters() {
+        let guid = Guid::generate();
+        let (p0, p1) = UnixStream::pair().unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let c = Connection::new_unix_server(p0, &guid).unwrap();
+            // The consumed signal was never sent
+            assert_eq!(c.receive_message().unwrap().to_string(), "Signal Public");
+            for name in &["Dropped", "A", "B"] {
+                c.emit_signal(None, "/", "org.zbus.p2p", name, &()).unwrap();
+            }
+        });
+
+        let c = Connection::new_unix_client(p1, false).unwrap();
+        let secret = c.add_filter(MessageDirection::Outgoing, |msg| {
+            if msg.to_string() == "Signal Secret" {
+                None
+            } else {
+                Some(msg)
+            }
+        });
+        c.emit_signal(None, "/", "org.zbus.p2p", "Secret", &())
+            .unwrap();
+        c.emit_signal(None, "/", "org.zbus.p2p", "Public", &())
+            .unwrap();
+        assert!(c.remove_filter(secret));
+        assert!(!c.remove_filter(secret));
+
+        c.add_filter(MessageDirection::Incoming, |msg| {
+            if msg.to_string() == "Signal Dropped" {
+                None
+            } else {
+                Some(msg)
+            }
+        });
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = {
+            let count = count.clone();
+            c.add_filter(MessageDirection::Incoming, move |msg| {
+                count.fetch_add(1, Ordering::SeqCst);
+                Some(msg)
+            })
+        };
+        c.add_filter(MessageDirection::Incoming, |msg| {
+            if msg.to_string() == "Signal A" {
+                Message::signal(None, None, "/", "org.zbus.p2p", "Transformed", &()).ok()
+            } else {
+                Some(msg)
+            }
+        });
+
+        // Filters run in order: the consumed signal didn't reach the following ones
+        assert_eq!(
+            c.receive_message().unwrap().to_string(),
+            "Signal Transformed"
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(c.remove_filter(counter));
+        assert_eq!(c.receive_message().unwrap().to_string(), "Signal B");
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        server_thread.join().expect("failed to join server thread");
+    }
+
+    #[test]
+    fn queue_overflow() {
+        let guid = Guid::generate();
+        let (p0, p1) = UnixStream::pair().unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let c = Connection::new_unix_server(p0, &guid).unwrap();
+            for _ in 0..2 {
+                let call = c.receive_message().unwrap();
+                for i in 0..4 {
+                    let name = format!("Signal{}", i);
+                    c.emit_signal(None, "/", "org.zbus.p2p", &name, &())
+                        .unwrap();
+                }
+                c.reply(&call, &()).unwrap();
+            }
+            c.emit_signal(None, "/", "org.zbus.p2p", "End", &())
+                .unwrap();
+        });
+
+        let c = Connection::new_unix_client(p1, false)
+            .unwrap()
+            .set_max_queued(2)
+            .set_overflow_policy(OverflowPolicy::DropOldest);
+        assert_eq!(c.overflow_policy(), OverflowPolicy::DropOldest);
+        c.call_method(None, "/", Some("org.zbus.p2p"), "Test", &())
+            .unwrap();
+        assert_eq!(c.dropped_messages(), 2);
+        assert_eq!(c.receive_message().unwrap().to_string(), "Signal Signal2");
+        assert_eq!(c.receive_message().unwrap().to_string(), "Signal Signal3");
+
+        let c = c.set_overflow_policy(OverflowPolicy::Error);
+        let res = c.call_method(None, "/", Some("org.zbus.p2p"), "Test", &());
+        assert!(matches!(res, Err(Error::QueueFull)));
+        assert_eq!(c.dropped_messages(), 3);
+        assert_eq!(c.receive_message().unwrap().to_string(), "Signal Signal0");
+        assert_eq!(c.receive_message().unwrap().to_string(), "Signal Signal1");
+        // The signal arriving after the call failed isn't queued, and its reply is dropped
+        assert_eq!(c.receive_message().unwrap().to_string(), "Signal Signal3");
+        assert_eq!(c.receive_message().unwrap().to_string(), "Signal End");
+
+        server_thread.join().expect("failed to join server thread");
+    }
+
+    #[test]
+    fn disconnection() {
+        let guid = Guid::generate();
+        let (p0, p1) = UnixStream::pair().unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let c = Connection::new_unix_server(p0, &guid).unwrap();
+            c.emit_signal(None, "/", "org.zbus.p2p", "Bye", &())
+                .unwrap();
+            c.close().unwrap();
+            assert!(c.is_closed());
+            // Already closed, so run right away
+            let (tx, rx) = std::sync::mpsc::channel();
+            c.on_close(move || tx.send(()).unwrap());
+            rx.try_recv().unwrap();
+            c.close().unwrap();
+        });
+
+        let c = Connection::new_unix_client(p1, false).unwrap();
+        let closed = Arc::new(AtomicUsize::new(0));
+        {
+            let closed = closed.clone();
+            c.on_close(move || {
+                closed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // The pending signal is received before the connection is found closed
+        assert_eq!(c.receive_message().unwrap().to_string(), "Signal Bye");
+        let m = c.receive_message().unwrap();
+        assert_eq!(m.to_string(), "Signal Disconnected");
+        let h = m.header().unwrap();
+        assert_eq!(h.path().unwrap().unwrap().as_str(), fdo::LOCAL_PATH);
+        assert_eq!(h.interface().unwrap().unwrap(), fdo::LOCAL_INTERFACE);
+        assert!(c.is_closed());
+        assert_eq!(closed.load(Ordering::SeqCst), 1);
+
+        assert!(matches!(c.receive_message(), Err(Error::Io(_))));
+        let res = c.call_method(None, "/", Some("org.zbus.p2p"), "Test", &());
+        assert!(matches!(res, Err(Error::Io(_))));
+        assert_eq!(closed.load(Ordering::SeqCst), 1);
+
+        server_thread.join().expect("failed to join server thread");
+    }
+
     #[test]
     fn serial_monotonically_increases() {
         let c = Connection::new_session().unwrap();
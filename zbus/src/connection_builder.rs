@@ -0,0 +1,457 @@
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use enumflags2::BitFlags;
+
+use crate::fdo::{RequestNameFlags, RequestNameReply};
+use crate::handshake::{
+    AuthMechanism, AuthPolicy, Authenticated, ClientHandshake, DefaultAuthPolicy, ServerHandshake,
+};
+use crate::raw::Socket;
+use crate::{Address, Connection, Error, Guid, OverflowPolicy, Result};
+
+// What to connect to
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+enum Target {
+    Session,
+    System,
+    Address(String),
+    Socket(#[derivative(Debug = "ignore")] Box<dyn Socket>),
+}
+
+/// A builder for [`Connection`].
+///
+/// This is the one place to configure a connection with, before the handshake: what to connect to
+/// (an address or a socket), whether it's a bus or peer-to-peer connection, whether we're the
+/// client or the server, the handshake options, the connection settings and the names to request
+/// once connected to a bus.
+///
+/// # Examples
+///
+/// A connection to the session bus, owning a well-known name:
+///
+/// ```no_run
+///# use std::error::Error;
+///#
+/// use std::time::Duration;
+/// use zbus::ConnectionBuilder;
+///
+/// let conn = ConnectionBuilder::session()
+///     .max_queued(64)
+///     .default_timeout(Some(Duration::from_secs(5)))
+///     .name("org.zbus.MyService", Default::default())
+///     .build()?;
+///# Ok::<_, Box<dyn Error + Send + Sync>>(())
+/// ```
+///
+/// Both sides of a peer-to-peer connection:
+///
+/// ```
+///# use std::error::Error;
+///#
+/// use std::os::unix::net::UnixStream;
+/// use zbus::{ConnectionBuilder, Guid};
+///
+/// let guid = Guid::generate();
+/// let (p0, p1) = UnixStream::pair()?;
+///
+/// let server = std::thread::spawn(move || {
+///     ConnectionBuilder::unix_stream(p0).server().guid(&guid).build()
+/// });
+/// let client = ConnectionBuilder::unix_stream(p1).p2p().build()?;
+/// let server = server.join().unwrap()?;
+/// assert_eq!(client.server_guid(), server.server_guid());
+///# Ok::<_, Box<dyn Error + Send + Sync>>(())
+/// ```
+///
+/// [`Connection`]: struct.Connection.html
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ConnectionBuilder {
+    target: Target,
+    p2p: bool,
+    server: bool,
+    guid: Option<Guid>,
+    auth_mechanisms: Option<Vec<AuthMechanism>>,
+    #[derivative(Debug = "ignore")]
+    auth_policy: Option<Arc<dyn AuthPolicy>>,
+    unix_fd: bool,
+    max_queued: Option<usize>,
+    overflow_policy: Option<OverflowPolicy>,
+    default_timeout: Option<Option<Duration>>,
+    names: Vec<(String, BitFlags<RequestNameFlags>)>,
+}
+
+impl ConnectionBuilder {
+    /// Create a builder for a connection to the session/user message bus.
+    pub fn session() -> Self {
+        Self::new(Target::Session)
+    }
+
+    /// Create a builder for a connection to the system-wide message bus.
+    pub fn system() -> Self {
+        Self::new(Target::System)
+    }
+
+    /// Create a builder for a connection to the given [D-Bus address].
+    ///
+    /// The address can be a `;`-separated list of addresses, like with
+    /// [`Connection::new_for_address`].
+    ///
+    /// [D-Bus address]: https://dbus.freedesktop.org/doc/dbus-specification.html#addresses
+    /// [`Connection::new_for_address`]: struct.Connection.html#method.new_for_address
+    pub fn address(address: &str) -> Self {
+        Self::new(Target::Address(address.to_owned()))
+    }
+
+    /// Create a builder for a connection over the given `UnixStream`.
+    pub fn unix_stream(stream: UnixStream) -> Self {
+        Self::socket(stream)
+    }
+
+    /// Create a builder for a connection over the given socket.
+    pub fn socket<S: Socket + 'static>(socket: S) -> Self {
+        Self::new(Target::Socket(Box::new(socket)))
+    }
+
+    fn new(target: Target) -> Self {
+        Self {
+            target,
+            p2p: false,
+            server: false,
+            guid: None,
+            auth_mechanisms: None,
+            auth_policy: None,
+            unix_fd: true,
+            max_queued: None,
+            overflow_policy: None,
+            default_timeout: None,
+            names: vec![],
+        }
+    }
+
+    /// Make a peer-to-peer connection, instead of a bus connection.
+    ///
+    /// No `Hello` call is made on peer-to-peer connections.
+    pub fn p2p(mut self) -> Self {
+        self.p2p = true;
+
+        self
+    }
+
+    /// Be the server side of a peer-to-peer connection.
+    ///
+    /// This implies [`p2p`]. With an [`address`], building the connection listens on the first
+    /// address of the list that can be listened on, and waits for a single client to connect: the
+    /// address should be one the client knows in advance, such as a `unix:path=` one. Use a
+    /// [`Server`] to serve more clients. Building a connection to the session or system bus fails
+    /// with [`Error::Unsupported`].
+    ///
+    /// [`p2p`]: struct.ConnectionBuilder.html#method.p2p
+    /// [`address`]: struct.ConnectionBuilder.html#method.address
+    /// [`Server`]: struct.Server.html
+    /// [`Error::Unsupported`]: enum.Error.html#variant.Unsupported
+    pub fn server(mut self) -> Self {
+        self.p2p = true;
+        self.server = true;
+
+        self
+    }
+
+    /// Set the GUID of the server.
+    ///
+    /// On the server side, this is the GUID sent to the client, a new one being generated if none
+    /// is set. On the client side, the handshake fails if the server has a different GUID.
+    pub fn guid(mut self, guid: &Guid) -> Self {
+        self.guid = Some(guid.clone());
+
+        self
+    }
+
+    /// Set the authentication mechanisms.
+    ///
+    /// On the client side, these are the mechanisms to try, in order. On the server side, these are
+    /// the mechanisms of the [`DefaultAuthPolicy`], unless an [`auth_policy`] is set.
+    ///
+    /// [`DefaultAuthPolicy`]: handshake/struct.DefaultAuthPolicy.html
+    /// [`auth_policy`]: struct.ConnectionBuilder.html#method.auth_policy
+    pub fn auth_mechanisms(mut self, mechanisms: &[AuthMechanism]) -> Self {
+        self.auth_mechanisms = Some(mechanisms.to_vec());
+
+        self
+    }
+
+    /// Set the policy deciding which clients are allowed to connect, on the server side.
+    pub fn auth_policy(mut self, policy: Arc<dyn AuthPolicy>) -> Self {
+        self.auth_policy = Some(policy);
+
+        self
+    }
+
+    /// Set whether to negotiate file descriptor passing.
+    ///
+    /// This is done by default, if the transport supports it.
+    pub fn unix_fd(mut self, negotiate: bool) -> Self {
+        self.unix_fd = negotiate;
+
+        self
+    }
+
+    /// Set the max number of messages to queue.
+    ///
+    /// See [`Connection::set_max_queued`].
+    ///
+    /// [`Connection::set_max_queued`]: struct.Connection.html#method.set_max_queued
+    pub fn max_queued(mut self, max: usize) -> Self {
+        self.max_queued = Some(max);
+
+        self
+    }
+
+    /// Set what to do with incoming messages once the queue is full.
+    ///
+    /// See [`Connection::set_overflow_policy`].
+    ///
+    /// [`Connection::set_overflow_policy`]: struct.Connection.html#method.set_overflow_policy
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = Some(policy);
+
+        self
+    }
+
+    /// Set the default timeout of method calls.
+    ///
+    /// See [`Connection::set_default_timeout`]. The timeout also applies to the `Hello` call and to
+    /// the name requests.
+    ///
+    /// [`Connection::set_default_timeout`]: struct.Connection.html#method.set_default_timeout
+    pub fn default_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.default_timeout = Some(timeout);
+
+        self
+    }
+
+    /// Request the well-known `name` with `flags`, right after `Hello`.
+    ///
+    /// Names are requested in the order they were added, and only on bus connections. Building the
+    /// connection fails if a request fails, or with [`Error::NameTaken`] if the name ends up owned
+    /// by another peer (whether we're queued for it or not).
+    ///
+    /// [`Error::NameTaken`]: enum.Error.html#variant.NameTaken
+    pub fn name(mut self, name: &str, flags: BitFlags<RequestNameFlags>) -> Self {
+        self.names.push((name.to_owned(), flags));
+
+        self
+    }
+
+    /// Make the connection.
+    ///
+    /// This runs the handshake, then calls `Hello` and requests the names on bus connections.
+    pub fn build(mut self) -> Result<Connection> {
+        let target = std::mem::replace(&mut self.target, Target::Session);
+        let auth = if self.server {
+            let socket = match target {
+                Target::Socket(socket) => socket,
+                Target::Address(address) => Box::new(accept(&address)?),
+                _ => return Err(Error::Unsupported),
+            };
+            self.server_handshake(socket)?
+        } else {
+            self.client_handshake(target)?
+        };
+
+        let mut conn = Connection::new_authenticated(auth);
+        if let Some(max) = self.max_queued {
+            conn = conn.set_max_queued(max);
+        }
+        if let Some(policy) = self.overflow_policy {
+            conn = conn.set_overflow_policy(policy);
+        }
+        if let Some(timeout) = self.default_timeout {
+            conn = conn.set_default_timeout(timeout);
+        }
+
+        if !self.p2p {
+            conn.hello()?;
+
+            for (name, flags) in &self.names {
+                let reply = conn.call_method(
+                    Some("org.freedesktop.DBus"),
+                    "/org/freedesktop/DBus",
+                    Some("org.freedesktop.DBus"),
+                    "RequestName",
+                    &(name, *flags),
+                )?;
+                match reply.body::<RequestNameReply>()? {
+                    RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => (),
+                    RequestNameReply::InQueue | RequestNameReply::Exists => {
+                        return Err(Error::NameTaken(name.clone()))
+                    }
+                }
+            }
+        }
+
+        Ok(conn)
+    }
+
+    fn client_handshake(&self, target: Target) -> Result<Authenticated<Box<dyn Socket>>> {
+        let mut handshake = match target {
+            Target::Session => ClientHandshake::new_session()?,
+            Target::System => ClientHandshake::new_system()?,
+            Target::Address(address) => ClientHandshake::new_for_address(&address)?,
+            Target::Socket(socket) => ClientHandshake::new(socket),
+        };
+        if let Some(guid) = &self.guid {
+            handshake.set_expected_server_guid(guid.clone());
+        }
+        if let Some(mechanisms) = &self.auth_mechanisms {
+            handshake.set_mechanisms(mechanisms);
+        }
+        handshake.set_negotiate_unix_fd(self.unix_fd);
+
+        handshake.blocking_finish()
+    }
+
+    fn server_handshake(&self, socket: Box<dyn Socket>) -> Result<Authenticated<Box<dyn Socket>>> {
+        let guid = self.guid.clone().unwrap_or_else(Guid::generate);
+        let mut handshake = ServerHandshake::with_peer_credentials(socket, guid);
+        let policy = match (&self.auth_policy, &self.auth_mechanisms) {
+            (Some(policy), _) => policy.clone(),
+            (None, Some(mechanisms)) => Arc::new(DefaultAuthPolicy::new().mechanisms(mechanisms)),
+            (None, None) => Arc::new(DefaultAuthPolicy::new()),
+        };
+        handshake.set_auth_policy(policy);
+        handshake.set_negotiate_unix_fd(self.unix_fd);
+
+        handshake.blocking_finish()
+    }
+}
+
+// Listen on the first address of the `;`-separated `addresses` we can, and accept a client there
+fn accept(addresses: &str) -> Result<UnixStream> {
+    let mut error = None;
+    for address in Address::from_list(addresses)? {
+        match address.listen() {
+            Ok(listener) => return listener.accept(),
+            Err(e) => error = Some(e),
+        }
+    }
+
+    Err(error.unwrap_or_else(|| Error::Address("empty address list".into())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::fdo::{self, RequestNameFlags};
+    use crate::handshake::AuthMechanism;
+    use zvariant::Fd;
+
+    use crate::{Connection, ConnectionBuilder, Error, Guid, Message, OverflowPolicy};
+
+    #[test]
+    fn p2p() {
+        let guid = Guid::generate();
+        let (p0, p1) = UnixStream::pair().unwrap();
+
+        let server_guid = guid.clone();
+        let server_thread = thread::spawn(move || {
+            let c = ConnectionBuilder::unix_stream(p0)
+                .server()
+                .guid(&server_guid)
+                .auth_mechanisms(&[AuthMechanism::Anonymous])
+                .build()
+                .unwrap();
+            let call = c.receive_message().unwrap();
+            c.reply(&call, &"yay").unwrap();
+        });
+
+        let c = ConnectionBuilder::unix_stream(p1)
+            .p2p()
+            .guid(&guid)
+            .auth_mechanisms(&[AuthMechanism::External, AuthMechanism::Anonymous])
+            .unix_fd(false)
+            .max_queued(4)
+            .overflow_policy(OverflowPolicy::DropOldest)
+            .default_timeout(Some(Duration::from_secs(5)))
+            .build()
+            .unwrap();
+        assert_eq!(c.server_guid(), guid.as_str());
+        assert_eq!(c.unique_name(), None);
+        assert_eq!(c.max_queued(), 4);
+        assert_eq!(c.overflow_policy(), OverflowPolicy::DropOldest);
+        assert_eq!(c.default_timeout(), Some(Duration::from_secs(5)));
+        let fd_msg = Message::method(None, None, "/", None, "Fd", &Fd::from(0)).unwrap();
+        assert!(matches!(c.send_message(fd_msg), Err(Error::Unsupported)));
+        let reply = c
+            .call_method(None, "/", Some("org.zbus.p2p"), "Test", &())
+            .unwrap();
+        assert_eq!(reply.body::<&str>().unwrap(), "yay");
+
+        server_thread.join().expect("failed to join server thread");
+    }
+
+    #[test]
+    fn bus() {
+        let c = ConnectionBuilder::session()
+            .name(
+                "org.zbus.ConnectionBuilder",
+                RequestNameFlags::DoNotQueue.into(),
+            )
+            .build()
+            .unwrap();
+        let name = c.unique_name().unwrap();
+
+        let other = Connection::new_session().unwrap();
+        let owner = fdo::DBusProxy::new(&other)
+            .unwrap()
+            .get_name_owner("org.zbus.ConnectionBuilder")
+            .unwrap();
+        assert_eq!(owner, name);
+
+        let res = ConnectionBuilder::session()
+            .name(
+                "org.zbus.ConnectionBuilder",
+                RequestNameFlags::DoNotQueue.into(),
+            )
+            .build();
+        assert!(matches!(res, Err(Error::NameTaken(n)) if n == "org.zbus.ConnectionBuilder"));
+
+        let res = ConnectionBuilder::session().server().build();
+        assert!(matches!(res, Err(Error::Unsupported)));
+    }
+
+    #[test]
+    fn server_address() {
+        let address = format!("unix:abstract=/tmp/zbus-builder-{}", std::process::id());
+
+        let server_address = address.clone();
+        let server_thread = thread::spawn(move || {
+            let c = ConnectionBuilder::address(&server_address)
+                .server()
+                .build()
+                .unwrap();
+            let call = c.receive_message().unwrap();
+            c.reply(&call, &"yay").unwrap();
+        });
+
+        // Wait for the server to listen
+        let c = loop {
+            match ConnectionBuilder::address(&address).p2p().build() {
+                Ok(c) => break c,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        let reply = c
+            .call_method(None, "/", Some("org.zbus.p2p"), "Test", &())
+            .unwrap();
+        assert_eq!(reply.body::<&str>().unwrap(), "yay");
+
+        server_thread.join().expect("failed to join server thread");
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::libc;
+use nix::sys::socket::{getsockname, getsockopt, sockopt::PeerCredentials, SockAddr};
+use zvariant::{Fd, OwnedValue};
+
+use crate::{Message, OwnedFd, Result};
+
+// Not exposed by the libc crate for Linux yet. These are the values of all the architectures but
+// alpha, mips, parisc and sparc: the corresponding credentials are not read on the others.
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "mips",
+        target_arch = "mips32r6",
+        target_arch = "mips64",
+        target_arch = "mips64r6",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    ))
+))]
+mod sockopt {
+    use nix::libc::c_int;
+
+    pub(super) const SO_PEERSEC: Option<c_int> = Some(31);
+    pub(super) const SO_PEERGROUPS: Option<c_int> = Some(59);
+    pub(super) const SO_PEERPIDFD: Option<c_int> = Some(77);
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "mips",
+        target_arch = "mips32r6",
+        target_arch = "mips64",
+        target_arch = "mips64r6",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    ))
+)))]
+mod sockopt {
+    use nix::libc::c_int;
+
+    pub(super) const SO_PEERSEC: Option<c_int> = None;
+    pub(super) const SO_PEERGROUPS: Option<c_int> = None;
+    pub(super) const SO_PEERPIDFD: Option<c_int> = None;
+}
+
+/// Credentials of the process on the other end of a connection.
+///
+/// This is the typed version of the `a{sv}` dictionary returned by the
+/// `org.freedesktop.DBus.GetConnectionCredentials` method of the bus, so that credentials of
+/// peer-to-peer and bus peers can be handled the same way: the former are returned by
+/// [`Connection::peer_credentials`], the latter by [`fdo::DBusProxy::connection_credentials`].
+///
+/// Each credential is optional, as not all of them are available on all systems and transports.
+///
+/// [`Connection::peer_credentials`]: struct.Connection.html#method.peer_credentials
+/// [`fdo::DBusProxy::connection_credentials`]: fdo/struct.DBusProxy.html#method.connection_credentials
+#[derive(Debug, Default)]
+pub struct ConnectionCredentials {
+    unix_user_id: Option<u32>,
+    unix_group_ids: Option<Vec<u32>>,
+    process_id: Option<u32>,
+    linux_security_label: Option<Vec<u8>>,
+    process_fd: Option<OwnedFd>,
+}
+
+impl ConnectionCredentials {
+    /// The numeric Unix user ID (`UnixUserID`).
+    pub fn unix_user_id(&self) -> Option<u32> {
+        self.unix_user_id
+    }
+
+    /// The numeric Unix group IDs, including the primary group (`UnixGroupIDs`).
+    pub fn unix_group_ids(&self) -> Option<&[u32]> {
+        self.unix_group_ids.as_deref()
+    }
+
+    /// The numeric process ID (`ProcessID`).
+    pub fn process_id(&self) -> Option<u32> {
+        self.process_id
+    }
+
+    /// The Linux security label, as returned by the `SO_PEERSEC` socket option
+    /// (`LinuxSecurityLabel`).
+    ///
+    /// The label is in an unspecified ASCII-compatible encoding, and is followed by a single zero
+    /// byte. It's only available if a Linux security module, such as SELinux or AppArmor, is
+    /// active.
+    pub fn linux_security_label(&self) -> Option<&[u8]> {
+        self.linux_security_label.as_deref()
+    }
+
+    /// A pidfd referring to the process (`ProcessFD`).
+    ///
+    /// Unlike the [`process_id`], it can not end up referring to another process once the peer
+    /// exits. It's only available on Linux 6.5 or later.
+    ///
+    /// [`process_id`]: struct.ConnectionCredentials.html#method.process_id
+    pub fn process_fd(&self) -> Option<&OwnedFd> {
+        self.process_fd.as_ref()
+    }
+
+    /// Read the credentials of the peer of the socket `fd`.
+    ///
+    /// Only Unix sockets carry credentials: none are returned for other sockets. The primary group
+    /// of the peer is the first of its group IDs.
+    pub(crate) fn from_socket(fd: RawFd) -> Self {
+        if !matches!(getsockname(fd), Ok(SockAddr::Unix(_))) {
+            return Self::default();
+        }
+
+        let creds = getsockopt(fd, PeerCredentials).ok();
+        // The primary group comes first
+        let unix_group_ids = creds.map(|creds| {
+            let gid = creds.gid();
+            let mut gids = peer_groups(fd).unwrap_or_default();
+            gids.retain(|g| *g != gid);
+            gids.insert(0, gid);
+
+            gids
+        });
+
+        Self {
+            unix_user_id: creds.map(|creds| creds.uid()),
+            unix_group_ids,
+            process_id: creds.map(|creds| creds.pid() as u32),
+            linux_security_label: peer_security_label(fd),
+            process_fd: peer_pidfd(fd),
+        }
+    }
+
+    /// Parse the reply to a `GetConnectionCredentials` call.
+    ///
+    /// Unknown keys are ignored. The process fd is duplicated, so that it outlives `reply`.
+    pub(crate) fn from_reply(reply: &Message) -> Result<Self> {
+        let mut credentials = Self::default();
+        let dict: HashMap<String, OwnedValue> = reply.body()?;
+        for (key, value) in dict {
+            match key.as_str() {
+                "UnixUserID" => credentials.unix_user_id = Some(u32::try_from(value)?),
+                "UnixGroupIDs" => credentials.unix_group_ids = Some(Vec::try_from(value)?),
+                "ProcessID" => credentials.process_id = Some(u32::try_from(value)?),
+                "LinuxSecurityLabel" => {
+                    credentials.linux_security_label = Some(Vec::try_from(value)?)
+                }
+                "ProcessFD" => {
+                    let fd = fcntl(
+                        Fd::try_from(value)?.as_raw_fd(),
+                        FcntlArg::F_DUPFD_CLOEXEC(0),
+                    )?;
+                    credentials.process_fd = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+                }
+                _ => (),
+            }
+        }
+
+        Ok(credentials)
+    }
+}
+
+// Read a variable-size socket option into a buffer of `T`, growing it as long as it's too small
+fn getsockopt_vec<T: Copy + Default>(fd: RawFd, opt: libc::c_int) -> Option<Vec<T>> {
+    let mut buf = vec![T::default(); 64];
+    loop {
+        let mut len = (buf.len() * size_of::<T>()) as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                opt,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res == 0 {
+            buf.truncate(len as usize / size_of::<T>());
+
+            return Some(buf);
+        }
+        // On `ERANGE`, `len` is set to the needed size
+        if nix::errno::Errno::last() != nix::errno::Errno::ERANGE {
+            return None;
+        }
+        buf.resize(len as usize / size_of::<T>() + 1, T::default());
+    }
+}
+
+fn peer_groups(fd: RawFd) -> Option<Vec<u32>> {
+    getsockopt_vec::<libc::gid_t>(fd, sockopt::SO_PEERGROUPS?)
+}
+
+fn peer_security_label(fd: RawFd) -> Option<Vec<u8>> {
+    let mut label = getsockopt_vec::<u8>(fd, sockopt::SO_PEERSEC?)?;
+    // Some security modules include the trailing zero byte, others don't
+    while label.last() == Some(&0) {
+        label.pop();
+    }
+    if label.is_empty() {
+        return None;
+    }
+    label.push(0);
+
+    Some(label)
+}
+
+fn peer_pidfd(fd: RawFd) -> Option<OwnedFd> {
+    let opt = sockopt::SO_PEERPIDFD?;
+    let mut pidfd: libc::c_int = -1;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            opt,
+            &mut pidfd as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if res == 0 && pidfd >= 0 {
+        Some(unsafe { OwnedFd::from_raw_fd(pidfd) })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    use nix::unistd::{Gid, Uid};
+    use zvariant::{Fd, Value};
+
+    use crate::fdo;
+    use crate::{Connection, ConnectionCredentials, Message};
+
+    #[test]
+    fn socket() {
+        let (p0, _p1) = UnixStream::pair().unwrap();
+        let creds = ConnectionCredentials::from_socket(p0.as_raw_fd());
+        assert_eq!(creds.unix_user_id(), Some(Uid::current().as_raw()));
+        assert_eq!(creds.process_id(), Some(std::process::id()));
+        assert_eq!(
+            creds.unix_group_ids().unwrap().first(),
+            Some(&Gid::effective().as_raw())
+        );
+        if let Some(label) = creds.linux_security_label() {
+            assert_eq!(label.last(), Some(&0));
+        }
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let creds = ConnectionCredentials::from_socket(tcp.as_raw_fd());
+        assert_eq!(creds.unix_user_id(), None);
+        assert_eq!(creds.process_id(), None);
+    }
+
+    #[test]
+    fn reply() {
+        let (p0, _p1) = UnixStream::pair().unwrap();
+        let call = Message::method(None, None, "/", None, "GetConnectionCredentials", &()).unwrap();
+        let mut dict = HashMap::new();
+        dict.insert("UnixUserID", Value::from(1000u32));
+        dict.insert("ProcessFD", Value::from(Fd::from(p0.as_raw_fd())));
+        let reply = Message::method_reply(None, &call, &dict).unwrap();
+
+        let creds = ConnectionCredentials::from_reply(&reply).unwrap();
+        assert_eq!(creds.unix_user_id(), Some(1000));
+        assert_eq!(creds.process_id(), None);
+        // The fd is duplicated, without leaking into child processes
+        let fd = creds.process_fd().unwrap().as_raw_fd();
+        assert_ne!(fd, p0.as_raw_fd());
+        let flags = FdFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFD).unwrap());
+        assert!(flags.contains(FdFlag::FD_CLOEXEC));
+    }
+
+    #[test]
+    fn bus() {
+        let conn = Connection::new_session().unwrap();
+        let proxy = fdo::DBusProxy::new(&conn).unwrap();
+        let creds = proxy
+            .connection_credentials(conn.unique_name().unwrap())
+            .unwrap();
+        assert_eq!(creds.unix_user_id(), Some(Uid::current().as_raw()));
+        assert_eq!(creds.process_id(), Some(std::process::id()));
+
+        // Our peer is the bus itself. Its process ID is not compared, as the socket records the
+        // process that created it, which may have forked since.
+        let bus = proxy
+            .connection_credentials("org.freedesktop.DBus")
+            .unwrap();
+        assert_eq!(bus.unix_user_id(), conn.peer_credentials().unix_user_id());
+        assert!(conn.peer_credentials().process_id().is_some());
+    }
+}
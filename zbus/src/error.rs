@@ -12,6 +12,8 @@ pub enum Error {
     InterfaceNotFound,
     /// Invalid D-Bus address.
     Address(String),
+    /// Invalid match rule.
+    MatchRule(String),
     /// An I/O error.
     Io(io::Error),
     /// Message parsing error.
@@ -30,6 +32,12 @@ pub enum Error {
     InvalidGUID,
     /// Unsupported function, or support currently lacking.
     Unsupported,
+    /// A method call timed out.
+    Timeout,
+    /// The incoming message queue is full.
+    QueueFull,
+    /// The requested well-known name is owned by another peer.
+    NameTaken(String),
     /// Thread-local connection is not set.
     #[deprecated(since = "1.1.2", note = "No longer returned by any of our API")]
     NoTLSConnection,
@@ -52,6 +60,7 @@ impl error::Error for Error {
         match self {
             Error::InterfaceNotFound => None,
             Error::Address(_) => None,
+            Error::MatchRule(_) => None,
             Error::Io(e) => Some(e),
             Error::Handshake(_) => None,
             Error::Message(e) => Some(e),
@@ -60,6 +69,9 @@ impl error::Error for Error {
             Error::MethodError(_, _, _) => None,
             Error::InvalidGUID => None,
             Error::Unsupported => None,
+            Error::Timeout => None,
+            Error::QueueFull => None,
+            Error::NameTaken(_) => None,
             #[allow(deprecated)]
             Error::NoTLSConnection => None,
             #[allow(deprecated)]
@@ -73,6 +85,7 @@ impl fmt::Display for Error {
         match self {
             Error::InterfaceNotFound => write!(f, "Interface not found"),
             Error::Address(e) => write!(f, "address error: {}", e),
+            Error::MatchRule(e) => write!(f, "match rule error: {}", e),
             Error::Io(e) => write!(f, "I/O error: {}", e),
             Error::Handshake(e) => write!(f, "D-Bus handshake failed: {}", e),
             Error::Message(e) => write!(f, "Message creation error: {}", e),
@@ -86,6 +99,9 @@ impl fmt::Display for Error {
             ),
             Error::InvalidGUID => write!(f, "Invalid GUID"),
             Error::Unsupported => write!(f, "Connection support is lacking"),
+            Error::Timeout => write!(f, "Method call timed out"),
+            Error::QueueFull => write!(f, "Incoming message queue is full"),
+            Error::NameTaken(name) => write!(f, "Name '{}' is owned by another peer", name),
             #[allow(deprecated)]
             Error::NoTLSConnection => write!(f, "No TLS connection"),
             #[allow(deprecated)]
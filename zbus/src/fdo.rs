@@ -9,7 +9,15 @@ use serde_repr::{Deserialize_repr, Serialize_repr};
 use std::collections::HashMap;
 use zvariant::{derive::Type, OwnedObjectPath, OwnedValue, Value};
 
-use crate::{dbus_proxy, DBusError};
+use crate::{dbus_proxy, ConnectionCredentials, DBusError};
+
+/// The object path of the local messages, synthesized by the connection itself.
+///
+/// Such as the `Disconnected` signal, received once the connection is closed.
+pub const LOCAL_PATH: &str = "/org/freedesktop/DBus/Local";
+
+/// The interface of the local messages, synthesized by the connection itself.
+pub const LOCAL_INTERFACE: &str = "org.freedesktop.DBus.Local";
 
 /// Proxy for the `org.freedesktop.DBus.Introspectable` interface.
 #[dbus_proxy(interface = "org.freedesktop.DBus.Introspectable", default_path = "/")]
@@ -251,6 +259,22 @@ trait DBus {
     fn interfaces(&self) -> Result<Vec<String>>;
 }
 
+impl<'c> DBusProxy<'c> {
+    /// Returns as many credentials as possible for the process connected to the server, typed.
+    ///
+    /// This is [`get_connection_credentials`], returning the same [`ConnectionCredentials`] as
+    /// [`Connection::peer_credentials`] does for peer-to-peer connections.
+    ///
+    /// [`get_connection_credentials`]: struct.DBusProxy.html#method.get_connection_credentials
+    /// [`ConnectionCredentials`]: ../struct.ConnectionCredentials.html
+    /// [`Connection::peer_credentials`]: ../struct.Connection.html#method.peer_credentials
+    pub fn connection_credentials(&self, bus_name: &str) -> Result<ConnectionCredentials> {
+        let reply = self.call_method("GetConnectionCredentials", &bus_name)?;
+
+        ConnectionCredentials::from_reply(&reply).map_err(Error::from)
+    }
+}
+
 /// Errors from https://gitlab.freedesktop.org/dbus/dbus/-/blob/master/dbus/dbus-protocol.h
 #[derive(Debug, DBusError, PartialEq)]
 #[dbus_error(prefix = "org.freedesktop.DBus.Error")]
@@ -1,18 +1,202 @@
+use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::env;
-use std::io::BufRead;
-use std::os::unix::io::AsRawFd;
-use std::os::unix::net::UnixStream;
+use std::fmt;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use nix::poll::PollFlags;
 use nix::unistd::Uid;
 
 use crate::address::Address;
 use crate::guid::Guid;
+use crate::keyring::{self, random_hex};
 use crate::raw::{Connection, Socket};
+use crate::sha1::sha1;
 use crate::utils::wait_on;
-use crate::{Error, Result};
+use crate::{ConnectionCredentials, Error, Result};
+
+// The maximum length of a handshake command, in bytes.
+const MAX_COMMAND_LENGTH: usize = 16 * 1024;
+// The maximum number of commands a peer may send during a handshake.
+const MAX_COMMANDS: usize = 64;
+// The maximum number of failed authentication attempts, like the reference implementation.
+const MAX_FAILURES: usize = 6;
+
+/// A SASL authentication mechanism.
+///
+/// See the D-Bus specification [authentication mechanisms chapter] for details.
+///
+/// [authentication mechanisms chapter]: https://dbus.freedesktop.org/doc/dbus-specification.html#auth-mechanisms
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMechanism {
+    /// The EXTERNAL mechanism, relying on the credentials of the socket peer.
+    External,
+    /// The DBUS_COOKIE_SHA1 mechanism, relying on a secret cookie shared through the
+    /// `~/.dbus-keyrings` directory of the user.
+    Cookie,
+    /// The ANONYMOUS mechanism, authenticating no one in particular.
+    Anonymous,
+}
+
+impl fmt::Display for AuthMechanism {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mech = match self {
+            AuthMechanism::External => "EXTERNAL",
+            AuthMechanism::Cookie => "DBUS_COOKIE_SHA1",
+            AuthMechanism::Anonymous => "ANONYMOUS",
+        };
+        write!(f, "{}", mech)
+    }
+}
+
+impl FromStr for AuthMechanism {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "EXTERNAL" => Ok(AuthMechanism::External),
+            "DBUS_COOKIE_SHA1" => Ok(AuthMechanism::Cookie),
+            "ANONYMOUS" => Ok(AuthMechanism::Anonymous),
+            _ => Err(Error::Handshake(format!("Unknown mechanism: {}", s))),
+        }
+    }
+}
+
+/// A client authentication attempt, as seen by an [`AuthPolicy`].
+///
+/// [`AuthPolicy`]: trait.AuthPolicy.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthRequest {
+    /// The mechanism the client authenticates with.
+    pub mechanism: AuthMechanism,
+    /// The uid the client claims to be, if the mechanism carries one.
+    ///
+    /// The claim has been verified when the policy is consulted: for `EXTERNAL`, it's the uid of
+    /// the socket peer, and for `DBUS_COOKIE_SHA1`, the client proved it can read our keyring.
+    pub claimed_uid: Option<u32>,
+    /// The uid of the socket peer, if known.
+    pub peer_uid: Option<u32>,
+    /// The gid of the socket peer, if known.
+    pub peer_gid: Option<u32>,
+    /// The process ID of the socket peer, if known.
+    pub peer_pid: Option<u32>,
+}
+
+/// A server-side authentication policy.
+///
+/// [`ServerHandshake`] consults its policy for the mechanisms to offer, and for each client trying
+/// to authenticate. Any `Fn(&AuthRequest) -> bool` closure is a policy only offering `EXTERNAL`.
+///
+/// [`ServerHandshake`]: struct.ServerHandshake.html
+pub trait AuthPolicy: Send + Sync {
+    /// The mechanisms offered to clients.
+    fn mechanisms(&self) -> Vec<AuthMechanism> {
+        vec![AuthMechanism::External]
+    }
+
+    /// Whether the client making `request` is allowed to connect.
+    fn authorize(&self, request: &AuthRequest) -> bool;
+}
+
+impl<F> AuthPolicy for F
+where
+    F: Fn(&AuthRequest) -> bool + Send + Sync,
+{
+    fn authorize(&self, request: &AuthRequest) -> bool {
+        self(request)
+    }
+}
+
+/// The default [`AuthPolicy`].
+///
+/// By default, only `EXTERNAL` is offered, and any client whose claimed uid matches the uid of the
+/// socket peer is accepted. Clients can be further restricted to a set of uids and gids: a client
+/// is then accepted if either its uid or the primary gid of the socket peer is in the
+/// corresponding set.
+///
+/// `DBUS_COOKIE_SHA1` uses the keyring of the user running the server, and thus only authenticates
+/// clients running as the same user. Note that offering `ANONYMOUS` lets anyone connect.
+///
+/// ```
+/// use zbus::handshake::{AuthMechanism, DefaultAuthPolicy};
+///
+/// let policy = DefaultAuthPolicy::new()
+///     .mechanisms(&[AuthMechanism::External, AuthMechanism::Cookie])
+///     .allow_uid(1000)
+///     .allow_gid(100);
+/// ```
+///
+/// [`AuthPolicy`]: trait.AuthPolicy.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct DefaultAuthPolicy {
+    mechanisms: Vec<AuthMechanism>,
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+}
+
+impl DefaultAuthPolicy {
+    /// Create the default policy.
+    pub fn new() -> Self {
+        DefaultAuthPolicy {
+            mechanisms: vec![AuthMechanism::External],
+            uids: vec![],
+            gids: vec![],
+        }
+    }
+
+    /// Set the mechanisms to offer.
+    pub fn mechanisms(mut self, mechanisms: &[AuthMechanism]) -> Self {
+        self.mechanisms = mechanisms.to_vec();
+
+        self
+    }
+
+    /// Accept clients authenticated as `uid`.
+    pub fn allow_uid(mut self, uid: u32) -> Self {
+        self.uids.push(uid);
+
+        self
+    }
+
+    /// Accept clients whose socket peer has `gid` as its primary group.
+    ///
+    /// Supplementary groups are not taken into account.
+    pub fn allow_gid(mut self, gid: u32) -> Self {
+        self.gids.push(gid);
+
+        self
+    }
+}
+
+impl Default for DefaultAuthPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AuthPolicy for DefaultAuthPolicy {
+    fn mechanisms(&self) -> Vec<AuthMechanism> {
+        self.mechanisms.clone()
+    }
+
+    fn authorize(&self, request: &AuthRequest) -> bool {
+        if !self.mechanisms.contains(&request.mechanism) {
+            return false;
+        }
+        let uid = match (request.mechanism, request.claimed_uid) {
+            (AuthMechanism::External, Some(uid)) if request.peer_uid == Some(uid) => uid,
+            (AuthMechanism::Cookie, Some(uid)) => uid,
+            (AuthMechanism::Anonymous, _) => return true,
+            _ => return false,
+        };
+
+        (self.uids.is_empty() && self.gids.is_empty())
+            || self.uids.contains(&uid)
+            || matches!(request.peer_gid, Some(gid) if self.gids.contains(&gid))
+    }
+}
 
 /*
  * Client-side handshake logic
@@ -23,6 +207,8 @@ enum ClientHandshakeStep {
     Init,
     SendingOauth,
     WaitOauth,
+    SendingCancel,
+    WaitReject,
     SendingNegociateFd,
     WaitNegociateFd,
     SendingBegin,
@@ -51,7 +237,19 @@ pub struct ClientHandshake<S> {
     buffer: Vec<u8>,
     step: ClientHandshakeStep,
     server_guid: Option<Guid>,
+    // The GUID the server is expected to reply with, if known (from the `guid` address option)
+    expected_guid: Option<Guid>,
     cap_unix_fd: bool,
+    // Whether to negotiate FD passing, if the transport supports it
+    negotiate_unix_fd: bool,
+    // Nonce to send before the SASL exchange (nonce-tcp transport)
+    nonce: Option<Vec<u8>>,
+    // The mechanisms left to try, the first one being the one in use
+    mechanisms: VecDeque<AuthMechanism>,
+    // The number of commands received so far
+    commands: usize,
+    // The DBUS_COOKIE_SHA1 keyrings directory, `~/.dbus-keyrings` if unset
+    keyring_dir: Option<PathBuf>,
 }
 
 /// The result of a finalized handshake
@@ -69,6 +267,20 @@ pub struct Authenticated<S> {
     pub(crate) server_guid: Guid,
     /// Whether file descriptor passing has been accepted by both sides
     pub(crate) cap_unix_fd: bool,
+    /// The credentials of the peer, read from the socket during the handshake
+    pub(crate) peer_credentials: ConnectionCredentials,
+}
+
+impl<S: Socket + 'static> Authenticated<S> {
+    /// Erase the type of the underlying socket.
+    pub(crate) fn into_boxed(self) -> Authenticated<Box<dyn Socket>> {
+        Authenticated {
+            conn: self.conn.into_boxed(),
+            server_guid: self.server_guid,
+            cap_unix_fd: self.cap_unix_fd,
+            peer_credentials: self.peer_credentials,
+        }
+    }
 }
 
 impl<S: Socket> ClientHandshake<S> {
@@ -79,10 +291,90 @@ impl<S: Socket> ClientHandshake<S> {
             buffer: Vec::new(),
             step: ClientHandshakeStep::Init,
             server_guid: None,
+            expected_guid: None,
             cap_unix_fd: false,
+            negotiate_unix_fd: true,
+            nonce: None,
+            mechanisms: vec![
+                AuthMechanism::External,
+                AuthMechanism::Cookie,
+                AuthMechanism::Anonymous,
+            ]
+            .into(),
+            commands: 0,
+            keyring_dir: None,
         }
     }
 
+    /// Set the GUID the server is expected to have.
+    ///
+    /// The handshake fails if the server replies with a different GUID. This is done
+    /// automatically for addresses carrying a `guid` option.
+    pub fn set_expected_server_guid(&mut self, guid: Guid) {
+        self.expected_guid = Some(guid);
+    }
+
+    /// Set the authentication mechanisms to try, in order.
+    ///
+    /// By default, `EXTERNAL` is tried first, then `DBUS_COOKIE_SHA1` and finally `ANONYMOUS`.
+    /// Mechanisms the server doesn't list as supported when rejecting one are skipped.
+    pub fn set_mechanisms(&mut self, mechanisms: &[AuthMechanism]) {
+        self.mechanisms = mechanisms.iter().copied().collect();
+    }
+
+    /// Set whether to negotiate file descriptor passing.
+    ///
+    /// This is done by default, if the transport supports it.
+    pub fn set_negotiate_unix_fd(&mut self, negotiate: bool) {
+        self.negotiate_unix_fd = negotiate;
+    }
+
+    // Use the DBUS_COOKIE_SHA1 keyrings in `dir` rather than in `~/.dbus-keyrings`.
+    #[cfg(test)]
+    pub(crate) fn set_keyring_dir(&mut self, dir: PathBuf) {
+        self.keyring_dir = Some(dir);
+    }
+
+    // The AUTH command for the current mechanism
+    fn auth_command(&self) -> Result<String> {
+        let mech = self
+            .mechanisms
+            .front()
+            .ok_or_else(|| Error::Handshake("Exhausted available AUTH mechanisms".to_string()))?;
+        let initial_response = match mech {
+            // EXTERNAL and DBUS_COOKIE_SHA1 identify us by our uid.
+            AuthMechanism::External | AuthMechanism::Cookie => Uid::current().to_string(),
+            // ANONYMOUS takes an optional trace string, that servers may log.
+            AuthMechanism::Anonymous => "zbus".to_string(),
+        };
+
+        Ok(format!(
+            "AUTH {} {}\r\n",
+            mech,
+            hex_encode(initial_response.as_bytes())
+        ))
+    }
+
+    // The response to the DBUS_COOKIE_SHA1 challenge from the server
+    fn cookie_response(&self, data: &str) -> Result<String> {
+        let data = String::from_utf8(hex_decode(data)?)
+            .map_err(|_| Error::Handshake("Invalid cookie challenge".to_string()))?;
+        let mut words = data.split(' ');
+        let (context, id, server_challenge) = match (words.next(), words.next(), words.next()) {
+            (Some(context), Some(id), Some(challenge)) => (context, id, challenge),
+            _ => return Err(Error::Handshake("Invalid cookie challenge".to_string())),
+        };
+        let id = id
+            .parse()
+            .map_err(|_| Error::Handshake(format!("Invalid cookie ID: {}", id)))?;
+        let cookie = keyring::lookup(self.keyring_dir.as_deref(), context, id)?;
+        let client_challenge = random_hex(16)?;
+        let hash = sha1(format!("{}:{}:{}", server_challenge, client_challenge, cookie).as_bytes());
+        let response = format!("{} {}", client_challenge, hex_encode(&hash));
+
+        Ok(format!("DATA {}\r\n", hex_encode(response.as_bytes())))
+    }
+
     fn flush_buffer(&mut self) -> Result<()> {
         while !self.buffer.is_empty() {
             let written = self.socket.sendmsg(&self.buffer, &[])?;
@@ -91,15 +383,36 @@ impl<S: Socket> ClientHandshake<S> {
         Ok(())
     }
 
-    fn read_command(&mut self) -> Result<()> {
-        while !self.buffer.ends_with(b"\r\n") {
-            let mut buf = [0; 40];
-            let (read, _) = self.socket.recvmsg(&mut buf)?;
-            self.buffer.extend(&buf[..read]);
+    fn read_command(&mut self) -> Result<String> {
+        read_command(&mut self.socket, &mut self.buffer, &mut self.commands)
+    }
+
+    // Move on to the next mechanism, skipping those not in the `REJECTED` list of the server
+    fn next_mechanism(&mut self, rejected: &str) -> Result<()> {
+        let supported: Vec<_> = rejected
+            .split_whitespace()
+            .skip(1)
+            .filter_map(|m| m.parse::<AuthMechanism>().ok())
+            .collect();
+        self.mechanisms.pop_front();
+        while let Some(mech) = self.mechanisms.front() {
+            if supported.is_empty() || supported.contains(mech) {
+                break;
+            }
+            self.mechanisms.pop_front();
         }
+        self.buffer = self.auth_command()?.into();
+        self.step = ClientHandshakeStep::SendingOauth;
+
         Ok(())
     }
 
+    // Give up on the current mechanism, the server will reply with `REJECTED`
+    fn cancel(&mut self) {
+        self.buffer = Vec::from(&b"CANCEL\r\n"[..]);
+        self.step = ClientHandshakeStep::SendingCancel;
+    }
+
     /// Attempt to advance the handshake
     ///
     /// In non-blocking mode, you need to invoke this method repeatedly
@@ -114,13 +427,12 @@ impl<S: Socket> ClientHandshake<S> {
         loop {
             match self.step {
                 ClientHandshakeStep::Init => {
+                    // nonce-tcp requires the nonce to be sent before anything else
+                    let mut buffer = self.nonce.take().unwrap_or_default();
                     // send the SASL handshake
-                    let uid_str = Uid::current()
-                        .to_string()
-                        .chars()
-                        .map(|c| format!("{:x}", c as u32))
-                        .collect::<String>();
-                    self.buffer = format!("\0AUTH EXTERNAL {}\r\n", uid_str).into();
+                    buffer.push(b'\0');
+                    buffer.extend(self.auth_command()?.as_bytes());
+                    self.buffer = buffer;
                     self.step = ClientHandshakeStep::SendingOauth;
                 }
                 ClientHandshakeStep::SendingOauth => {
@@ -128,37 +440,87 @@ impl<S: Socket> ClientHandshake<S> {
                     self.step = ClientHandshakeStep::WaitOauth;
                 }
                 ClientHandshakeStep::WaitOauth => {
-                    self.read_command()?;
-                    let mut reply = String::new();
-                    (&self.buffer[..]).read_line(&mut reply)?;
+                    let reply = self.read_command()?;
                     let mut words = reply.split_whitespace();
                     // We expect a 2 words answer "OK" and the server Guid
                     let guid = match (words.next(), words.next(), words.next()) {
                         (Some("OK"), Some(guid), None) => guid.try_into()?,
+                        (Some("REJECTED"), _, _) => {
+                            self.next_mechanism(&reply)?;
+                            continue;
+                        }
+                        (Some("DATA"), data, None) => {
+                            let response = match (self.mechanisms.front(), data) {
+                                (Some(AuthMechanism::Cookie), Some(data)) => {
+                                    self.cookie_response(data).ok()
+                                }
+                                _ => None,
+                            };
+                            match response {
+                                Some(response) => {
+                                    self.buffer = response.into();
+                                    self.step = ClientHandshakeStep::SendingOauth;
+                                }
+                                // We can't answer this challenge, so let's try another mechanism
+                                None => self.cancel(),
+                            }
+                            continue;
+                        }
+                        (Some("ERROR"), _, _) => {
+                            self.cancel();
+                            continue;
+                        }
                         _ => {
-                            return Err(Error::Handshake(
-                                "Unexpected server AUTH reply".to_string(),
-                            ))
+                            self.buffer = Vec::from(&b"ERROR Unexpected command\r\n"[..]);
+                            self.step = ClientHandshakeStep::SendingOauth;
+                            continue;
                         }
                     };
+                    if let Some(expected) = &self.expected_guid {
+                        if *expected != guid {
+                            return Err(Error::Handshake(format!(
+                                "Server GUID mismatch: expected {}, got {}",
+                                expected, guid
+                            )));
+                        }
+                    }
                     self.server_guid = Some(guid);
-                    self.buffer = Vec::from(&b"NEGOTIATE_UNIX_FD\r\n"[..]);
-                    self.step = ClientHandshakeStep::SendingNegociateFd;
+                    if self.negotiate_unix_fd && self.socket.can_pass_unix_fd() {
+                        self.buffer = Vec::from(&b"NEGOTIATE_UNIX_FD\r\n"[..]);
+                        self.step = ClientHandshakeStep::SendingNegociateFd;
+                    } else {
+                        // No point in negotiating FD passing on a transport that can't do it.
+                        self.buffer = Vec::from(&b"BEGIN\r\n"[..]);
+                        self.step = ClientHandshakeStep::SendingBegin;
+                    }
+                }
+                ClientHandshakeStep::SendingCancel => {
+                    self.flush_buffer()?;
+                    self.step = ClientHandshakeStep::WaitReject;
+                }
+                ClientHandshakeStep::WaitReject => {
+                    let reply = self.read_command()?;
+                    if reply.split_whitespace().next() != Some("REJECTED") {
+                        return Err(Error::Handshake(
+                            "Unexpected server reply to CANCEL".to_string(),
+                        ));
+                    }
+                    self.next_mechanism(&reply)?;
                 }
                 ClientHandshakeStep::SendingNegociateFd => {
                     self.flush_buffer()?;
                     self.step = ClientHandshakeStep::WaitNegociateFd;
                 }
                 ClientHandshakeStep::WaitNegociateFd => {
-                    self.read_command()?;
-                    if self.buffer.starts_with(b"AGREE_UNIX_FD") {
-                        self.cap_unix_fd = true;
-                    } else if self.buffer.starts_with(b"ERROR") {
-                        self.cap_unix_fd = false;
-                    } else {
-                        return Err(Error::Handshake(
-                            "Unexpected server UNIX_FD reply".to_string(),
-                        ));
+                    let reply = self.read_command()?;
+                    match reply.split_whitespace().next() {
+                        Some("AGREE_UNIX_FD") => self.cap_unix_fd = true,
+                        Some("ERROR") => self.cap_unix_fd = false,
+                        _ => {
+                            return Err(Error::Handshake(
+                                "Unexpected server UNIX_FD reply".to_string(),
+                            ))
+                        }
                     }
                     self.buffer = Vec::from(&b"BEGIN\r\n"[..]);
                     self.step = ClientHandshakeStep::SendingBegin;
@@ -178,102 +540,144 @@ impl<S: Socket> ClientHandshake<S> {
     /// returned `Ok(())`. Otherwise it'll error and return you the object.
     pub fn try_finish(self) -> std::result::Result<Authenticated<S>, Self> {
         if let ClientHandshakeStep::Done = self.step {
+            // Read them right away, while the server is known to be there
+            let peer_credentials = ConnectionCredentials::from_socket(self.socket.as_raw_fd());
+
             Ok(Authenticated {
                 conn: Connection::wrap(self.socket),
                 server_guid: self.server_guid.unwrap(),
                 cap_unix_fd: self.cap_unix_fd,
+                peer_credentials,
             })
         } else {
             Err(self)
         }
     }
 
+    /// Block and automatically drive the handshake for this client
+    ///
+    /// This method will block until the handshake is finalized, even if the
+    /// socket is in non-blocking mode.
+    pub fn blocking_finish(mut self) -> Result<Authenticated<S>> {
+        loop {
+            match self.advance_handshake() {
+                Ok(()) => return Ok(self.try_finish().unwrap_or_else(|_| unreachable!())),
+                Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    // we raised a WouldBlock error, this means this is a non-blocking socket
+                    // we use poll to wait until the action we need is available
+                    wait_on(self.socket.as_raw_fd(), self.wait_flags())?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     /// Access the socket backing this handshake
     ///
     /// Would typically be used to register it for readiness.
     pub fn socket(&self) -> &S {
         &self.socket
     }
+
+    // The readiness to wait for, once `advance_handshake` failed with `WouldBlock`
+    pub(crate) fn wait_flags(&self) -> PollFlags {
+        match self.step {
+            ClientHandshakeStep::SendingOauth
+            | ClientHandshakeStep::SendingCancel
+            | ClientHandshakeStep::SendingNegociateFd
+            | ClientHandshakeStep::SendingBegin => PollFlags::POLLOUT,
+            ClientHandshakeStep::WaitOauth
+            | ClientHandshakeStep::WaitReject
+            | ClientHandshakeStep::WaitNegociateFd => PollFlags::POLLIN,
+            ClientHandshakeStep::Init | ClientHandshakeStep::Done => unreachable!(),
+        }
+    }
 }
 
-impl ClientHandshake<UnixStream> {
+impl ClientHandshake<Box<dyn Socket>> {
     /// Initialize a handshake to the session/user message bus.
     ///
     /// The socket backing this connection is created in blocking mode.
     pub fn new_session() -> Result<Self> {
-        session_socket().map(Self::new)
+        Self::new_for_addresses(&session_address(), false)
     }
 
     /// Initialize a handshake to the session/user message bus.
     ///
     /// The socket backing this connection is created in non-blocking mode.
     pub fn new_session_nonblock() -> Result<Self> {
-        let socket = session_socket()?;
-        socket.set_nonblocking(true)?;
-        Ok(Self::new(socket))
+        Self::new_for_addresses(&session_address(), true)
     }
 
     /// Initialize a handshake to the system-wide message bus.
     ///
     /// The socket backing this connection is created in blocking mode.
     pub fn new_system() -> Result<Self> {
-        system_socket().map(Self::new)
+        Self::new_for_addresses(&system_address(), false)
     }
 
     /// Initialize a handshake to the system-wide message bus.
     ///
     /// The socket backing this connection is created in non-blocking mode.
     pub fn new_system_nonblock() -> Result<Self> {
-        let socket = system_socket()?;
-        socket.set_nonblocking(true)?;
-        Ok(Self::new(socket))
+        Self::new_for_addresses(&system_address(), true)
     }
 
     /// Create a handshake for the given [D-Bus address].
     ///
+    /// The address can be a `;`-separated list of addresses, in which case each one is tried in
+    /// turn until a connection succeeds.
+    ///
     /// The socket backing this connection is created in blocking mode.
     ///
     /// [D-Bus address]: https://dbus.freedesktop.org/doc/dbus-specification.html#addresses
     pub fn new_for_address(address: &str) -> Result<Self> {
-        Address::from_str(address)?.connect().map(Self::new)
+        Self::new_for_addresses(address, false)
     }
 
     /// Create a handshake for the given [D-Bus address].
     ///
+    /// The address can be a `;`-separated list of addresses, in which case each one is tried in
+    /// turn until a connection succeeds.
+    ///
     /// The socket backing this connection is created in non-blocking mode.
     ///
     /// [D-Bus address]: https://dbus.freedesktop.org/doc/dbus-specification.html#addresses
     pub fn new_for_address_nonblock(address: &str) -> Result<Self> {
-        let socket = crate::address::Address::from_str(address)?.connect()?;
-        socket.set_nonblocking(true)?;
-        Ok(Self::new(socket))
+        Self::new_for_addresses(address, true)
     }
 
-    /// Block and automatically drive the handshake for this client
-    ///
-    /// This method will block until the handshake is finalized, even if the
-    /// socket is in non-blocking mode.
-    pub fn blocking_finish(mut self) -> Result<Authenticated<UnixStream>> {
-        loop {
-            match self.advance_handshake() {
-                Ok(()) => return Ok(self.try_finish().unwrap_or_else(|_| unreachable!())),
-                Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // we raised a WouldBlock error, this means this is a non-blocking socket
-                    // we use poll to wait until the action we need is available
-                    let flags = match self.step {
-                        ClientHandshakeStep::SendingOauth
-                        | ClientHandshakeStep::SendingNegociateFd
-                        | ClientHandshakeStep::SendingBegin => PollFlags::POLLOUT,
-                        ClientHandshakeStep::WaitOauth | ClientHandshakeStep::WaitNegociateFd => {
-                            PollFlags::POLLIN
-                        }
-                        ClientHandshakeStep::Init | ClientHandshakeStep::Done => unreachable!(),
-                    };
-                    wait_on(self.socket.as_raw_fd(), flags)?;
-                }
-                Err(e) => return Err(e),
+    // Try each address of the list in turn, including the invalid ones in the errors reported
+    fn new_for_addresses(addresses: &str, nonblocking: bool) -> Result<Self> {
+        let mut errors = vec![];
+        for (text, address) in Address::parse_list(addresses)? {
+            match address.and_then(|address| Self::new_for_parsed_address(&address, nonblocking)) {
+                Ok(handshake) => return Ok(handshake),
+                Err(e) => errors.push((text, e)),
             }
         }
+
+        if errors.len() == 1 {
+            return Err(errors.remove(0).1);
+        }
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|(address, e)| format!("{}: {}", address, e))
+            .collect();
+
+        Err(Error::Address(format!(
+            "failed to connect to any of the addresses: {}",
+            errors.join("; ")
+        )))
+    }
+
+    fn new_for_parsed_address(address: &Address, nonblocking: bool) -> Result<Self> {
+        let expected_guid = address.guid()?;
+        let mut handshake = Self::new(address.connect(nonblocking)?);
+        handshake.nonce = address.read_nonce()?;
+        handshake.expected_guid = expected_guid;
+
+        Ok(handshake)
     }
 }
 
@@ -287,6 +691,8 @@ enum ServerHandshakeStep {
     WaitingForAuth,
     SendingAuthOK,
     SendingAuthError,
+    SendingAuthData,
+    WaitingForData,
     WaitingForBegin,
     SendingBeginMessage,
     Done,
@@ -310,25 +716,232 @@ enum ServerHandshakeStep {
 /// [`Authenticated`]: struct.Authenticated.html
 /// [`Connection::new_authenticated`]: ../struct.Connection.html#method.new_authenticated
 /// [`blocking_finish`]: struct.ServerHandshake.html#method.blocking_finish
-#[derive(Debug)]
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
 pub struct ServerHandshake<S> {
     socket: S,
     buffer: Vec<u8>,
     step: ServerHandshakeStep,
     server_guid: Guid,
     cap_unix_fd: bool,
-    client_uid: u32,
+    // Whether to agree on FD passing, if the transport supports it
+    negotiate_unix_fd: bool,
+    peer_uid: Option<u32>,
+    peer_gid: Option<u32>,
+    peer_pid: Option<u32>,
+    // The credentials read from the socket, handed over to the connection
+    peer_credentials: ConnectionCredentials,
+    #[derivative(Debug = "ignore")]
+    policy: Arc<dyn AuthPolicy>,
+    // The mechanism waiting for a `DATA` response of the client
+    mechanism: Option<AuthMechanism>,
+    // The uid, cookie and the challenge we sent, while DBUS_COOKIE_SHA1 is in progress
+    cookie_challenge: Option<(u32, String, String)>,
+    // The number of commands received and of `REJECTED` replies sent so far
+    commands: usize,
+    failures: usize,
+    // The DBUS_COOKIE_SHA1 keyrings directory, `~/.dbus-keyrings` if unset
+    keyring_dir: Option<PathBuf>,
 }
 
 impl<S: Socket> ServerHandshake<S> {
+    /// Start a handshake on this server socket, for a client running as `client_uid`.
+    ///
+    /// The gid and process ID of the client are taken from the socket if possible.
     pub fn new(socket: S, guid: Guid, client_uid: u32) -> ServerHandshake<S> {
+        let mut handshake = Self::with_peer_credentials(socket, guid);
+        handshake.peer_uid = Some(client_uid);
+
+        handshake
+    }
+
+    /// Start a handshake on this server socket, taking the client credentials from the socket.
+    ///
+    /// No credentials are available on sockets other than Unix sockets.
+    pub fn with_peer_credentials(socket: S, guid: Guid) -> ServerHandshake<S> {
+        let peer_credentials = ConnectionCredentials::from_socket(socket.as_raw_fd());
+        let peer_gid = peer_credentials
+            .unix_group_ids()
+            .and_then(|gids| gids.first().copied());
+
         ServerHandshake {
             socket,
             buffer: Vec::new(),
             step: ServerHandshakeStep::WaitingForNull,
             server_guid: guid,
             cap_unix_fd: false,
-            client_uid,
+            negotiate_unix_fd: true,
+            peer_uid: peer_credentials.unix_user_id(),
+            peer_gid,
+            peer_pid: peer_credentials.process_id(),
+            peer_credentials,
+            policy: Arc::new(DefaultAuthPolicy::new()),
+            mechanism: None,
+            cookie_challenge: None,
+            commands: 0,
+            failures: 0,
+            keyring_dir: None,
+        }
+    }
+
+    /// Set the policy deciding which clients are allowed to connect.
+    ///
+    /// The [`DefaultAuthPolicy`] is used by default.
+    ///
+    /// [`DefaultAuthPolicy`]: struct.DefaultAuthPolicy.html
+    pub fn set_auth_policy(&mut self, policy: Arc<dyn AuthPolicy>) {
+        self.policy = policy;
+    }
+
+    /// Set whether to agree on file descriptor passing, when the client asks for it.
+    ///
+    /// This is done by default, if the transport supports it.
+    pub fn set_negotiate_unix_fd(&mut self, negotiate: bool) {
+        self.negotiate_unix_fd = negotiate;
+    }
+
+    // Use the DBUS_COOKIE_SHA1 keyrings in `dir` rather than in `~/.dbus-keyrings`.
+    #[cfg(test)]
+    pub(crate) fn set_keyring_dir(&mut self, dir: PathBuf) {
+        self.keyring_dir = Some(dir);
+    }
+
+    fn rejected(&self) -> Vec<u8> {
+        let mechs: Vec<_> = self
+            .policy
+            .mechanisms()
+            .iter()
+            .map(|m| m.to_string())
+            .collect();
+
+        format!("REJECTED {}\r\n", mechs.join(" ")).into()
+    }
+
+    fn offers(&self, mechanism: AuthMechanism) -> bool {
+        self.policy.mechanisms().contains(&mechanism)
+    }
+
+    // Ask the policy about a client, and reply accordingly
+    fn authorize(&mut self, mechanism: AuthMechanism, claimed_uid: Option<u32>) -> Result<()> {
+        // Policies only ever see verified identities: an EXTERNAL claim must be the peer's uid.
+        if mechanism == AuthMechanism::External
+            && (claimed_uid.is_none() || claimed_uid != self.peer_uid)
+        {
+            return self.reject();
+        }
+        let request = AuthRequest {
+            mechanism,
+            claimed_uid,
+            peer_uid: self.peer_uid,
+            peer_gid: self.peer_gid,
+            peer_pid: self.peer_pid,
+        };
+        if !self.policy.authorize(&request) {
+            return self.reject();
+        }
+        self.buffer = format!("OK {}\r\n", self.server_guid).into();
+        self.step = ServerHandshakeStep::SendingAuthOK;
+
+        Ok(())
+    }
+
+    // Reply with `REJECTED`, giving up once the client failed too many times
+    fn reject(&mut self) -> Result<()> {
+        self.failures += 1;
+        if self.failures > MAX_FAILURES {
+            return Err(Error::Handshake(
+                "Too many failed authentication attempts".to_string(),
+            ));
+        }
+        self.mechanism = None;
+        self.cookie_challenge = None;
+        self.buffer = self.rejected();
+        self.step = ServerHandshakeStep::SendingAuthError;
+
+        Ok(())
+    }
+
+    // Handle `AUTH <mechanism> [initial-response]`
+    fn start_mechanism(&mut self, mechanism: AuthMechanism, response: Option<&str>) -> Result<()> {
+        match (mechanism, response) {
+            (AuthMechanism::Anonymous, _) => self.authorize(mechanism, None),
+            // No initial response, ask the client for it
+            (_, None) => {
+                self.mechanism = Some(mechanism);
+                self.buffer = Vec::from(&b"DATA\r\n"[..]);
+                self.step = ServerHandshakeStep::SendingAuthData;
+
+                Ok(())
+            }
+            (_, Some(response)) => self.handle_response(mechanism, response),
+        }
+    }
+
+    // Handle the (initial or `DATA`) response of the client for `mechanism`
+    fn handle_response(&mut self, mechanism: AuthMechanism, response: &str) -> Result<()> {
+        match mechanism {
+            // An empty response means the client wants to be authenticated as the socket peer.
+            AuthMechanism::External if response.is_empty() => {
+                self.authorize(mechanism, self.peer_uid)
+            }
+            AuthMechanism::External => match id_from_str(response) {
+                Ok(uid) => self.authorize(mechanism, Some(uid)),
+                Err(_) => self.reject(),
+            },
+            AuthMechanism::Cookie if self.cookie_challenge.is_none() => {
+                self.cookie_challenge(response)
+            }
+            AuthMechanism::Cookie => match self.check_cookie_response(response) {
+                Some(uid) => self.authorize(mechanism, Some(uid)),
+                None => self.reject(),
+            },
+            AuthMechanism::Anonymous => self.authorize(mechanism, None),
+        }
+    }
+
+    // Handle `AUTH DBUS_COOKIE_SHA1 <user>`, by sending a challenge
+    fn cookie_challenge(&mut self, user: &str) -> Result<()> {
+        // We can only use our own keyring, so only our own user can be authenticated.
+        let cookie = match id_from_str(user) {
+            Ok(uid) if uid == u32::from(Uid::current()) => {
+                keyring::get_or_create(self.keyring_dir.as_deref(), keyring::DEFAULT_CONTEXT)
+                    .ok()
+                    .map(|cookie| (uid, cookie))
+            }
+            _ => None,
+        };
+
+        match cookie {
+            Some((uid, cookie)) => {
+                let challenge = random_hex(16)?;
+                let data = format!("{} {} {}", keyring::DEFAULT_CONTEXT, cookie.id, challenge);
+                self.buffer = format!("DATA {}\r\n", hex_encode(data.as_bytes())).into();
+                self.mechanism = Some(AuthMechanism::Cookie);
+                self.cookie_challenge = Some((uid, cookie.cookie, challenge));
+                self.step = ServerHandshakeStep::SendingAuthData;
+
+                Ok(())
+            }
+            None => self.reject(),
+        }
+    }
+
+    // Check the client response to our DBUS_COOKIE_SHA1 challenge, returning the verified uid
+    fn check_cookie_response(&mut self, data: &str) -> Option<u32> {
+        let (uid, cookie, server_challenge) = self.cookie_challenge.take()?;
+        let data = hex_decode(data)
+            .ok()
+            .and_then(|d| String::from_utf8(d).ok())?;
+        let mut words = data.split(' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some(client_challenge), Some(hash), None) => {
+                let expected = sha1(
+                    format!("{}:{}:{}", server_challenge, client_challenge, cookie).as_bytes(),
+                );
+                Some(uid)
+                    .filter(|_| constant_time_eq(hex_encode(&expected).as_bytes(), hash.as_bytes()))
+            }
+            _ => None,
         }
     }
 
@@ -340,13 +953,8 @@ impl<S: Socket> ServerHandshake<S> {
         Ok(())
     }
 
-    fn read_command(&mut self) -> Result<()> {
-        while !self.buffer.ends_with(b"\r\n") {
-            let mut buf = [0; 40];
-            let (read, _) = self.socket.recvmsg(&mut buf)?;
-            self.buffer.extend(&buf[..read]);
-        }
-        Ok(())
+    fn read_command(&mut self) -> Result<String> {
+        read_command(&mut self.socket, &mut self.buffer, &mut self.commands)
     }
 
     /// Attempt to advance the handshake
@@ -363,8 +971,11 @@ impl<S: Socket> ServerHandshake<S> {
                 ServerHandshakeStep::WaitingForNull => {
                     let mut buffer = [0; 1];
                     let (read, _) = self.socket.recvmsg(&mut buffer)?;
-                    // recvmsg cannot return anything else than Ok(1) or Err
-                    debug_assert!(read == 1);
+                    if read == 0 {
+                        return Err(Error::Handshake(
+                            "Connection closed during handshake".to_string(),
+                        ));
+                    }
                     if buffer[0] != 0 {
                         return Err(Error::Handshake(
                             "First client byte is not NUL!".to_string(),
@@ -373,26 +984,19 @@ impl<S: Socket> ServerHandshake<S> {
                     self.step = ServerHandshakeStep::WaitingForAuth;
                 }
                 ServerHandshakeStep::WaitingForAuth => {
-                    self.read_command()?;
-                    let mut reply = String::new();
-                    (&self.buffer[..]).read_line(&mut reply)?;
-                    let mut words = reply.split_whitespace();
+                    let command = self.read_command()?;
+                    let mut words = command.split_whitespace();
                     match (words.next(), words.next(), words.next(), words.next()) {
-                        (Some("AUTH"), Some("EXTERNAL"), Some(uid), None) => {
-                            let uid = id_from_str(uid)
-                                .map_err(|e| Error::Handshake(format!("Invalid UID: {}", e)))?;
-                            if uid == self.client_uid {
-                                self.buffer = format!("OK {}\r\n", self.server_guid).into();
-                                self.step = ServerHandshakeStep::SendingAuthOK;
-                            } else {
-                                self.buffer = Vec::from(&b"REJECTED EXTERNAL\r\n"[..]);
-                                self.step = ServerHandshakeStep::SendingAuthError;
+                        (Some("AUTH"), Some(mech), response, None) => {
+                            match mech.parse::<AuthMechanism>() {
+                                Ok(mech) if self.offers(mech) => {
+                                    self.start_mechanism(mech, response)?
+                                }
+                                _ => self.reject()?,
                             }
                         }
-                        (Some("AUTH"), _, _, _) | (Some("ERROR"), _, _, _) => {
-                            self.buffer = Vec::from(&b"REJECTED EXTERNAL\r\n"[..]);
-                            self.step = ServerHandshakeStep::SendingAuthError;
-                        }
+                        // `AUTH` alone asks for the list of supported mechanisms
+                        (Some("AUTH"), _, _, _) | (Some("ERROR"), _, _, _) => self.reject()?,
                         (Some("BEGIN"), None, None, None) => {
                             return Err(Error::Handshake(
                                 "Received BEGIN while not authenticated".to_string(),
@@ -408,30 +1012,52 @@ impl<S: Socket> ServerHandshake<S> {
                     self.flush_buffer()?;
                     self.step = ServerHandshakeStep::WaitingForAuth;
                 }
+                ServerHandshakeStep::SendingAuthData => {
+                    self.flush_buffer()?;
+                    self.step = ServerHandshakeStep::WaitingForData;
+                }
+                ServerHandshakeStep::WaitingForData => {
+                    let command = self.read_command()?;
+                    let mut words = command.split_whitespace();
+                    match (words.next(), words.next(), words.next()) {
+                        (Some("DATA"), response, None) => match self.mechanism.take() {
+                            Some(mech) => self.handle_response(mech, response.unwrap_or(""))?,
+                            None => self.reject()?,
+                        },
+                        (Some("CANCEL"), None, None) | (Some("ERROR"), _, _) => self.reject()?,
+                        (Some("BEGIN"), None, None) => {
+                            return Err(Error::Handshake(
+                                "Received BEGIN while not authenticated".to_string(),
+                            ));
+                        }
+                        _ => {
+                            self.buffer = Vec::from(&b"ERROR Unsupported command\r\n"[..]);
+                            self.step = ServerHandshakeStep::SendingAuthData;
+                        }
+                    }
+                }
                 ServerHandshakeStep::SendingAuthOK => {
                     self.flush_buffer()?;
                     self.step = ServerHandshakeStep::WaitingForBegin;
                 }
                 ServerHandshakeStep::WaitingForBegin => {
-                    self.read_command()?;
-                    let mut reply = String::new();
-                    (&self.buffer[..]).read_line(&mut reply)?;
-                    let mut words = reply.split_whitespace();
+                    let command = self.read_command()?;
+                    let mut words = command.split_whitespace();
                     match (words.next(), words.next()) {
                         (Some("BEGIN"), None) => {
                             self.step = ServerHandshakeStep::Done;
                         }
-                        (Some("CANCEL"), None) => {
-                            self.buffer = Vec::from(&b"REJECTED EXTERNAL\r\n"[..]);
-                            self.step = ServerHandshakeStep::SendingAuthError;
-                        }
-                        (Some("ERROR"), _) => {
-                            self.buffer = Vec::from(&b"REJECTED EXTERNAL\r\n"[..]);
-                            self.step = ServerHandshakeStep::SendingAuthError;
-                        }
+                        (Some("CANCEL"), None) | (Some("ERROR"), _) => self.reject()?,
                         (Some("NEGOTIATE_UNIX_FD"), None) => {
-                            self.cap_unix_fd = true;
-                            self.buffer = Vec::from(&b"AGREE_UNIX_FD\r\n"[..]);
+                            self.cap_unix_fd =
+                                self.negotiate_unix_fd && self.socket.can_pass_unix_fd();
+                            self.buffer = if self.cap_unix_fd {
+                                Vec::from(&b"AGREE_UNIX_FD\r\n"[..])
+                            } else if self.negotiate_unix_fd {
+                                Vec::from(&b"ERROR FD passing not supported by transport\r\n"[..])
+                            } else {
+                                Vec::from(&b"ERROR FD passing disabled\r\n"[..])
+                            };
                             self.step = ServerHandshakeStep::SendingBeginMessage;
                         }
                         _ => {
@@ -459,73 +1085,128 @@ impl<S: Socket> ServerHandshake<S> {
                 conn: Connection::wrap(self.socket),
                 server_guid: self.server_guid,
                 cap_unix_fd: self.cap_unix_fd,
+                peer_credentials: self.peer_credentials,
             })
         } else {
             Err(self)
         }
     }
 
-    /// Access the socket backing this handshake
-    ///
-    /// Would typically be used to register it for readiness.
-    pub fn socket(&self) -> &S {
-        &self.socket
-    }
-}
-
-impl ServerHandshake<UnixStream> {
     /// Block and automatically drive the handshake for this server
     ///
     /// This method will block until the handshake is finalized, even if the
     /// socket is in non-blocking mode.
-    pub fn blocking_finish(mut self) -> Result<Authenticated<UnixStream>> {
+    pub fn blocking_finish(mut self) -> Result<Authenticated<S>> {
         loop {
             match self.advance_handshake() {
                 Ok(()) => return Ok(self.try_finish().unwrap_or_else(|_| unreachable!())),
                 Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     // we raised a WouldBlock error, this means this is a non-blocking socket
                     // we use poll to wait until the action we need is available
-                    let flags = match self.step {
-                        ServerHandshakeStep::SendingAuthError
-                        | ServerHandshakeStep::SendingAuthOK
-                        | ServerHandshakeStep::SendingBeginMessage => PollFlags::POLLOUT,
-                        ServerHandshakeStep::WaitingForNull
-                        | ServerHandshakeStep::WaitingForBegin
-                        | ServerHandshakeStep::WaitingForAuth => PollFlags::POLLIN,
-                        ServerHandshakeStep::Done => unreachable!(),
-                    };
-                    wait_on(self.socket.as_raw_fd(), flags)?;
+                    wait_on(self.socket.as_raw_fd(), self.wait_flags())?;
                 }
                 Err(e) => return Err(e),
             }
         }
     }
-}
 
-/// Get a session socket respecting the DBUS_SESSION_BUS_ADDRESS environment
-/// variable. If we don't recognize the value (or it's not set) we fall back to
-/// /run/user/UID/bus
-fn session_socket() -> Result<UnixStream> {
-    match env::var("DBUS_SESSION_BUS_ADDRESS") {
-        Ok(val) => Address::from_str(&val)?.connect(),
-        _ => {
-            let uid = Uid::current();
-            let path = format!("/run/user/{}/bus", uid);
-            Ok(UnixStream::connect(path)?)
+    /// Access the socket backing this handshake
+    ///
+    /// Would typically be used to register it for readiness.
+    pub fn socket(&self) -> &S {
+        &self.socket
+    }
+
+    // The readiness to wait for, once `advance_handshake` failed with `WouldBlock`
+    pub(crate) fn wait_flags(&self) -> PollFlags {
+        match self.step {
+            ServerHandshakeStep::SendingAuthError
+            | ServerHandshakeStep::SendingAuthData
+            | ServerHandshakeStep::SendingAuthOK
+            | ServerHandshakeStep::SendingBeginMessage => PollFlags::POLLOUT,
+            ServerHandshakeStep::WaitingForNull
+            | ServerHandshakeStep::WaitingForData
+            | ServerHandshakeStep::WaitingForBegin
+            | ServerHandshakeStep::WaitingForAuth => PollFlags::POLLIN,
+            ServerHandshakeStep::Done => unreachable!(),
         }
     }
 }
 
-/// Get a system socket respecting the DBUS_SYSTEM_BUS_ADDRESS environment
-/// variable. If we don't recognize the value (or it's not set) we fall back to
-/// /var/run/dbus/system_bus_socket
-fn system_socket() -> Result<UnixStream> {
-    match env::var("DBUS_SYSTEM_BUS_ADDRESS") {
-        Ok(val) => Address::from_str(&val)?.connect(),
-        _ => Ok(UnixStream::connect("/var/run/dbus/system_bus_socket")?),
+/// Get the session bus address respecting the DBUS_SESSION_BUS_ADDRESS environment
+/// variable. If it's not set we fall back to /run/user/UID/bus
+fn session_address() -> String {
+    env::var("DBUS_SESSION_BUS_ADDRESS")
+        .unwrap_or_else(|_| format!("unix:path=/run/user/{}/bus", Uid::current()))
+}
+
+/// Get the system bus address respecting the DBUS_SYSTEM_BUS_ADDRESS environment
+/// variable. If it's not set we fall back to /var/run/dbus/system_bus_socket
+fn system_address() -> String {
+    env::var("DBUS_SYSTEM_BUS_ADDRESS")
+        .unwrap_or_else(|_| "unix:path=/var/run/dbus/system_bus_socket".to_owned())
+}
+
+// Read a command from `socket` into `buffer`, returning it without its `\r\n` once complete.
+//
+// Bytes are read one at a time so that nothing past the command is consumed, since the first
+// message may directly follow `BEGIN`. To protect against misbehaving peers, both the length of a
+// command and the number of commands in a handshake are limited.
+fn read_command<S: Socket>(
+    socket: &mut S,
+    buffer: &mut Vec<u8>,
+    commands: &mut usize,
+) -> Result<String> {
+    while !buffer.ends_with(b"\r\n") {
+        if buffer.len() >= MAX_COMMAND_LENGTH {
+            return Err(Error::Handshake("Handshake command too long".to_string()));
+        }
+        let mut byte = [0; 1];
+        let (read, _) = socket.recvmsg(&mut byte)?;
+        if read == 0 {
+            return Err(Error::Handshake(
+                "Connection closed during handshake".to_string(),
+            ));
+        }
+        buffer.push(byte[0]);
+    }
+
+    *commands += 1;
+    if *commands > MAX_COMMANDS {
+        return Err(Error::Handshake("Too many handshake commands".to_string()));
+    }
+    let command = std::mem::take(buffer);
+    match String::from_utf8(command) {
+        Ok(command) if command.is_ascii() => Ok(command.trim_end_matches("\r\n").to_string()),
+        _ => Err(Error::Handshake(
+            "Invalid characters in handshake command".to_string(),
+        )),
     }
 }
 
+// Compare `a` and `b` in a time only depending on their length, not to leak how much of a secret
+// value a peer guessed right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(s: &str) -> Result<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|c| {
+            std::str::from_utf8(c)
+                .ok()
+                .filter(|c| c.len() == 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                .ok_or_else(|| Error::Handshake(format!("Invalid hex data: {}", s)))
+        })
+        .collect()
+}
+
 fn id_from_str(s: &str) -> std::result::Result<u32, Box<dyn std::error::Error>> {
     let mut id = String::new();
     for s in s.as_bytes().chunks(2) {
@@ -537,6 +1218,8 @@ fn id_from_str(s: &str) -> std::result::Result<u32, Box<dyn std::error::Error>>
 
 #[cfg(test)]
 mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
     use std::os::unix::net::UnixStream;
 
     use super::*;
@@ -577,4 +1260,248 @@ mod tests {
         assert_eq!(client.server_guid, server.server_guid);
         assert_eq!(client.cap_unix_fd, server.cap_unix_fd);
     }
+
+    #[test]
+    fn cookie_sha1_handshake() {
+        // Use a pristine keyring, so that it needs to be created. Tests run in parallel, so
+        // don't touch `HOME`.
+        let home = env::temp_dir().join(format!("zbus-home-{}", Guid::generate()));
+        std::fs::create_dir(&home).unwrap();
+        let keyrings = home.join(".dbus-keyrings");
+
+        let (p0, p1) = UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        let server_guid = guid.clone();
+        let server_keyrings = keyrings.clone();
+        let server_thread = std::thread::spawn(move || {
+            let mut server = ServerHandshake::new(p1, server_guid, Uid::current().into());
+            server.set_auth_policy(Arc::new(
+                DefaultAuthPolicy::new().mechanisms(&[AuthMechanism::Cookie]),
+            ));
+            server.set_keyring_dir(server_keyrings);
+            server.blocking_finish().unwrap()
+        });
+
+        // EXTERNAL is tried first and rejected, then DBUS_COOKIE_SHA1 succeeds.
+        let mut client = ClientHandshake::new(p0);
+        client.set_keyring_dir(keyrings.clone());
+        let client = client.blocking_finish().unwrap();
+        let server = server_thread.join().unwrap();
+        assert_eq!(client.server_guid, guid);
+        assert_eq!(server.server_guid, guid);
+
+        let mode = std::fs::metadata(&keyrings).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        let keyring = std::fs::read_to_string(keyrings.join("org_freedesktop_general")).unwrap();
+        assert_eq!(keyring.lines().count(), 1);
+
+        // The client must fail if no mechanism is common to both sides
+        let (p0, p1) = UnixStream::pair().unwrap();
+        let server_thread = std::thread::spawn(move || {
+            let mut server = ServerHandshake::new(p1, Guid::generate(), Uid::current().into());
+            server.set_auth_policy(Arc::new(
+                DefaultAuthPolicy::new().mechanisms(&[AuthMechanism::Cookie]),
+            ));
+            // The client hangs up on us, so we can't know how this ends.
+            let _ = server.blocking_finish();
+        });
+        let mut client = ClientHandshake::new(p0);
+        client.set_mechanisms(&[AuthMechanism::External]);
+        match client.blocking_finish() {
+            Err(Error::Handshake(e)) => assert_eq!(e, "Exhausted available AUTH mechanisms"),
+            _ => panic!("handshake without a common mechanism succeeded"),
+        }
+        server_thread.join().unwrap();
+
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn anonymous_handshake() {
+        let (p0, p1) = UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        let server_guid = guid.clone();
+        let server_thread = std::thread::spawn(move || {
+            // A uid that can't be ours, so that EXTERNAL is rejected.
+            let mut server = ServerHandshake::new(p1, server_guid, u32::MAX);
+            server.set_auth_policy(Arc::new(
+                DefaultAuthPolicy::new()
+                    .mechanisms(&[AuthMechanism::External, AuthMechanism::Anonymous]),
+            ));
+            server.blocking_finish().unwrap()
+        });
+
+        // EXTERNAL is rejected, DBUS_COOKIE_SHA1 is skipped as unsupported by the server, and
+        // ANONYMOUS succeeds.
+        let client = ClientHandshake::new(p0).blocking_finish().unwrap();
+        let server = server_thread.join().unwrap();
+        assert_eq!(client.server_guid, guid);
+        assert_eq!(server.server_guid, guid);
+
+        assert_eq!(
+            "ANONYMOUS".parse::<AuthMechanism>().unwrap(),
+            AuthMechanism::Anonymous
+        );
+        assert_eq!(AuthMechanism::Cookie.to_string(), "DBUS_COOKIE_SHA1");
+        assert!("PLAIN".parse::<AuthMechanism>().is_err());
+    }
+
+    #[test]
+    fn default_auth_policy() {
+        let request = AuthRequest {
+            mechanism: AuthMechanism::External,
+            claimed_uid: Some(1000),
+            peer_uid: Some(1000),
+            peer_gid: Some(100),
+            peer_pid: None,
+        };
+        let policy = DefaultAuthPolicy::new();
+        assert!(policy.authorize(&request));
+        assert!(!policy.authorize(&AuthRequest {
+            peer_uid: Some(1001),
+            ..request.clone()
+        }));
+        assert!(!policy.authorize(&AuthRequest {
+            mechanism: AuthMechanism::Anonymous,
+            ..request.clone()
+        }));
+
+        let policy = DefaultAuthPolicy::new().allow_uid(1001);
+        assert!(!policy.authorize(&request));
+        assert!(policy.authorize(&AuthRequest {
+            claimed_uid: Some(1001),
+            peer_uid: Some(1001),
+            ..request.clone()
+        }));
+        let policy = policy.allow_gid(100);
+        assert!(policy.authorize(&request));
+
+        let policy = DefaultAuthPolicy::new().mechanisms(&[AuthMechanism::Cookie]);
+        assert!(!policy.authorize(&request));
+        assert!(policy.authorize(&AuthRequest {
+            mechanism: AuthMechanism::Cookie,
+            peer_uid: None,
+            peer_gid: None,
+            ..request
+        }));
+    }
+
+    #[test]
+    fn custom_auth_policy() {
+        let (p0, p1) = UnixStream::pair().unwrap();
+        let server_thread = std::thread::spawn(move || {
+            let mut server = ServerHandshake::with_peer_credentials(p1, Guid::generate());
+            server.set_auth_policy(Arc::new(|request: &AuthRequest| {
+                request.peer_pid == Some(std::process::id())
+                    && request.peer_uid == Some(Uid::current().into())
+                    && request.claimed_uid == request.peer_uid
+            }));
+            server.blocking_finish().unwrap()
+        });
+
+        ClientHandshake::new(p0).blocking_finish().unwrap();
+        server_thread.join().unwrap();
+
+        // Even a policy accepting anyone never sees a spoofed EXTERNAL identity
+        let (mut p0, p1) = UnixStream::pair().unwrap();
+        let server_thread = std::thread::spawn(move || {
+            let mut server = ServerHandshake::with_peer_credentials(p1, Guid::generate());
+            server.set_auth_policy(Arc::new(|_: &AuthRequest| true));
+            server.blocking_finish()
+        });
+        let spoofed = u32::from(Uid::current()).wrapping_add(1).to_string();
+        let command = format!("\0AUTH EXTERNAL {}", hex_encode(spoofed.as_bytes()));
+        assert_eq!(exchange(&mut p0, &command), "REJECTED EXTERNAL");
+        drop(p0);
+        assert!(server_thread.join().unwrap().is_err());
+    }
+
+    // Send a command to the server and read its reply
+    fn exchange(stream: &mut UnixStream, command: &str) -> String {
+        stream.write_all(command.as_bytes()).unwrap();
+        stream.write_all(b"\r\n").unwrap();
+        let mut reply = Vec::new();
+        read_command(stream, &mut reply, &mut 0).unwrap()
+    }
+
+    #[test]
+    fn server_state_machine() {
+        let (mut p0, p1) = UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        let server_guid = guid.clone();
+        let server_thread = std::thread::spawn(move || {
+            let server = ServerHandshake::with_peer_credentials(p1, server_guid);
+            let server = server.blocking_finish().unwrap();
+            // Nothing following BEGIN must have been consumed by the handshake
+            let mut message = [0; 7];
+            server.conn.socket().read_exact(&mut message).unwrap();
+            assert_eq!(&message, b"message");
+        });
+
+        let uid = hex_encode(Uid::current().to_string().as_bytes());
+        assert_eq!(exchange(&mut p0, "\0AUTH"), "REJECTED EXTERNAL");
+        assert!(exchange(&mut p0, "HELLO").starts_with("ERROR"));
+        // Without an initial response, the server asks for it
+        assert_eq!(exchange(&mut p0, "AUTH EXTERNAL"), "DATA");
+        assert!(exchange(&mut p0, "NEGOTIATE_UNIX_FD").starts_with("ERROR"));
+        assert_eq!(exchange(&mut p0, "CANCEL"), "REJECTED EXTERNAL");
+        assert_eq!(exchange(&mut p0, "AUTH EXTERNAL"), "DATA");
+        assert_eq!(exchange(&mut p0, "DATA"), format!("OK {}", guid));
+        assert_eq!(exchange(&mut p0, "ERROR"), "REJECTED EXTERNAL");
+        assert_eq!(exchange(&mut p0, "AUTH ANONYMOUS"), "REJECTED EXTERNAL");
+        assert_eq!(
+            exchange(&mut p0, &format!("AUTH EXTERNAL {}", uid)),
+            format!("OK {}", guid)
+        );
+        assert_eq!(exchange(&mut p0, "NEGOTIATE_UNIX_FD"), "AGREE_UNIX_FD");
+        p0.write_all(b"BEGIN\r\nmessage").unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn server_limits() {
+        // Too many failed attempts
+        let (mut p0, p1) = UnixStream::pair().unwrap();
+        let server_thread = std::thread::spawn(move || {
+            ServerHandshake::new(p1, Guid::generate(), u32::MAX).blocking_finish()
+        });
+        p0.write_all(b"\0").unwrap();
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(exchange(&mut p0, "AUTH EXTERNAL"), "DATA");
+            assert_eq!(exchange(&mut p0, "DATA 30"), "REJECTED EXTERNAL");
+        }
+        p0.write_all(b"AUTH EXTERNAL 30\r\n").unwrap();
+        match server_thread.join().unwrap() {
+            Err(Error::Handshake(e)) => assert_eq!(e, "Too many failed authentication attempts"),
+            _ => panic!("too many failed attempts were accepted"),
+        }
+
+        // Overlong command
+        let (mut p0, p1) = UnixStream::pair().unwrap();
+        let server_thread = std::thread::spawn(move || {
+            ServerHandshake::new(p1, Guid::generate(), u32::MAX).blocking_finish()
+        });
+        p0.write_all(b"\0AUTH EXTERNAL ").unwrap();
+        // The server may hang up on us before we're done
+        let _ = p0.write_all(&vec![b'3'; MAX_COMMAND_LENGTH]);
+        match server_thread.join().unwrap() {
+            Err(Error::Handshake(e)) => assert_eq!(e, "Handshake command too long"),
+            _ => panic!("overlong command was accepted"),
+        }
+
+        // Too many commands
+        let (mut p0, p1) = UnixStream::pair().unwrap();
+        let server_thread = std::thread::spawn(move || {
+            ServerHandshake::new(p1, Guid::generate(), u32::MAX).blocking_finish()
+        });
+        p0.write_all(b"\0").unwrap();
+        for _ in 0..MAX_COMMANDS {
+            assert!(exchange(&mut p0, "HELLO").starts_with("ERROR"));
+        }
+        let _ = p0.write_all(b"HELLO\r\n");
+        match server_thread.join().unwrap() {
+            Err(Error::Handshake(e)) => assert_eq!(e, "Too many handshake commands"),
+            _ => panic!("too many commands were accepted"),
+        }
+    }
 }
@@ -0,0 +1,294 @@
+// The DBUS_COOKIE_SHA1 keyrings, stored in `~/.dbus-keyrings`.
+//
+// See the D-Bus specification for the details:
+// https://dbus.freedesktop.org/doc/dbus-specification.html#auth-mechanisms-sha
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{Error, Result};
+
+/// The cookie context used when none is specified.
+pub(crate) const DEFAULT_CONTEXT: &str = "org_freedesktop_general";
+
+// The timeouts used by the reference implementation.
+const NEW_COOKIE_TIMEOUT: u64 = 60 * 5;
+const EXPIRE_COOKIE_TIMEOUT: u64 = NEW_COOKIE_TIMEOUT + 60 * 2;
+const MAX_TIME_TRAVEL: u64 = 60 * 5;
+
+// Servers take the lock in the middle of handshakes, so only wait a few ms for it.
+const LOCK_ATTEMPTS: u32 = 5;
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(2);
+// A lock older than the time the reference implementation waits for it is considered stale.
+const LOCK_STALE_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// A cookie of a keyring.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Cookie {
+    pub(crate) id: u32,
+    pub(crate) created: u64,
+    pub(crate) cookie: String,
+}
+
+/// Check that `context` is a valid cookie context name.
+pub(crate) fn validate_context(context: &str) -> Result<()> {
+    if context.is_empty()
+        || context
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c == '.' || c.is_whitespace())
+    {
+        return Err(Error::Handshake(format!(
+            "Invalid cookie context '{}'",
+            context
+        )));
+    }
+
+    Ok(())
+}
+
+/// Look up cookie `id` of the keyring `context` in `dir`, as a client does.
+///
+/// The keyrings of the user are in `~/.dbus-keyrings` if `dir` is `None`.
+pub(crate) fn lookup(dir: Option<&Path>, context: &str, id: u32) -> Result<String> {
+    validate_context(context)?;
+
+    read_keyring(&keyring_dir(dir)?.join(context))?
+        .into_iter()
+        .find(|c| c.id == id)
+        .map(|c| c.cookie)
+        .ok_or_else(|| Error::Handshake(format!("Cookie {} not found in '{}'", id, context)))
+}
+
+/// Get a recent enough cookie from the keyring `context` in `dir`, as a server does.
+///
+/// The keyring (and its directory) is created if needed, expired cookies are removed and a new
+/// cookie is added if none is recent enough. Like for [`lookup`], `dir` defaults to
+/// `~/.dbus-keyrings`. Fails with a `WouldBlock` I/O error if the keyring is locked by someone else
+/// for more than a few ms.
+///
+/// [`lookup`]: fn.lookup.html
+pub(crate) fn get_or_create(dir: Option<&Path>, context: &str) -> Result<Cookie> {
+    validate_context(context)?;
+
+    let dir = keyring_dir(dir)?;
+    create_keyring_dir(&dir)?;
+    let path = dir.join(context);
+    let _lock = Lock::acquire(&dir.join(format!("{}.lock", context)))?;
+
+    let now = now();
+    let mut cookies: Vec<_> = read_keyring(&path)?
+        .into_iter()
+        .filter(|c| c.created + EXPIRE_COOKIE_TIMEOUT > now && c.created < now + MAX_TIME_TRAVEL)
+        .collect();
+    let cookie = cookies
+        .iter()
+        .filter(|c| c.created + NEW_COOKIE_TIMEOUT > now)
+        .max_by_key(|c| c.created)
+        .cloned();
+    let cookie = match cookie {
+        Some(cookie) => cookie,
+        None => {
+            let cookie = Cookie {
+                id: cookies.iter().map(|c| c.id).max().map_or(0, |id| id + 1),
+                created: now,
+                cookie: random_hex(24)?,
+            };
+            cookies.push(cookie.clone());
+            cookie
+        }
+    };
+    write_keyring(&path, &cookies)?;
+
+    Ok(cookie)
+}
+
+/// Generate `len` random bytes, hex-encoded.
+///
+/// Cookies and challenges must not be predictable, so the bytes come from the OS.
+pub(crate) fn random_hex(len: usize) -> Result<String> {
+    let mut bytes = vec![0; len];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn keyring_dir(dir: Option<&Path>) -> Result<PathBuf> {
+    if let Some(dir) = dir {
+        return Ok(dir.to_owned());
+    }
+    let home = env::var_os("HOME")
+        .ok_or_else(|| Error::Handshake("HOME is not set, can not find keyrings".into()))?;
+
+    Ok(Path::new(&home).join(".dbus-keyrings"))
+}
+
+fn create_keyring_dir(dir: &Path) -> Result<()> {
+    match fs::create_dir(dir) {
+        Ok(()) => fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    // Like the reference implementation, refuse to use a keyring others could read.
+    if fs::metadata(dir)?.permissions().mode() & 0o077 != 0 {
+        return Err(Error::Handshake(format!(
+            "Keyring directory '{}' is accessible by other users",
+            dir.display()
+        )));
+    }
+
+    Ok(())
+}
+
+fn read_keyring(path: &Path) -> Result<Vec<Cookie>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    // Invalid lines are ignored, like the reference implementation does.
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(id), Some(created), Some(cookie), None) => Some(Cookie {
+                    id: id.parse().ok()?,
+                    created: created.parse().ok()?,
+                    cookie: cookie.to_owned(),
+                }),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+fn write_keyring(path: &Path, cookies: &[Cookie]) -> Result<()> {
+    // Write to a temporary file first, so that readers never see a partial keyring.
+    let tmp = path.with_extension(format!("{}.tmp", random_hex(4)?));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    for c in cookies {
+        writeln!(file, "{} {} {}", c.id, c.created, c.cookie)?;
+    }
+    drop(file);
+
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e.into()
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// An exclusive lock on a keyring, released when dropped.
+struct Lock(PathBuf);
+
+impl Lock {
+    fn acquire(path: &Path) -> Result<Self> {
+        let create = || {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+        };
+
+        for _ in 0..LOCK_ATTEMPTS {
+            match create() {
+                Ok(_) => return Ok(Lock(path.to_owned())),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => sleep(LOCK_RETRY_DELAY),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // The lock is likely stale (its owner died) if it's old, so break it like the reference
+        // implementation does. Otherwise, let the caller try again later.
+        let age = fs::metadata(path)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+        if age < LOCK_STALE_TIMEOUT {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "keyring is locked",
+            )));
+        }
+        fs::remove_file(path)?;
+        create()
+            .map(|_: File| Lock(path.to_owned()))
+            .map_err(Error::Io)
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyring() {
+        // Tests run in parallel, so don't touch `HOME` here and just work on a directory.
+        let dir = env::temp_dir().join(format!("zbus-keyring-{}", random_hex(8).unwrap()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join(DEFAULT_CONTEXT);
+
+        let now = now();
+        let cookies = vec![
+            Cookie {
+                id: 1,
+                created: now - EXPIRE_COOKIE_TIMEOUT - 1,
+                cookie: "expired".into(),
+            },
+            Cookie {
+                id: 2,
+                created: now - 1,
+                cookie: "fresh".into(),
+            },
+        ];
+        write_keyring(&path, &cookies).unwrap();
+        fs::write(&path, fs::read_to_string(&path).unwrap() + "invalid line\n").unwrap();
+        assert_eq!(read_keyring(&path).unwrap(), cookies);
+        assert_eq!(read_keyring(&dir.join("missing")).unwrap(), vec![]);
+
+        let lock_path = dir.join("lock");
+        let lock = Lock::acquire(&lock_path).unwrap();
+        assert!(lock_path.exists());
+        // A held lock isn't waited for
+        assert!(matches!(
+            Lock::acquire(&lock_path),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock
+        ));
+        drop(lock);
+        assert!(!lock_path.exists());
+
+        assert!(validate_context(DEFAULT_CONTEXT).is_ok());
+        assert!(validate_context("../foo").is_err());
+        assert!(validate_context("foo bar").is_err());
+
+        let secret = random_hex(16).unwrap();
+        assert_eq!(secret.len(), 32);
+        assert_ne!(secret, random_hex(16).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -135,6 +135,7 @@ mod error;
 pub use error::*;
 
 mod address;
+pub use address::*;
 
 mod guid;
 pub use guid::*;
@@ -151,9 +152,24 @@ pub use message_field::*;
 mod message_fields;
 pub use message_fields::*;
 
+mod match_rule;
+pub use match_rule::*;
+
 mod connection;
 pub use connection::*;
 
+mod connection_builder;
+pub use connection_builder::*;
+
+mod credentials;
+pub use credentials::*;
+
+mod reconnect;
+pub use reconnect::*;
+
+mod monitor;
+pub use monitor::*;
+
 mod proxy;
 pub use proxy::*;
 
@@ -162,15 +178,25 @@ pub use owned_fd::*;
 
 mod utils;
 
+mod keyring;
+mod sha1;
+
 mod object_server;
 pub use object_server::*;
 
+mod server;
+pub use server::*;
+
 pub mod fdo;
 
 pub mod raw;
 
+pub mod azync;
+
 pub mod handshake;
 
+pub mod pcap;
+
 pub mod xml;
 
 pub use zbus_macros::{dbus_interface, dbus_proxy, DBusError};
@@ -274,7 +300,7 @@ mod tests {
 
     #[test]
     fn freedesktop_api() {
-        let mut connection = crate::Connection::new_session()
+        let connection = crate::Connection::new_session()
             .map_err(|e| {
                 println!("error: {}", e);
 
@@ -282,12 +308,12 @@ mod tests {
             })
             .unwrap();
 
-        connection.set_default_message_handler(Box::new(|msg| {
+        connection.add_filter(crate::MessageDirection::Incoming, |msg| {
             // Debug implementation will test it a bit
             println!("Received while waiting for a reply: {}", msg);
 
             Some(msg)
-        }));
+        });
 
         // Let's try getting us a fancy name on the bus
         #[repr(u32)]
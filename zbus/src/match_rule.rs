@@ -0,0 +1,730 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use zvariant::Value;
+
+use crate::{Error, Message, MessageType, Result};
+
+// The highest argument index a rule can match on, as per the specification.
+const MAX_ARG_INDEX: u8 = 63;
+
+/// A [match rule], selecting messages on their type, header fields and arguments.
+///
+/// Match rules are what the bus uses to route signals to the connections interested in them
+/// (see [`fdo::DBusProxy::add_match`]), and what monitors select messages with. A `MatchRule` can be
+/// built with a [`MatchRuleBuilder`], or parsed from the string syntax of the specification through
+/// its [`FromStr`] implementation. Its [`Display`] implementation gives that string syntax back.
+///
+/// The [`matches`] method checks locally whether a message matches the rule.
+///
+/// # Example
+///
+/// ```
+///# use std::error::Error;
+///#
+/// use zbus::{MatchRule, MessageType};
+///
+/// let rule = MatchRule::builder()
+///     .msg_type(MessageType::Signal)
+///     .interface("org.freedesktop.DBus.Properties")
+///     .member("PropertiesChanged")
+///     .arg(0, "org.zbus.MyIface")
+///     .build()?;
+/// assert_eq!(
+///     rule.to_string(),
+///     "type='signal',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',\
+///      arg0='org.zbus.MyIface'",
+/// );
+/// assert_eq!(rule.to_string().parse::<MatchRule>()?, rule);
+///# Ok::<_, Box<dyn Error + Send + Sync>>(())
+/// ```
+///
+/// [match rule]: https://dbus.freedesktop.org/doc/dbus-specification.html#message-bus-routing-match-rules
+/// [`fdo::DBusProxy::add_match`]: fdo/struct.DBusProxy.html#method.add_match
+/// [`MatchRuleBuilder`]: struct.MatchRuleBuilder.html
+/// [`FromStr`]: https://doc.rust-lang.org/std/str/trait.FromStr.html
+/// [`Display`]: https://doc.rust-lang.org/std/fmt/trait.Display.html
+/// [`matches`]: struct.MatchRule.html#method.matches
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MatchRule {
+    msg_type: Option<MessageType>,
+    sender: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    path: Option<PathMatch>,
+    destination: Option<String>,
+    args: BTreeMap<u8, ArgMatch>,
+    eavesdrop: Option<bool>,
+}
+
+/// A builder for [`MatchRule`].
+///
+/// Setting the same key twice replaces the previous value. `path` and `path_namespace`, as well as
+/// the ways of matching a given argument, replace each other too.
+///
+/// [`MatchRule`]: struct.MatchRule.html
+#[derive(Clone, Debug, Default)]
+pub struct MatchRuleBuilder {
+    rule: MatchRule,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PathMatch {
+    Path(String),
+    Namespace(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum ArgMatch {
+    Str(String),
+    Path(String),
+    // Only possible for the first argument
+    Namespace(String),
+}
+
+impl MatchRule {
+    /// Create a builder for a `MatchRule`.
+    pub fn builder() -> MatchRuleBuilder {
+        MatchRuleBuilder::default()
+    }
+
+    /// The type of the matched messages (`type`).
+    pub fn msg_type(&self) -> Option<MessageType> {
+        self.msg_type
+    }
+
+    /// The sender of the matched messages (`sender`).
+    pub fn sender(&self) -> Option<&str> {
+        self.sender.as_deref()
+    }
+
+    /// The interface of the matched messages (`interface`).
+    pub fn interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+
+    /// The member of the matched messages (`member`).
+    pub fn member(&self) -> Option<&str> {
+        self.member.as_deref()
+    }
+
+    /// The object path of the matched messages (`path`).
+    pub fn path(&self) -> Option<&str> {
+        match &self.path {
+            Some(PathMatch::Path(path)) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The object path the matched messages are at or below (`path_namespace`).
+    pub fn path_namespace(&self) -> Option<&str> {
+        match &self.path {
+            Some(PathMatch::Namespace(namespace)) => Some(namespace),
+            _ => None,
+        }
+    }
+
+    /// The destination of the matched messages (`destination`).
+    pub fn destination(&self) -> Option<&str> {
+        self.destination.as_deref()
+    }
+
+    /// The string argument `index` of the matched messages is equal to (`argN`).
+    pub fn arg(&self, index: u8) -> Option<&str> {
+        match self.args.get(&index) {
+            Some(ArgMatch::Str(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The path the argument `index` of the matched messages is related to (`argNpath`).
+    pub fn arg_path(&self, index: u8) -> Option<&str> {
+        match self.args.get(&index) {
+            Some(ArgMatch::Path(path)) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The namespace of the first argument of the matched messages (`arg0namespace`).
+    pub fn arg0_namespace(&self) -> Option<&str> {
+        match self.args.get(&0) {
+            Some(ArgMatch::Namespace(namespace)) => Some(namespace),
+            _ => None,
+        }
+    }
+
+    /// Whether the rule asks to receive messages addressed to other connections (`eavesdrop`).
+    pub fn eavesdrop(&self) -> Option<bool> {
+        self.eavesdrop
+    }
+
+    /// Check whether `msg` matches the rule, like the bus does.
+    ///
+    /// Two parts of the rule are not checked, as they depend on the bus:
+    ///
+    /// - `sender` is compared with the sender field of the message as is, while the bus also
+    ///   matches a well-known name against the unique name of its owner.
+    /// - `eavesdrop` only decides whether the bus delivers messages addressed to other connections.
+    ///
+    /// Returns an error if the message is malformed.
+    pub fn matches(&self, msg: &Message) -> Result<bool> {
+        let h = msg.header()?;
+
+        if let Some(msg_type) = self.msg_type {
+            if h.message_type()? != msg_type {
+                return Ok(false);
+            }
+        }
+        if !matches_field(&self.sender, h.sender()?)
+            || !matches_field(&self.interface, h.interface()?)
+            || !matches_field(&self.member, h.member()?)
+            || !matches_field(&self.destination, h.destination()?)
+        {
+            return Ok(false);
+        }
+        if let Some(path_match) = &self.path {
+            let path = match h.path()? {
+                Some(path) => path.as_str(),
+                None => return Ok(false),
+            };
+            let matched = match path_match {
+                PathMatch::Path(p) => path == p,
+                PathMatch::Namespace(namespace) => is_in_namespace(path, namespace, '/'),
+            };
+            if !matched {
+                return Ok(false);
+            }
+        }
+
+        if self.args.is_empty() {
+            return Ok(true);
+        }
+        // Only decode the arguments up to the last one to match
+        let count = self.args.keys().next_back().map_or(0, |i| *i as usize + 1);
+        let values = msg.body_string_args(count)?;
+        for (index, arg_match) in &self.args {
+            let value = values.get(*index as usize).and_then(Option::as_ref);
+            let matched = match (arg_match, value) {
+                (ArgMatch::Str(s), Some(Value::Str(v))) => v.as_str() == s,
+                (ArgMatch::Path(p), Some(Value::Str(v))) => is_path_related(v.as_str(), p),
+                (ArgMatch::Path(p), Some(Value::ObjectPath(v))) => is_path_related(v.as_str(), p),
+                (ArgMatch::Namespace(namespace), Some(Value::Str(v))) => {
+                    is_in_namespace(v.as_str(), namespace, '.')
+                }
+                _ => false,
+            };
+            if !matched {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl MatchRuleBuilder {
+    /// Create a builder for a `MatchRule` matching all messages.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match messages of the given type (`type`).
+    pub fn msg_type(mut self, msg_type: MessageType) -> Self {
+        self.rule.msg_type = Some(msg_type);
+
+        self
+    }
+
+    /// Match messages sent by `sender` (`sender`).
+    pub fn sender(mut self, sender: &str) -> Self {
+        self.rule.sender = Some(sender.to_owned());
+
+        self
+    }
+
+    /// Match messages with the given interface (`interface`).
+    pub fn interface(mut self, interface: &str) -> Self {
+        self.rule.interface = Some(interface.to_owned());
+
+        self
+    }
+
+    /// Match messages with the given member (`member`).
+    pub fn member(mut self, member: &str) -> Self {
+        self.rule.member = Some(member.to_owned());
+
+        self
+    }
+
+    /// Match messages at the given object path (`path`).
+    pub fn path(mut self, path: &str) -> Self {
+        self.rule.path = Some(PathMatch::Path(path.to_owned()));
+
+        self
+    }
+
+    /// Match messages at the given object path, or below it (`path_namespace`).
+    pub fn path_namespace(mut self, namespace: &str) -> Self {
+        self.rule.path = Some(PathMatch::Namespace(namespace.to_owned()));
+
+        self
+    }
+
+    /// Match messages addressed to `destination` (`destination`).
+    pub fn destination(mut self, destination: &str) -> Self {
+        self.rule.destination = Some(destination.to_owned());
+
+        self
+    }
+
+    /// Match messages whose argument `index` is the string `value` (`argN`).
+    pub fn arg(mut self, index: u8, value: &str) -> Self {
+        self.rule
+            .args
+            .insert(index, ArgMatch::Str(value.to_owned()));
+
+        self
+    }
+
+    /// Match messages whose argument `index` is a string or object path equal to `path`, or
+    /// related to it (`argNpath`).
+    ///
+    /// If one of the argument and `path` ends with `/`, the other may be below it.
+    pub fn arg_path(mut self, index: u8, path: &str) -> Self {
+        self.rule
+            .args
+            .insert(index, ArgMatch::Path(path.to_owned()));
+
+        self
+    }
+
+    /// Match messages whose first argument is a bus name or interface name equal to `namespace`,
+    /// or in it (`arg0namespace`).
+    pub fn arg0_namespace(mut self, namespace: &str) -> Self {
+        self.rule
+            .args
+            .insert(0, ArgMatch::Namespace(namespace.to_owned()));
+
+        self
+    }
+
+    /// Ask the bus to also send messages addressed to other connections (`eavesdrop`).
+    pub fn eavesdrop(mut self, eavesdrop: bool) -> Self {
+        self.rule.eavesdrop = Some(eavesdrop);
+
+        self
+    }
+
+    /// Build the rule.
+    ///
+    /// Returns `Err(`[`Error::MatchRule`]`)` if the rule is invalid.
+    ///
+    /// [`Error::MatchRule`]: enum.Error.html#variant.MatchRule
+    pub fn build(self) -> Result<MatchRule> {
+        let rule = self.rule;
+        if rule.msg_type == Some(MessageType::Invalid) {
+            return Err(Error::MatchRule("invalid message type".into()));
+        }
+        match &rule.path {
+            Some(PathMatch::Path(path)) | Some(PathMatch::Namespace(path))
+                if !path.starts_with('/') =>
+            {
+                return Err(Error::MatchRule(format!("invalid object path '{}'", path)));
+            }
+            _ => (),
+        }
+        if let Some(index) = rule.args.keys().find(|i| **i > MAX_ARG_INDEX) {
+            return Err(Error::MatchRule(format!(
+                "argument index {} is out of range",
+                index
+            )));
+        }
+
+        Ok(rule)
+    }
+}
+
+impl FromStr for MatchRule {
+    type Err = Error;
+
+    /// Parse a match rule, like `type='signal',interface='org.zbus.MyIface'`.
+    fn from_str(rule: &str) -> Result<Self> {
+        let mut builder = MatchRule::builder();
+        let mut keys = vec![];
+        for (key, value) in split_rule(rule)? {
+            if keys.contains(&key) {
+                return Err(Error::MatchRule(format!("duplicate key '{}'", key)));
+            }
+
+            builder = match key.as_str() {
+                "type" => builder.msg_type(match value.as_str() {
+                    "signal" => MessageType::Signal,
+                    "method_call" => MessageType::MethodCall,
+                    "method_return" => MessageType::MethodReturn,
+                    "error" => MessageType::Error,
+                    _ => {
+                        return Err(Error::MatchRule(format!(
+                            "invalid message type '{}'",
+                            value
+                        )))
+                    }
+                }),
+                "sender" => builder.sender(&value),
+                "interface" => builder.interface(&value),
+                "member" => builder.member(&value),
+                "path" | "path_namespace" if keys.iter().any(|k| k.starts_with("path")) => {
+                    return Err(Error::MatchRule(
+                        "'path' and 'path_namespace' can't be both set".into(),
+                    ))
+                }
+                "path" => builder.path(&value),
+                "path_namespace" => builder.path_namespace(&value),
+                "destination" => builder.destination(&value),
+                "eavesdrop" => builder.eavesdrop(match value.as_str() {
+                    "true" => true,
+                    "false" => false,
+                    _ => {
+                        return Err(Error::MatchRule(format!(
+                            "invalid eavesdrop value '{}'",
+                            value
+                        )))
+                    }
+                }),
+                _ => {
+                    let (index, kind) = parse_arg_key(&key)?;
+                    if builder.rule.args.contains_key(&index) {
+                        return Err(Error::MatchRule(format!(
+                            "argument {} is matched more than once",
+                            index
+                        )));
+                    }
+                    match kind {
+                        "" => builder.arg(index, &value),
+                        "path" => builder.arg_path(index, &value),
+                        _ => builder.arg0_namespace(&value),
+                    }
+                }
+            };
+            keys.push(key);
+        }
+
+        builder.build()
+    }
+}
+
+impl fmt::Display for MatchRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg_type = self.msg_type.map(|t| match t {
+            MessageType::MethodCall => "method_call",
+            MessageType::MethodReturn => "method_return",
+            MessageType::Error => "error",
+            MessageType::Signal => "signal",
+            MessageType::Invalid => "invalid",
+        });
+        let mut pairs: Vec<(String, &str)> = vec![
+            ("type", msg_type),
+            ("sender", self.sender()),
+            ("interface", self.interface()),
+            ("member", self.member()),
+            ("path", self.path()),
+            ("path_namespace", self.path_namespace()),
+            ("destination", self.destination()),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key.to_owned(), value)))
+        .collect();
+        for (index, arg_match) in &self.args {
+            pairs.push(match arg_match {
+                ArgMatch::Str(value) => (format!("arg{}", index), value),
+                ArgMatch::Path(value) => (format!("arg{}path", index), value),
+                ArgMatch::Namespace(value) => (format!("arg{}namespace", index), value),
+            });
+        }
+        let eavesdrop = self.eavesdrop.map(|e| if e { "true" } else { "false" });
+        if let Some(eavesdrop) = eavesdrop {
+            pairs.push(("eavesdrop".to_owned(), eavesdrop));
+        }
+
+        for (i, (key, value)) in pairs.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            // A quote can't be escaped within quotes: close them, and escape it outside.
+            write!(f, "{}='{}'", key, value.replace('\'', "'\\''"))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Whether `field` matches the optional value of the rule
+fn matches_field(rule: &Option<String>, field: Option<&str>) -> bool {
+    match rule {
+        Some(value) => field == Some(value.as_str()),
+        None => true,
+    }
+}
+
+// Whether `name` is `namespace` itself, or below it
+fn is_in_namespace(name: &str, namespace: &str, separator: char) -> bool {
+    if !name.starts_with(namespace) {
+        return false;
+    }
+
+    name.len() == namespace.len()
+        || name[namespace.len()..].starts_with(separator)
+        || namespace.ends_with(separator)
+}
+
+// The `argNpath` semantics: equal paths, or one ending with `/` and being a prefix of the other
+fn is_path_related(arg: &str, path: &str) -> bool {
+    arg == path
+        || (path.ends_with('/') && arg.starts_with(path))
+        || (arg.ends_with('/') && path.starts_with(arg))
+}
+
+// Split a rule into its unquoted key-value pairs
+fn split_rule(rule: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = vec![];
+    let mut chars = rule.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+        loop {
+            match chars.next() {
+                Some('=') => break,
+                Some(c) => key.push(c),
+                None => return Err(Error::MatchRule(format!("key '{}' has no value", key))),
+            }
+        }
+        let key = key.trim_end().to_owned();
+        if key.is_empty() {
+            return Err(Error::MatchRule("empty key".into()));
+        }
+
+        // Like the reference implementation, quotes are optional, and a backslash only escapes a
+        // quote, outside of quotes.
+        let mut value = String::new();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' => quoted = !quoted,
+                '\\' if !quoted && chars.peek() == Some(&'\'') => {
+                    value.push('\'');
+                    chars.next();
+                }
+                ',' if !quoted => break,
+                c => value.push(c),
+            }
+        }
+        if quoted {
+            return Err(Error::MatchRule(format!(
+                "unterminated quote in the value of '{}'",
+                key
+            )));
+        }
+
+        pairs.push((key, value));
+    }
+
+    Ok(pairs)
+}
+
+// Split an `argN`, `argNpath` or `arg0namespace` key into its index and kind
+fn parse_arg_key(key: &str) -> Result<(u8, &str)> {
+    let unknown = || Error::MatchRule(format!("unknown key '{}'", key));
+    let rest = key.strip_prefix("arg").ok_or_else(unknown)?;
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    // No leading zeros, like the reference implementation
+    if digits == 0 || (digits > 1 && rest.starts_with('0')) {
+        return Err(unknown());
+    }
+    let index = rest[..digits]
+        .parse::<u8>()
+        .ok()
+        .filter(|i| *i <= MAX_ARG_INDEX)
+        .ok_or_else(|| Error::MatchRule(format!("argument index of '{}' is out of range", key)))?;
+
+    match &rest[digits..] {
+        "" => Ok((index, "")),
+        "path" => Ok((index, "path")),
+        "namespace" if index == 0 => Ok((index, "namespace")),
+        _ => Err(unknown()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+
+    use zvariant::{ObjectPath, Value};
+
+    use crate::{Error, MatchRule, Message, MessageType};
+
+    fn signal(path: &str, iface: &str, member: &str, arg0: &str, arg1: &ObjectPath) -> Message {
+        let mut msg = Message::signal(
+            Some(":1.42"),
+            Some(":1.7"),
+            path,
+            iface,
+            member,
+            &(arg0, arg1, vec![1u32, 2], 3u8),
+        )
+        .unwrap();
+        msg.modify_primary_header(|primary| {
+            primary.set_serial_num(1);
+
+            Ok(())
+        })
+        .unwrap();
+
+        msg
+    }
+
+    fn matches(rule: &str, msg: &Message) -> bool {
+        rule.parse::<MatchRule>().unwrap().matches(msg).unwrap()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        let rule: MatchRule = " type='signal', sender=org.freedesktop.DBus,\
+             path_namespace='/org/zbus',arg0='it'\\''s',arg2path='/a/',arg5='',eavesdrop='true'"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.msg_type(), Some(MessageType::Signal));
+        assert_eq!(rule.sender(), Some("org.freedesktop.DBus"));
+        assert_eq!(rule.path(), None);
+        assert_eq!(rule.path_namespace(), Some("/org/zbus"));
+        assert_eq!(rule.arg(0), Some("it's"));
+        assert_eq!(rule.arg_path(2), Some("/a/"));
+        assert_eq!(rule.arg(5), Some(""));
+        assert_eq!(rule.eavesdrop(), Some(true));
+        assert_eq!(
+            rule.to_string(),
+            "type='signal',sender='org.freedesktop.DBus',path_namespace='/org/zbus',\
+             arg0='it'\\''s',arg2path='/a/',arg5='',eavesdrop='true'"
+        );
+        assert_eq!(rule.to_string().parse::<MatchRule>().unwrap(), rule);
+
+        let rule = MatchRule::builder()
+            .destination(":1.7")
+            .arg0_namespace("org.zbus")
+            .member("Changed")
+            .build()
+            .unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "member='Changed',destination=':1.7',arg0namespace='org.zbus'"
+        );
+        assert_eq!("".parse::<MatchRule>().unwrap(), MatchRule::default());
+
+        for invalid in &[
+            "type='invalid'",
+            "foo='bar'",
+            "type='signal',type='error'",
+            "path='/a',path_namespace='/a'",
+            "path='a'",
+            "arg0='a',arg0path='/a'",
+            "arg64='a'",
+            "arg01='a'",
+            "arg1namespace='a'",
+            "arg0",
+            "member='a",
+            "eavesdrop='yes'",
+        ] {
+            assert!(
+                matches!(invalid.parse::<MatchRule>(), Err(Error::MatchRule(_))),
+                "{} should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn matching() {
+        let path = ObjectPath::try_from("/org/zbus/a/b").unwrap();
+        let msg = signal(
+            "/org/zbus/Obj",
+            "org.zbus.Iface",
+            "Changed",
+            "org.zbus.Name",
+            &path,
+        );
+
+        assert!(matches("", &msg));
+        assert!(matches(
+            "type='signal',sender=':1.42',interface='org.zbus.Iface',member='Changed',\
+             path='/org/zbus/Obj',destination=':1.7',eavesdrop='true'",
+            &msg
+        ));
+        assert!(!matches("type='method_call'", &msg));
+        assert!(!matches("sender=':1.43'", &msg));
+        assert!(!matches("interface='org.zbus.Other'", &msg));
+        assert!(!matches("member='Other'", &msg));
+        assert!(!matches("path='/org/zbus'", &msg));
+        assert!(!matches("destination=':1.8'", &msg));
+
+        assert!(matches("path_namespace='/'", &msg));
+        assert!(matches("path_namespace='/org/zbus'", &msg));
+        assert!(matches("path_namespace='/org/zbus/Obj'", &msg));
+        assert!(!matches("path_namespace='/org/zb'", &msg));
+
+        assert!(matches("arg0='org.zbus.Name'", &msg));
+        assert!(!matches("arg0='org.zbus'", &msg));
+        // Only strings match `argN`, and missing arguments match nothing
+        assert!(!matches("arg1='/org/zbus/a/b'", &msg));
+        assert!(!matches("arg3='3'", &msg));
+        assert!(!matches("arg4=''", &msg));
+
+        assert!(matches("arg0namespace='org.zbus'", &msg));
+        assert!(matches("arg0namespace='org.zbus.Name'", &msg));
+        assert!(!matches("arg0namespace='org.zb'", &msg));
+
+        assert!(matches("arg1path='/org/zbus/a/b'", &msg));
+        assert!(matches("arg1path='/org/zbus/'", &msg));
+        assert!(!matches("arg1path='/org/zbus'", &msg));
+        assert!(!matches("arg1path='/org/zbus/a/b/c'", &msg));
+        // Object paths can't end with `/`, strings can
+        let path = ObjectPath::try_from("/org/zbus/a").unwrap();
+        let msg = signal("/", "org.zbus.Iface", "Changed", "/org/", &path);
+        assert!(matches("arg0path='/org/zbus/a/b'", &msg));
+        assert!(matches("arg0path='/org/'", &msg));
+        assert!(!matches("arg0path='/org'", &msg));
+        assert!(matches("arg1path='/org/'", &msg));
+        assert!(!matches("arg1path='/org/zbus/a/b'", &msg));
+    }
+
+    #[test]
+    fn matching_long_signature() {
+        // The longest signature possible: 255 bytes, with a string first
+        let t = (
+            0u8, 1u8, 2u8, 3u8, 4u8, 5u8, 6u8, 7u8, 8u8, 9u8, 10u8, 11u8, 12u8, 13u8, 14u8, 15u8,
+        );
+        let body = ("org.zbus.Name", (t, t, t, t, t, t, t, t, t, t, t, t, t, t));
+        let msg = Message::signal(None, None, "/", "org.zbus.Iface", "Changed", &body).unwrap();
+        assert_eq!(msg.body_signature().unwrap().len(), 255);
+
+        assert!(matches("arg0='org.zbus.Name'", &msg));
+        assert!(matches("arg0namespace='org.zbus'", &msg));
+        assert!(!matches("arg1=''", &msg));
+        assert!(!matches("arg0='org.zbus.Name',arg2=''", &msg));
+
+        // Containers before the matched argument are skipped over
+        let mut dict = HashMap::new();
+        dict.insert("v", Value::from((1u32, "a")));
+        let body = (dict, vec![(1u8, "b"), (2u8, "c")], "org.zbus.Name");
+        let msg = Message::signal(None, None, "/", "org.zbus.Iface", "Changed", &body).unwrap();
+        assert!(matches("arg2='org.zbus.Name'", &msg));
+        assert!(!matches("arg1='b'", &msg));
+    }
+}
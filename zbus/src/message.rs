@@ -4,8 +4,9 @@ use std::fmt;
 use std::io::{Cursor, Error as IOError};
 use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 
+use serde::de::{self, Deserialize, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
 use zvariant::{EncodingContext, Error as VariantError};
-use zvariant::{Signature, Type};
+use zvariant::{ObjectPath, Signature, Type, Value};
 
 use crate::owned_fd::OwnedFd;
 use crate::utils::padding_for_8_bytes;
@@ -480,6 +481,54 @@ impl Message {
         self.body_unchecked()
     }
 
+    /// Deserialize the first `count` arguments of the body (fewer if there are less), keeping the
+    /// strings and object paths only, since those are the ones match rules can match.
+    ///
+    /// The other arguments are skipped, and come as `None`.
+    pub(crate) fn body_string_args(
+        &self,
+        count: usize,
+    ) -> Result<Vec<Option<Value<'_>>>, MessageError> {
+        let sig = match self.body_signature() {
+            Ok(sig) => sig,
+            Err(MessageError::NoBodySignature) => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        if self.bytes_to_completion()? != 0 {
+            return Err(MessageError::InsufficientData);
+        }
+
+        let mut header_len = MIN_MESSAGE_SIZE + self.fields_len()?;
+        header_len = header_len + padding_for_8_bytes(header_len);
+        let fds = self.fds();
+        // The arguments are decoded one after the other, following the body signature
+        let mut de = zvariant::Deserializer::new(
+            &self.bytes[header_len..],
+            Some(&fds),
+            &sig,
+            dbus_context!(0),
+        );
+
+        let mut args = vec![];
+        let mut rest = sig.as_str();
+        while !rest.is_empty() && args.len() < count {
+            let len = complete_type_len(rest)?;
+            let arg = match &rest[..len] {
+                "s" => Some(Value::from(<&str>::deserialize(&mut de)?)),
+                "o" => Some(Value::from(ObjectPath::deserialize(&mut de)?)),
+                arg_sig => {
+                    SkipValue(arg_sig).deserialize(&mut de)?;
+
+                    None
+                }
+            };
+            args.push(arg);
+            rest = &rest[len..];
+        }
+
+        Ok(args)
+    }
+
     pub(crate) fn fds(&self) -> Vec<RawFd> {
         match &self.fds {
             Fds::Raw(fds) => fds.clone(),
@@ -499,6 +548,109 @@ impl Message {
     }
 }
 
+// The length of the single complete type `sig` starts with
+fn complete_type_len(sig: &str) -> Result<usize, MessageError> {
+    let mut depth = 0usize;
+    for (i, c) in sig.bytes().enumerate() {
+        match c {
+            // An array of the type that follows
+            b'a' => continue,
+            b'(' | b'{' => depth += 1,
+            b')' | b'}' => depth = depth.checked_sub(1).ok_or(MessageError::InvalidField)?,
+            _ => (),
+        }
+        if depth == 0 {
+            return Ok(i + 1);
+        }
+    }
+
+    Err(MessageError::InvalidField)
+}
+
+// Skips over a value of the given signature.
+//
+// Needed for the containers, since the structure fields have to be asked for one by one.
+struct SkipValue<'s>(&'s str);
+
+impl<'de, 's> DeserializeSeed<'de> for SkipValue<'s> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, 's> Visitor<'de> for SkipValue<'s> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "a value of signature `{}`", self.0)
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        match self.0.as_bytes().first() {
+            Some(b'a') => while seq.next_element_seed(SkipValue(&self.0[1..]))?.is_some() {},
+            Some(b'(') => {
+                let mut fields = &self.0[1..self.0.len() - 1];
+                while !fields.is_empty() {
+                    let len = complete_type_len(fields).map_err(de::Error::custom)?;
+                    seq.next_element_seed(SkipValue(&fields[..len]))?;
+                    fields = &fields[len..];
+                }
+            }
+            Some(b'v') => {
+                let sig = seq
+                    .next_element::<Signature<'_>>()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                seq.next_element_seed(SkipValue(sig.as_str()))?;
+            }
+            _ => return Err(de::Error::invalid_type(de::Unexpected::Seq, &self)),
+        }
+
+        Ok(())
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<(), A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        // `a{kv}`, with a basic type key
+        let value_sig = &self.0[3..self.0.len() - 1];
+        while map
+            .next_entry_seed(SkipValue(&self.0[2..3]), SkipValue(value_sig))?
+            .is_some()
+        {}
+
+        Ok(())
+    }
+}
+
 impl fmt::Debug for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut msg = f.debug_struct("Msg");
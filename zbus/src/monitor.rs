@@ -0,0 +1,177 @@
+use crate::connection::is_disconnected;
+use crate::{Connection, Message, MessageDirection, Result};
+
+const BUS_NAME: &str = "org.freedesktop.DBus";
+
+/// A connection to a message bus in monitor mode.
+///
+/// Once a connection calls `BecomeMonitor` on the bus, it's no longer a regular client: it may not
+/// send messages anymore, and it receives a copy of the messages going through the bus that match
+/// its match rules, whoever they were sent to. This type takes care of becoming a monitor, and
+/// only allows receiving messages afterwards, each tagged with its direction relative to the bus:
+///
+/// - [`MessageDirection::Incoming`] for the messages the bus received from its clients,
+/// - [`MessageDirection::Outgoing`] for the messages the bus sent itself, like the replies to the
+///   calls to the `org.freedesktop.DBus` interface, or the `NameOwnerChanged` signals.
+///
+/// A `MonitorConnection` is also an [`Iterator`] over the received messages, ending once the
+/// connection is closed.
+///
+/// # Example
+///
+/// A `dbus-monitor`-like tool, listing the signals on the session bus:
+///
+/// ```no_run
+///# use std::error::Error;
+///#
+/// use zbus::MonitorConnection;
+///
+/// for msg in MonitorConnection::new_session(&["type='signal'"])? {
+///     let (direction, msg) = msg?;
+///     println!("{:?}: {}", direction, msg);
+/// }
+///# Ok::<_, Box<dyn Error + Send + Sync>>(())
+/// ```
+///
+/// [`MessageDirection::Incoming`]: enum.MessageDirection.html#variant.Incoming
+/// [`MessageDirection::Outgoing`]: enum.MessageDirection.html#variant.Outgoing
+/// [`Iterator`]: https://doc.rust-lang.org/std/iter/trait.Iterator.html
+#[derive(Debug)]
+pub struct MonitorConnection {
+    conn: Connection,
+}
+
+impl MonitorConnection {
+    /// Create a `MonitorConnection` to the session/user message bus.
+    ///
+    /// Only the messages matching one of the `match_rules` are received, or all of them if no rule
+    /// is given.
+    pub fn new_session(match_rules: &[&str]) -> Result<Self> {
+        Self::new(Connection::new_session()?, match_rules)
+    }
+
+    /// Create a `MonitorConnection` to the system-wide message bus.
+    ///
+    /// Monitoring the system bus is usually restricted to privileged users.
+    pub fn new_system(match_rules: &[&str]) -> Result<Self> {
+        Self::new(Connection::new_system()?, match_rules)
+    }
+
+    /// Create a `MonitorConnection` to the message bus at the given D-Bus address.
+    pub fn new_for_address(address: &str, match_rules: &[&str]) -> Result<Self> {
+        Self::new(Connection::new_for_address(address, true)?, match_rules)
+    }
+
+    /// Turn the bus connection `conn` into a monitor connection.
+    ///
+    /// Settings like the max number of queued messages are kept, so this is the way to configure
+    /// the connection. The connection must not be used for anything else afterwards.
+    pub fn new(conn: Connection, match_rules: &[&str]) -> Result<Self> {
+        conn.call_method(
+            Some(BUS_NAME),
+            "/org/freedesktop/DBus",
+            Some("org.freedesktop.DBus.Monitoring"),
+            "BecomeMonitor",
+            &(match_rules, 0u32),
+        )?;
+
+        Ok(Self { conn })
+    }
+
+    /// The unique name the connection had on the bus, before becoming a monitor.
+    pub fn unique_name(&self) -> Option<&str> {
+        self.conn.unique_name()
+    }
+
+    /// Receive the next monitored message, along with its direction relative to the bus.
+    ///
+    /// Like with [`Connection::receive_message`], the local `Disconnected` signal is received once
+    /// the connection is closed, as an incoming message, and an error afterwards.
+    ///
+    /// [`Connection::receive_message`]: struct.Connection.html#method.receive_message
+    pub fn receive_message(&self) -> Result<(MessageDirection, Message)> {
+        let msg = self.conn.receive_message()?;
+        let direction = if msg.header()?.sender()? == Some(BUS_NAME) {
+            MessageDirection::Outgoing
+        } else {
+            MessageDirection::Incoming
+        };
+
+        Ok((direction, msg))
+    }
+
+    // The underlying connection, which must only be used to receive messages
+    pub(crate) fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Close the connection, ending the monitoring.
+    pub fn close(&self) -> Result<()> {
+        self.conn.close()
+    }
+}
+
+impl Iterator for MonitorConnection {
+    type Item = Result<(MessageDirection, Message)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.conn.is_closed() {
+            return None;
+        }
+
+        match self.receive_message() {
+            Ok((_, msg)) if is_disconnected(&msg).unwrap_or(false) => None,
+            res => Some(res),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntest::timeout;
+
+    use crate::{Connection, MessageDirection, MessageType, MonitorConnection};
+
+    #[test]
+    #[timeout(2000)]
+    fn monitor() {
+        let mut monitor = MonitorConnection::new_session(&[
+            "type='method_call',member='GetId'",
+            "type='method_return'",
+        ])
+        .unwrap();
+
+        let conn = Connection::new_session().unwrap();
+        let name = conn.unique_name().unwrap().to_owned();
+        let id = crate::fdo::DBusProxy::new(&conn).unwrap().get_id().unwrap();
+
+        // The `GetId` call from the client, then the reply of the bus. The reply to the `Hello`
+        // of the client, and the messages of other clients of the bus may come first.
+        let (direction, call) = monitor
+            .by_ref()
+            .map(Result::unwrap)
+            .find(|(_, msg)| {
+                msg.header().unwrap().message_type().unwrap() == MessageType::MethodCall
+            })
+            .unwrap();
+        assert_eq!(direction, MessageDirection::Incoming);
+        let h = call.header().unwrap();
+        assert_eq!(h.sender().unwrap(), Some(name.as_str()));
+        assert_eq!(h.member().unwrap(), Some("GetId"));
+
+        let (direction, reply) = monitor
+            .by_ref()
+            .map(Result::unwrap)
+            .find(|(_, msg)| {
+                msg.header().unwrap().destination().unwrap() == Some(name.as_str())
+                    && msg.header().unwrap().reply_serial().unwrap()
+                        == Some(call.primary_header().unwrap().serial_num())
+            })
+            .unwrap();
+        assert_eq!(direction, MessageDirection::Outgoing);
+        assert_eq!(reply.body::<&str>().unwrap(), id);
+
+        monitor.close().unwrap();
+        assert!(monitor.next().is_none());
+    }
+}
@@ -9,10 +9,28 @@ use std::rc::Rc;
 use scoped_tls::scoped_thread_local;
 use zvariant::{ObjectPath, OwnedValue, Value};
 
-use crate::{dbus_interface, fdo, Connection, Error, Message, MessageHeader, MessageType, Result};
+use crate::{
+    dbus_interface, fdo, Connection, Error, Message, MessageFlags, MessageHeader, MessageType,
+    Result,
+};
 
 scoped_thread_local!(static LOCAL_NODE: Node);
 scoped_thread_local!(static LOCAL_CONNECTION: Connection);
+// The serial of the dispatched call, if the caller doesn't expect a reply
+scoped_thread_local!(static NO_REPLY_SERIAL: u32);
+
+// Whether `msg` is a reply to a dispatched call not expecting any, so it must not be sent
+pub(crate) fn is_unexpected_reply(msg: &Message) -> bool {
+    NO_REPLY_SERIAL.is_set()
+        && NO_REPLY_SERIAL.with(|serial| {
+            let reply_serial = msg
+                .header()
+                .ok()
+                .and_then(|h| h.reply_serial().ok().flatten());
+
+            reply_serial == Some(*serial)
+        })
+}
 
 /// The trait used to dispatch messages to an interface instance.
 ///
@@ -521,6 +539,22 @@ impl<'a> ObjectServer<'a> {
     }
 
     fn dispatch_method_call(&mut self, msg_header: &MessageHeader, msg: &Message) -> Result<u32> {
+        let primary = msg.primary_header()?;
+        if primary.flags().contains(MessageFlags::NoReplyExpected) {
+            let serial = primary.serial_num();
+
+            return NO_REPLY_SERIAL
+                .set(&serial, || self.dispatch_method_call_reply(msg_header, msg));
+        }
+
+        self.dispatch_method_call_reply(msg_header, msg)
+    }
+
+    fn dispatch_method_call_reply(
+        &mut self,
+        msg_header: &MessageHeader,
+        msg: &Message,
+    ) -> Result<u32> {
         match self.dispatch_method_call_try(msg_header, msg) {
             Err(e) => e.reply(&self.conn, msg),
             Ok(r) => r,
@@ -536,7 +570,8 @@ impl<'a> ObjectServer<'a> {
     /// - calling the associated method if one exists,
     ///
     /// - returning a message (responding to the caller with either a return or error message) to
-    ///   the caller through the associated server connection.
+    ///   the caller through the associated server connection, unless the caller doesn't expect a
+    ///   reply (the call has the [`MessageFlags::NoReplyExpected`] flag).
     ///
     /// Returns an error if the message is malformed, true if it's handled, false otherwise.
     ///
@@ -544,6 +579,8 @@ impl<'a> ObjectServer<'a> {
     ///
     /// This API is subject to change, or becoming internal-only once zbus provides a general
     /// mechanism to dispatch messages.
+    ///
+    /// [`MessageFlags::NoReplyExpected`]: enum.MessageFlags.html#variant.NoReplyExpected
     pub fn dispatch_message(&mut self, msg: &Message) -> Result<bool> {
         let msg_header = msg.header()?;
 
@@ -556,6 +593,15 @@ impl<'a> ObjectServer<'a> {
         }
     }
 
+    // Dispatch `msg`, received on `conn` rather than on the associated connection, and reply there
+    pub(crate) fn dispatch_message_on(&mut self, conn: &Connection, msg: &Message) -> Result<bool> {
+        let own = std::mem::replace(&mut self.conn, conn.clone());
+        let res = self.dispatch_message(msg);
+        self.conn = own;
+
+        res
+    }
+
     /// Receive and handle the next message from the associated connection.
     ///
     /// This function will read the incoming message from
@@ -593,7 +639,10 @@ mod tests {
     use zvariant::derive::Type;
 
     use crate::fdo;
-    use crate::{dbus_interface, dbus_proxy, Connection, MessageHeader, MessageType, ObjectServer};
+    use crate::{
+        dbus_interface, dbus_proxy, Connection, MessageFlags, MessageHeader, MessageType,
+        ObjectServer,
+    };
 
     #[derive(Deserialize, Serialize, Type)]
     pub struct ArgStructTest {
@@ -695,6 +744,11 @@ mod tests {
         })?;
         proxy.introspect()?;
         let val = proxy.ping()?;
+        // No reply, not even an error one, is sent for calls not expecting any
+        let flags = MessageFlags::NoReplyExpected.into();
+        assert!(proxy
+            .call_method_with_flags("TestError", flags, &())?
+            .is_none());
         proxy.quit(true)?;
         Ok(val)
     }
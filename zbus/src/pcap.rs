@@ -0,0 +1,487 @@
+//! Capture and replay of D-Bus traffic, in the pcap format.
+//!
+//! The captures use the `LINKTYPE_DBUS` link-layer type, where each packet is a complete D-Bus
+//! message, like those of `dbus-monitor --pcap` or `busctl capture`. They can be opened in
+//! Wireshark, and read back with a [`Reader`].
+//!
+//! [`Reader`]: struct.Reader.html
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, NativeEndian, WriteBytesExt};
+
+use crate::{
+    Connection, Error, FilterId, Message, MessageDirection, MessageError, MonitorConnection, Result,
+};
+
+/// The pcap link-layer type of D-Bus messages.
+pub const LINKTYPE_DBUS: u32 = 231;
+
+// The largest message allowed by the specification
+const SNAPLEN: u32 = 128 * 1024 * 1024;
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+// The fixed part of a message, long enough to know the size of the rest
+const MIN_MESSAGE_SIZE: usize = 16;
+
+/// Writes messages to a pcap capture.
+///
+/// # Example
+///
+/// Recording the traffic of a connection:
+///
+/// ```no_run
+///# use std::error::Error;
+///#
+/// use std::fs::File;
+/// use zbus::{pcap, Connection};
+///
+/// let conn = Connection::new_session()?;
+/// let capture = pcap::Writer::new(File::create("session.pcap")?)?.attach(&conn);
+/// zbus::fdo::DBusProxy::new(&conn)?.get_id()?;
+/// capture.detach()?;
+///# Ok::<_, Box<dyn Error + Send + Sync>>(())
+/// ```
+#[derive(Debug)]
+pub struct Writer<W> {
+    writer: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// Create a writer, writing the pcap header right away.
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_u32::<NativeEndian>(MAGIC_MICROS)?;
+        writer.write_u16::<NativeEndian>(2)?;
+        writer.write_u16::<NativeEndian>(4)?;
+        // The time zone offset and the accuracy of the timestamps, always 0 in practice
+        writer.write_i32::<NativeEndian>(0)?;
+        writer.write_u32::<NativeEndian>(0)?;
+        writer.write_u32::<NativeEndian>(SNAPLEN)?;
+        writer.write_u32::<NativeEndian>(LINKTYPE_DBUS)?;
+
+        Ok(Self { writer })
+    }
+
+    /// Record `msg`, with the current time.
+    pub fn write_message(&mut self, msg: &Message) -> Result<()> {
+        self.write_message_at(msg, SystemTime::now())
+    }
+
+    /// Record `msg`, with the given time.
+    pub fn write_message_at(&mut self, msg: &Message, time: SystemTime) -> Result<()> {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let bytes = msg.as_bytes();
+
+        self.writer
+            .write_u32::<NativeEndian>(time.as_secs() as u32)?;
+        self.writer
+            .write_u32::<NativeEndian>(time.subsec_micros())?;
+        self.writer.write_u32::<NativeEndian>(bytes.len() as u32)?;
+        self.writer.write_u32::<NativeEndian>(bytes.len() as u32)?;
+        self.writer.write_all(bytes)?;
+
+        Ok(())
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush().map_err(Error::from)
+    }
+
+    /// Get the underlying writer back.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send + 'static> Writer<W> {
+    /// Record all the messages sent and received on `conn`, until the returned [`Capture`] is
+    /// detached.
+    ///
+    /// The messages are recorded from message filters, added last: they are recorded as the
+    /// filters added before let them through.
+    ///
+    /// [`Capture`]: struct.Capture.html
+    pub fn attach(self, conn: &Connection) -> Capture<W> {
+        self.attach_filters(
+            conn,
+            &[MessageDirection::Incoming, MessageDirection::Outgoing],
+        )
+    }
+
+    /// Record all the messages received by `monitor`, until the returned [`Capture`] is detached.
+    ///
+    /// This records the traffic of the bus matching the match rules of the monitor, like
+    /// `dbus-monitor --pcap` does. Like with [`attach`], the messages are recorded from a message
+    /// filter, before [`MonitorConnection::receive_message`] returns them.
+    ///
+    /// [`Capture`]: struct.Capture.html
+    /// [`attach`]: struct.Writer.html#method.attach
+    /// [`MonitorConnection::receive_message`]: ../struct.MonitorConnection.html#method.receive_message
+    pub fn attach_monitor(self, monitor: &MonitorConnection) -> Capture<W> {
+        // A monitor only receives messages
+        self.attach_filters(monitor.connection(), &[MessageDirection::Incoming])
+    }
+
+    fn attach_filters(self, conn: &Connection, directions: &[MessageDirection]) -> Capture<W> {
+        let shared = Arc::new(Mutex::new(Some(Shared {
+            writer: self,
+            error: None,
+        })));
+        let filters = directions
+            .iter()
+            .map(|direction| {
+                let shared = shared.clone();
+                conn.add_filter(*direction, move |msg| {
+                    // The filter may still run once detached, when it was just about to
+                    if let Some(shared) = shared.lock().expect("lock poisoned").as_mut() {
+                        shared.write(&msg);
+                    }
+
+                    Some(msg)
+                })
+            })
+            .collect();
+
+        Capture {
+            conn: conn.clone(),
+            filters,
+            shared,
+        }
+    }
+}
+
+/// A [`Writer`] recording the traffic of a [`Connection`].
+///
+/// This is created by [`Writer::attach`] or [`Writer::attach_monitor`].
+///
+/// [`Writer`]: struct.Writer.html
+/// [`Connection`]: ../struct.Connection.html
+/// [`Writer::attach`]: struct.Writer.html#method.attach
+/// [`Writer::attach_monitor`]: struct.Writer.html#method.attach_monitor
+#[derive(Debug)]
+pub struct Capture<W> {
+    conn: Connection,
+    filters: Vec<FilterId>,
+    // Taken back on detach
+    shared: Arc<Mutex<Option<Shared<W>>>>,
+}
+
+#[derive(Debug)]
+struct Shared<W> {
+    writer: Writer<W>,
+    // The first write error, as filters can't report it
+    error: Option<Error>,
+}
+
+impl<W: Write> Shared<W> {
+    fn write(&mut self, msg: &Message) {
+        if self.error.is_none() {
+            if let Err(e) = self.writer.write_message(msg) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl<W: Write> Capture<W> {
+    /// Stop recording, and get the writer back.
+    ///
+    /// If writing a message failed, the recording stopped there and the error is returned.
+    pub fn detach(self) -> Result<Writer<W>> {
+        for id in &self.filters {
+            self.conn.remove_filter(*id);
+        }
+        let shared = self
+            .shared
+            .lock()
+            .expect("lock poisoned")
+            .take()
+            .expect("capture detached twice");
+        if let Some(e) = shared.error {
+            return Err(e);
+        }
+
+        let mut writer = shared.writer;
+        writer.flush()?;
+
+        Ok(writer)
+    }
+}
+
+/// Reads messages from a pcap capture.
+///
+/// Captures of both byte orders, with microsecond or nanosecond timestamps, can be read. The
+/// messages must be in the native byte order though, and complete: captures truncated by a small
+/// snapshot length can't be read.
+///
+/// The reader is an [`Iterator`] over the messages, along with the time they were recorded.
+///
+/// # Example
+///
+/// Replaying the method calls of a capture into an [`ObjectServer`]:
+///
+/// ```no_run
+///# use std::error::Error;
+///#
+/// use std::fs::File;
+/// use zbus::{pcap, Connection, MessageType, ObjectServer};
+///
+/// let conn = Connection::new_session()?;
+/// let mut object_server = ObjectServer::new(&conn);
+/// // Register the interfaces...
+///
+/// for msg in pcap::Reader::new(File::open("session.pcap")?)? {
+///     let (_, msg) = msg?;
+///     if msg.header()?.message_type()? == MessageType::MethodCall {
+///         object_server.dispatch_message(&msg)?;
+///     }
+/// }
+///# Ok::<_, Box<dyn Error + Send + Sync>>(())
+/// ```
+///
+/// [`Iterator`]: https://doc.rust-lang.org/std/iter/trait.Iterator.html
+/// [`ObjectServer`]: ../struct.ObjectServer.html
+#[derive(Debug)]
+pub struct Reader<R> {
+    reader: R,
+    big_endian: bool,
+    nanos: bool,
+}
+
+impl<R: Read> Reader<R> {
+    /// Create a reader, reading and checking the pcap header right away.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header)?;
+        let (big_endian, nanos) = match (
+            LittleEndian::read_u32(&header),
+            BigEndian::read_u32(&header),
+        ) {
+            (MAGIC_MICROS, _) => (false, false),
+            (MAGIC_NANOS, _) => (false, true),
+            (_, MAGIC_MICROS) => (true, false),
+            (_, MAGIC_NANOS) => (true, true),
+            _ => return Err(invalid_data("not a pcap capture")),
+        };
+        let reader = Self {
+            reader,
+            big_endian,
+            nanos,
+        };
+        if reader.read_u32(&header[20..]) != LINKTYPE_DBUS {
+            return Err(invalid_data("not a capture of D-Bus messages"));
+        }
+
+        Ok(reader)
+    }
+
+    /// Read the next message, along with the time it was recorded.
+    ///
+    /// Returns `Ok(None)` at the end of the capture.
+    pub fn read_message(&mut self) -> Result<Option<(SystemTime, Message)>> {
+        let mut header = [0; 16];
+        // Only the end of the capture may come before the first byte of a record
+        match self.reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut header[1..])?,
+        }
+        let secs = self.read_u32(&header[0..]);
+        let fraction = self.read_u32(&header[4..]);
+        let len = self.read_u32(&header[8..]) as usize;
+        if len < MIN_MESSAGE_SIZE || len > SNAPLEN as usize {
+            return Err(invalid_data("invalid pcap record length"));
+        }
+        let mut bytes = vec![0; len];
+        self.reader.read_exact(&mut bytes)?;
+
+        let fraction = if self.nanos {
+            Duration::from_nanos(fraction.into())
+        } else {
+            Duration::from_micros(fraction.into())
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(secs.into()) + fraction;
+
+        let mut msg = Message::from_bytes(&bytes[..MIN_MESSAGE_SIZE])?;
+        msg.add_bytes(&bytes[MIN_MESSAGE_SIZE..])?;
+        if msg.bytes_to_completion()? != 0 {
+            return Err(MessageError::InsufficientData.into());
+        }
+
+        Ok(Some((time, msg)))
+    }
+
+    /// Get the underlying reader back.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_u32(&self, buf: &[u8]) -> u32 {
+        if self.big_endian {
+            BigEndian::read_u32(buf)
+        } else {
+            LittleEndian::read_u32(buf)
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<(SystemTime, Message)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::io::Cursor;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use ntest::timeout;
+
+    use super::{Reader, Writer};
+    use crate::{
+        dbus_interface, Connection, Error, Guid, Message, MessageType, MonitorConnection,
+        ObjectServer,
+    };
+
+    struct Echo;
+
+    #[dbus_interface(name = "org.zbus.Echo")]
+    impl Echo {
+        fn echo(&self, s: &str) -> String {
+            s.to_owned()
+        }
+    }
+
+    // A p2p connection pair, the server side serving `Echo` for `calls` calls
+    fn serve_echo(calls: usize) -> (Connection, thread::JoinHandle<()>) {
+        let guid = Guid::generate();
+        let (p0, p1) = UnixStream::pair().unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let conn = Connection::new_unix_server(p0, &guid).unwrap();
+            let mut object_server = ObjectServer::new(&conn);
+            object_server
+                .at(&"/org/zbus/Echo".try_into().unwrap(), Echo)
+                .unwrap();
+            for _ in 0..calls {
+                object_server.try_handle_next().unwrap();
+            }
+        });
+
+        (
+            Connection::new_unix_client(p1, false).unwrap(),
+            server_thread,
+        )
+    }
+
+    fn echo(conn: &Connection, s: &str) -> String {
+        conn.call_method(None, "/org/zbus/Echo", Some("org.zbus.Echo"), "Echo", &s)
+            .unwrap()
+            .body::<String>()
+            .unwrap()
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn capture_and_replay() {
+        let (conn, server_thread) = serve_echo(2);
+        let capture = Writer::new(vec![]).unwrap().attach(&conn);
+        assert_eq!(echo(&conn, "hello"), "hello");
+        assert_eq!(echo(&conn, "world"), "world");
+        let bytes = capture.detach().unwrap().into_inner();
+        server_thread.join().expect("failed to join server thread");
+
+        let messages = Reader::new(Cursor::new(bytes))
+            .unwrap()
+            .map(|r| r.unwrap().1)
+            .collect::<Vec<_>>();
+        let types = messages
+            .iter()
+            .map(|m| m.header().unwrap().message_type().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                MessageType::MethodCall,
+                MessageType::MethodReturn,
+                MessageType::MethodCall,
+                MessageType::MethodReturn
+            ]
+        );
+
+        // Replay the calls, expecting the same replies
+        let (conn, server_thread) = serve_echo(2);
+        for pair in messages.chunks(2) {
+            let call = pair[0].body::<&str>().unwrap();
+            assert_eq!(echo(&conn, call), pair[1].body::<&str>().unwrap());
+        }
+        server_thread.join().expect("failed to join server thread");
+    }
+
+    #[test]
+    fn format() {
+        let msg = Message::method(None, None, "/", None, "Ping", &()).unwrap();
+        let time = UNIX_EPOCH + Duration::from_micros(1_600_000_000_123_456);
+
+        let mut writer = Writer::new(vec![]).unwrap();
+        writer.write_message_at(&msg, time).unwrap();
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len(), 24 + 16 + msg.as_bytes().len());
+        assert_eq!(&bytes[24 + 16..], msg.as_bytes());
+
+        let mut reader = Reader::new(Cursor::new(&bytes)).unwrap();
+        let (t, m) = reader.read_message().unwrap().unwrap();
+        assert_eq!(t, time);
+        assert_eq!(m.as_bytes(), msg.as_bytes());
+        assert!(reader.read_message().unwrap().is_none());
+
+        // Truncated records are errors, unlike the end of the capture
+        let res = Reader::new(Cursor::new(&bytes[..bytes.len() - 1]))
+            .unwrap()
+            .read_message();
+        assert!(matches!(res, Err(Error::Io(_))));
+
+        // Other link types are not D-Bus messages
+        let mut other = bytes.clone();
+        other[20..24].copy_from_slice(&1u32.to_ne_bytes());
+        assert!(matches!(Reader::new(Cursor::new(other)), Err(Error::Io(_))));
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn capture_monitor() {
+        let monitor =
+            MonitorConnection::new_session(&["type='method_call',member='GetId'"]).unwrap();
+        let capture = Writer::new(vec![]).unwrap().attach_monitor(&monitor);
+
+        let conn = Connection::new_session().unwrap();
+        let name = conn.unique_name().unwrap().to_owned();
+        crate::fdo::DBusProxy::new(&conn).unwrap().get_id().unwrap();
+        // Other clients of the bus may call `GetId` too
+        let is_ours =
+            |msg: &Message| msg.header().unwrap().sender().unwrap() == Some(name.as_str());
+        while !is_ours(&monitor.receive_message().unwrap().1) {}
+
+        let bytes = capture.detach().unwrap().into_inner();
+        let call = Reader::new(Cursor::new(bytes))
+            .unwrap()
+            .map(|r| r.unwrap().1)
+            .find(is_ours)
+            .unwrap();
+        assert_eq!(call.header().unwrap().member().unwrap(), Some("GetId"));
+    }
+}
@@ -1,8 +1,9 @@
+use enumflags2::BitFlags;
 use std::borrow::Cow;
 use std::convert::{TryFrom, TryInto};
 use zvariant::{OwnedValue, Value};
 
-use crate::{Connection, Error, Message, Result};
+use crate::{Connection, Error, Message, MessageFlags, Result};
 
 use crate::fdo::{self, IntrospectableProxy, PropertiesProxy};
 
@@ -44,9 +45,11 @@ use crate::fdo::{self, IntrospectableProxy, PropertiesProxy};
 /// - have any signal handling
 /// - cache properties
 /// - track the current name owner
-/// - prevent auto-launching
+/// - prevent auto-launching by default (it can be prevented for a single call, with
+///   [`call_method_with_flags`])
 ///
 /// [`dbus_proxy`]: attr.dbus_proxy.html
+/// [`call_method_with_flags`]: struct.Proxy.html#method.call_method_with_flags
 pub struct Proxy<'a> {
     conn: Connection,
     destination: Cow<'a, str>,
@@ -148,6 +151,40 @@ impl<'a> Proxy<'a> {
         }
     }
 
+    /// Call a method with the given header `flags`, and return the reply.
+    ///
+    /// Use this method instead of [`call_method`] to send the call with flags, such as
+    /// [`MessageFlags::NoAutoStart`]. If `flags` contains [`MessageFlags::NoReplyExpected`], the
+    /// call is only sent and `None` is returned, without waiting for a reply.
+    ///
+    /// [`call_method`]: struct.Proxy.html#method.call_method
+    /// [`MessageFlags::NoAutoStart`]: enum.MessageFlags.html#variant.NoAutoStart
+    /// [`MessageFlags::NoReplyExpected`]: enum.MessageFlags.html#variant.NoReplyExpected
+    pub fn call_method_with_flags<B>(
+        &self,
+        method_name: &str,
+        flags: BitFlags<MessageFlags>,
+        body: &B,
+    ) -> Result<Option<Message>>
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+    {
+        let reply = self.conn.call_method_with_flags(
+            Some(&self.destination),
+            &self.path,
+            Some(&self.interface),
+            method_name,
+            flags,
+            body,
+        )?;
+
+        Ok(reply.map(|mut reply| {
+            reply.disown_fds();
+
+            reply
+        }))
+    }
+
     /// Call a method and return the reply body.
     ///
     /// Use [`call_method`] instead if you need to deserialize the reply manually/separately.
@@ -160,4 +197,28 @@ impl<'a> Proxy<'a> {
     {
         Ok(self.call_method(method_name, body)?.body()?)
     }
+
+    /// Call a method with the given header `flags`, and return the reply body.
+    ///
+    /// Use [`call_method_with_flags`] instead if you need to deserialize the reply
+    /// manually/separately. `None` is returned if `flags` contains
+    /// [`MessageFlags::NoReplyExpected`].
+    ///
+    /// [`call_method_with_flags`]: struct.Proxy.html#method.call_method_with_flags
+    /// [`MessageFlags::NoReplyExpected`]: enum.MessageFlags.html#variant.NoReplyExpected
+    pub fn call_with_flags<B, R>(
+        &self,
+        method_name: &str,
+        flags: BitFlags<MessageFlags>,
+        body: &B,
+    ) -> Result<Option<R>>
+    where
+        B: serde::ser::Serialize + zvariant::Type,
+        R: serde::de::DeserializeOwned + zvariant::Type,
+    {
+        match self.call_method_with_flags(method_name, flags, body)? {
+            Some(reply) => Ok(Some(reply.body()?)),
+            None => Ok(None),
+        }
+    }
 }
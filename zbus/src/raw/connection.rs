@@ -80,6 +80,11 @@ impl<S: Socket> Connection<S> {
         Ok(())
     }
 
+    // Whether there are messages not yet completely written to the socket
+    pub(crate) fn has_pending_output(&self) -> bool {
+        !self.raw_out_buffer.is_empty() || !self.msg_out_buffer.is_empty()
+    }
+
     /// Enqueue a message to be sent out to the socket
     ///
     /// This method will *not* write anything to the socket, you need to call
@@ -95,6 +100,8 @@ impl<S: Socket> Connection<S> {
     ///
     /// If the socket is in non-blocking mode, it may read a partial message. In such case it
     /// will buffer it internally and try to complete it the next time you call `try_receive_message`.
+    ///
+    /// Once the peer closed the connection, an error of kind `UnexpectedEof` is returned.
     pub fn try_receive_message(&mut self) -> crate::Result<Message> {
         if self.msg_in_buffer.is_none() {
             // We don't have enough data to make a proper message header yet.
@@ -107,6 +114,9 @@ impl<S: Socket> Connection<S> {
                 let current_bytes = self.raw_in_buffer.len();
                 let mut buf = vec![0; MIN_MESSAGE_SIZE - current_bytes];
                 let (read, fds) = self.socket.recvmsg(&mut buf)?;
+                if read == 0 {
+                    return Err(eof().into());
+                }
                 self.raw_in_buffer.extend(&buf[..read]);
                 self.raw_in_fds.extend(fds);
             }
@@ -130,6 +140,9 @@ impl<S: Socket> Connection<S> {
                         // we need to read more data
                         let mut buf = vec![0; needed];
                         let (read, fds) = self.socket.recvmsg(&mut buf)?;
+                        if read == 0 {
+                            return Err(eof().into());
+                        }
                         msg.add_bytes(&buf[..read])?;
                         self.raw_in_fds.extend(fds);
                     }
@@ -147,6 +160,29 @@ impl<S: Socket> Connection<S> {
         Ok(msg)
     }
 
+    /// Close the connection
+    ///
+    /// This shuts the socket down, without flushing the outgoing buffer first: call `try_flush()`
+    /// beforehand so that pending messages are sent out.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.socket.close()
+    }
+
+    /// Erase the type of the underlying socket
+    pub(crate) fn into_boxed(self) -> Connection<Box<dyn Socket>>
+    where
+        S: 'static,
+    {
+        Connection {
+            socket: Box::new(self.socket),
+            raw_in_buffer: self.raw_in_buffer,
+            raw_in_fds: self.raw_in_fds,
+            msg_in_buffer: self.msg_in_buffer,
+            raw_out_buffer: self.raw_out_buffer,
+            msg_out_buffer: self.msg_out_buffer,
+        }
+    }
+
     /// Access the underlying socket
     ///
     /// This method is intended to provide access to the socket in order to register it
@@ -159,6 +195,13 @@ impl<S: Socket> Connection<S> {
     }
 }
 
+fn eof() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed by the peer",
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::Connection;
@@ -180,5 +223,11 @@ mod tests {
         let ret = conn1.try_receive_message().unwrap();
 
         assert_eq!(ret.to_string(), "Method call Test");
+
+        conn0.close().unwrap();
+        match conn1.try_receive_message() {
+            Err(crate::Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+            _ => panic!("expected an EOF error"),
+        }
     }
 }
@@ -1,9 +1,12 @@
 use std::io;
+use std::net::TcpStream;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 
 use nix::cmsg_space;
-use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use nix::sys::socket::{
+    recvmsg, sendmsg, shutdown, ControlMessage, ControlMessageOwned, MsgFlags, Shutdown,
+};
 use nix::sys::uio::IoVec;
 
 use crate::utils::FDS_MAX;
@@ -11,12 +14,16 @@ use crate::OwnedFd;
 
 /// Trait representing some transport layer over which the DBus protocol can be used
 ///
-/// The crate provides an implementation of it for std's `UnixStream` on unix platforms.
-/// You will want to implement this trait to integrate zbus with a async-runtime-aware
+/// The crate provides an implementation of it for std's `UnixStream` and `TcpStream` on unix
+/// platforms. You will want to implement this trait to integrate zbus with a async-runtime-aware
 /// implementation of the socket, for example.
-pub trait Socket {
+///
+/// Sockets need to be `Send`, so that a [`Connection`] can be shared between threads.
+///
+/// [`Connection`]: ../struct.Connection.html
+pub trait Socket: AsRawFd + Send {
     /// Whether this transport supports file descriptor passing
-    const SUPPORTS_FD_PASSING: bool;
+    fn can_pass_unix_fd(&self) -> bool;
 
     /// Attempt to receive a message from the socket
     ///
@@ -40,10 +47,25 @@ pub trait Socket {
     /// If the underlying transport does not support transmitting file descriptors, this
     /// will return `Err(ErrorKind::InvalidInput)`.
     fn sendmsg(&mut self, buffer: &[u8], fds: &[RawFd]) -> io::Result<usize>;
+
+    /// Close the socket
+    ///
+    /// Shut down both directions of the transport, so that the peer sees the connection closing
+    /// and pending reads return. The default implementation shuts the file descriptor down as a
+    /// socket.
+    fn close(&self) -> io::Result<()> {
+        shutdown(self.as_raw_fd(), Shutdown::Both).map_err(|e| {
+            e.as_errno()
+                .map(io::Error::from)
+                .unwrap_or_else(|| io::ErrorKind::Other.into())
+        })
+    }
 }
 
 impl Socket for UnixStream {
-    const SUPPORTS_FD_PASSING: bool = true;
+    fn can_pass_unix_fd(&self) -> bool {
+        true
+    }
 
     fn recvmsg(&mut self, buffer: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
         let iov = [IoVec::from_mut_slice(buffer)];
@@ -93,3 +115,50 @@ impl Socket for UnixStream {
         }
     }
 }
+
+impl Socket for TcpStream {
+    fn can_pass_unix_fd(&self) -> bool {
+        false
+    }
+
+    fn recvmsg(&mut self, buffer: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+        let read = io::Read::read(self, buffer)?;
+
+        Ok((read, vec![]))
+    }
+
+    fn sendmsg(&mut self, buffer: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        if !fds.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "fds cannot be sent with a tcp stream",
+            ));
+        }
+
+        io::Write::write(self, buffer)
+    }
+}
+
+impl Socket for Box<dyn Socket> {
+    fn can_pass_unix_fd(&self) -> bool {
+        (**self).can_pass_unix_fd()
+    }
+
+    fn recvmsg(&mut self, buffer: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+        (**self).recvmsg(buffer)
+    }
+
+    fn sendmsg(&mut self, buffer: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        (**self).sendmsg(buffer, fds)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        (**self).close()
+    }
+}
+
+impl AsRawFd for Box<dyn Socket> {
+    fn as_raw_fd(&self) -> RawFd {
+        (**self).as_raw_fd()
+    }
+}
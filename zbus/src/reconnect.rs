@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::sleep;
+use std::time::Duration;
+
+use enumflags2::BitFlags;
+
+use crate::connection::is_disconnected;
+use crate::fdo::{self, RequestNameFlags, RequestNameReply};
+use crate::{Connection, Message, MessageDirection, MessageFlags, MessageType, Result};
+
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const BUS_NAME: &str = "org.freedesktop.DBus";
+
+type NameChangedFn = Box<dyn FnMut(&str, &str) + Send>;
+
+#[derive(Debug, Clone)]
+enum Bus {
+    Session,
+    System,
+    Address(String),
+}
+
+impl Bus {
+    fn connect(&self) -> Result<Connection> {
+        match self {
+            Bus::Session => Connection::new_session(),
+            Bus::System => Connection::new_system(),
+            Bus::Address(address) => Connection::new_for_address(address, true),
+        }
+    }
+}
+
+// What was registered on the bus, to register it again after reconnecting
+#[derive(Debug, Default)]
+struct Registrations {
+    names: HashMap<String, BitFlags<RequestNameFlags>>,
+    match_rules: Vec<String>,
+}
+
+// A registration call to the bus
+#[derive(Debug)]
+enum Registration {
+    Name(String, BitFlags<RequestNameFlags>),
+    MatchRule(String),
+}
+
+// The registration calls made through a connection, waiting for their reply, by serial
+type PendingRegistrations = HashMap<u32, Registration>;
+
+impl Registrations {
+    // Keep track of the registration call `msg`, if it's such a call to the bus. Registrations are
+    // only recorded once successful, while unregistrations are recorded right away.
+    fn record_call(&mut self, pending: &mut PendingRegistrations, msg: &Message) -> Result<()> {
+        let h = msg.header()?;
+        if h.message_type()? != MessageType::MethodCall
+            || h.destination()? != Some(BUS_NAME)
+            || h.interface()? != Some(BUS_NAME)
+        {
+            return Ok(());
+        }
+
+        let registration = match h.member()? {
+            Some("RequestName") => {
+                let (name, flags): (&str, BitFlags<RequestNameFlags>) = msg.body()?;
+
+                Registration::Name(name.to_owned(), flags)
+            }
+            Some("AddMatch") => Registration::MatchRule(msg.body::<&str>()?.to_owned()),
+            Some("ReleaseName") => {
+                let name = msg.body::<&str>()?;
+                // The bus handles the calls in order, so a pending request is released as well
+                pending.retain(|_, r| !matches!(r, Registration::Name(n, _) if n == name));
+                self.names.remove(name);
+
+                return Ok(());
+            }
+            Some("RemoveMatch") => {
+                let rule = msg.body::<&str>()?;
+                let serial = pending
+                    .iter()
+                    .find(|(_, r)| matches!(r, Registration::MatchRule(r) if r == rule))
+                    .map(|(serial, _)| *serial);
+                match serial {
+                    Some(serial) => {
+                        pending.remove(&serial);
+                    }
+                    None => {
+                        if let Some(i) = self.match_rules.iter().position(|r| r == rule) {
+                            self.match_rules.remove(i);
+                        }
+                    }
+                }
+
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+
+        // Without a reply, there's no telling whether the call succeeded, so assume it did
+        if msg
+            .primary_header()?
+            .flags()
+            .contains(MessageFlags::NoReplyExpected)
+        {
+            self.register(registration);
+        } else {
+            pending.insert(msg.primary_header()?.serial_num(), registration);
+        }
+
+        Ok(())
+    }
+
+    // Record the pending registration `msg` replies to, if it succeeded
+    fn record_reply(&mut self, pending: &mut PendingRegistrations, msg: &Message) -> Result<()> {
+        let h = msg.header()?;
+        let message_type = h.message_type()?;
+        if message_type != MessageType::MethodReturn && message_type != MessageType::Error {
+            return Ok(());
+        }
+        let registration = match h.reply_serial()?.and_then(|s| pending.remove(&s)) {
+            Some(registration) => registration,
+            None => return Ok(()),
+        };
+
+        if message_type == MessageType::Error {
+            return Ok(());
+        }
+        // Neither owning the name nor queued for it
+        if let Registration::Name(_, _) = registration {
+            if msg.body::<RequestNameReply>()? == RequestNameReply::Exists {
+                return Ok(());
+            }
+        }
+        self.register(registration);
+
+        Ok(())
+    }
+
+    fn register(&mut self, registration: Registration) {
+        match registration {
+            Registration::Name(name, flags) => {
+                self.names.insert(name, flags);
+            }
+            Registration::MatchRule(rule) => self.match_rules.push(rule),
+        }
+    }
+}
+
+/// A bus connection that reconnects, once the bus goes away.
+///
+/// This wraps a [`Connection`] to a message bus, to survive a restart of the bus. Once the
+/// connection is closed, a new one is made: the handshake and the `Hello` call are run again, the
+/// well-known names successfully requested through [`fdo::DBusProxy::request_name`] (and not
+/// released since) are requested again, and the match rules successfully added through
+/// [`fdo::DBusProxy::add_match`] (and not removed since) are added again. Since the bus assigns a
+/// new unique name to the new connection, the handlers registered with [`on_unique_name_changed`]
+/// are then called.
+///
+/// [`receive_message`] takes care of reconnecting, retrying as long as needed. Otherwise, call
+/// [`reconnect`] yourself.
+///
+/// **Note:** the [`Proxy`] and [`ObjectServer`] instances, as well as the message filters, are
+/// bound to the connection they were created with. Use [`connection`] to get the current connection
+/// and create them again after reconnecting.
+///
+/// # Example
+///
+/// ```no_run
+///# use std::error::Error;
+///#
+/// use zbus::ReconnectingConnection;
+///
+/// let conn = ReconnectingConnection::new_session()?;
+/// conn.on_unique_name_changed(|old, new| println!("Reconnected: {} is now {}", old, new));
+/// loop {
+///     let msg = conn.receive_message()?;
+///     println!("Got message: {}", msg);
+/// }
+///# Ok::<_, Box<dyn Error + Send + Sync>>(())
+/// ```
+///
+/// [`Connection`]: struct.Connection.html
+/// [`fdo::DBusProxy::request_name`]: fdo/struct.DBusProxy.html#method.request_name
+/// [`fdo::DBusProxy::add_match`]: fdo/struct.DBusProxy.html#method.add_match
+/// [`on_unique_name_changed`]: struct.ReconnectingConnection.html#method.on_unique_name_changed
+/// [`receive_message`]: struct.ReconnectingConnection.html#method.receive_message
+/// [`reconnect`]: struct.ReconnectingConnection.html#method.reconnect
+/// [`connection`]: struct.ReconnectingConnection.html#method.connection
+/// [`Proxy`]: struct.Proxy.html
+/// [`ObjectServer`]: struct.ObjectServer.html
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ReconnectingConnection {
+    bus: Bus,
+    conn: Mutex<Connection>,
+    registrations: Arc<Mutex<Registrations>>,
+    retry_delay: Duration,
+    #[derivative(Debug = "ignore")]
+    name_changed_handlers: Mutex<Vec<NameChangedFn>>,
+}
+
+impl ReconnectingConnection {
+    /// Create a `ReconnectingConnection` to the session/user message bus.
+    pub fn new_session() -> Result<Self> {
+        Self::new(Bus::Session)
+    }
+
+    /// Create a `ReconnectingConnection` to the system-wide message bus.
+    pub fn new_system() -> Result<Self> {
+        Self::new(Bus::System)
+    }
+
+    /// Create a `ReconnectingConnection` to the message bus at the given D-Bus address.
+    pub fn new_for_address(address: &str) -> Result<Self> {
+        Self::new(Bus::Address(address.to_owned()))
+    }
+
+    fn new(bus: Bus) -> Result<Self> {
+        let conn = bus.connect()?;
+        let registrations = Arc::new(Mutex::new(Registrations::default()));
+        record_registrations(&conn, &registrations);
+
+        Ok(Self {
+            bus,
+            conn: Mutex::new(conn),
+            registrations,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            name_changed_handlers: Mutex::new(vec![]),
+        })
+    }
+
+    /// The delay between two reconnection attempts of [`receive_message`].
+    ///
+    /// [`receive_message`]: struct.ReconnectingConnection.html#method.receive_message
+    pub fn retry_delay(&self) -> Duration {
+        self.retry_delay
+    }
+
+    /// Set the delay between two reconnection attempts of [`receive_message`].
+    ///
+    /// The default delay is one second. Like [`Connection::set_max_queued`], this method takes
+    /// ownership of `self` so you can use the builder pattern to set the value.
+    ///
+    /// [`receive_message`]: struct.ReconnectingConnection.html#method.receive_message
+    /// [`Connection::set_max_queued`]: struct.Connection.html#method.set_max_queued
+    pub fn set_retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+
+        self
+    }
+
+    /// The current connection.
+    pub fn connection(&self) -> Connection {
+        self.conn().clone()
+    }
+
+    /// Call `handler` with the previous and the new unique name, once reconnected.
+    pub fn on_unique_name_changed<F>(&self, handler: F)
+    where
+        F: FnMut(&str, &str) + Send + 'static,
+    {
+        self.name_changed_handlers
+            .lock()
+            .expect("lock poisoned")
+            .push(Box::new(handler));
+    }
+
+    /// Receive a message, reconnecting as needed.
+    ///
+    /// Same as [`Connection::receive_message`], except that once the connection is closed, this
+    /// reconnects (trying again every [`retry_delay`] until it succeeds) and goes on receiving
+    /// messages from the new connection. The `org.freedesktop.DBus.Local.Disconnected` signal is
+    /// not returned.
+    ///
+    /// [`Connection::receive_message`]: struct.Connection.html#method.receive_message
+    /// [`retry_delay`]: struct.ReconnectingConnection.html#method.retry_delay
+    pub fn receive_message(&self) -> Result<Message> {
+        loop {
+            let conn = self.connection();
+            match conn.receive_message() {
+                Ok(msg) if !is_disconnected(&msg)? => return Ok(msg),
+                Err(e) if !conn.is_closed() => return Err(e),
+                _ => (),
+            }
+
+            while self.reconnect().is_err() {
+                sleep(self.retry_delay);
+            }
+        }
+    }
+
+    /// Reconnect to the bus.
+    ///
+    /// The current connection is closed, and replaced by a new one with the same settings, once
+    /// the names and match rules are registered again on the new connection. Failing to register
+    /// them again (such as when another peer took a name meanwhile) is not an error.
+    pub fn reconnect(&self) -> Result<()> {
+        let mut conn = self.conn();
+        // The connection is gone most of the time, so there's nothing to do about errors here
+        let _ = conn.close();
+
+        let new_conn = self
+            .bus
+            .connect()?
+            .set_max_queued(conn.max_queued())
+            .set_overflow_policy(conn.overflow_policy())
+            .set_default_timeout(conn.default_timeout());
+        self.register_again(&new_conn)?;
+        record_registrations(&new_conn, &self.registrations);
+
+        let old_conn = std::mem::replace(&mut *conn, new_conn.clone());
+        drop(conn);
+
+        if let (Some(old), Some(new)) = (old_conn.unique_name(), new_conn.unique_name()) {
+            if old != new {
+                let mut handlers = self.name_changed_handlers.lock().expect("lock poisoned");
+                for handler in handlers.iter_mut() {
+                    handler(old, new);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn register_again(&self, conn: &Connection) -> Result<()> {
+        let (names, match_rules) = {
+            let registrations = self.registrations.lock().expect("lock poisoned");
+            let names: Vec<_> = registrations
+                .names
+                .iter()
+                .map(|(name, flags)| (name.clone(), *flags))
+                .collect();
+
+            (names, registrations.match_rules.clone())
+        };
+
+        let proxy = fdo::DBusProxy::new(conn)?;
+        for (name, flags) in names {
+            let _ = proxy.request_name(&name, flags);
+        }
+        for rule in match_rules {
+            let _ = proxy.add_match(&rule);
+        }
+
+        Ok(())
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().expect("lock poisoned")
+    }
+}
+
+// Keep track of the names and match rules registered through `conn`
+fn record_registrations(conn: &Connection, registrations: &Arc<Mutex<Registrations>>) {
+    // Serials are only unique per connection, so are the pending registrations
+    let pending = Arc::new(Mutex::new(PendingRegistrations::new()));
+    {
+        let registrations = registrations.clone();
+        let pending = pending.clone();
+        conn.add_filter(MessageDirection::Outgoing, move |msg| {
+            let mut registrations = registrations.lock().expect("lock poisoned");
+            let _ = registrations.record_call(&mut pending.lock().expect("lock poisoned"), &msg);
+
+            Some(msg)
+        });
+    }
+    let registrations = registrations.clone();
+    conn.add_filter(MessageDirection::Incoming, move |msg| {
+        let mut registrations = registrations.lock().expect("lock poisoned");
+        let _ = registrations.record_reply(&mut pending.lock().expect("lock poisoned"), &msg);
+
+        Some(msg)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, sleep};
+    use std::time::Duration;
+
+    use enumflags2::BitFlags;
+    use ntest::timeout;
+
+    use crate::fdo::{self, RequestNameFlags, RequestNameReply};
+    use crate::{Connection, ReconnectingConnection};
+
+    // Run a private session bus at `address`, once it's listening
+    fn spawn_bus(address: &str) -> Child {
+        let mut bus = Command::new("dbus-daemon")
+            .args([
+                "--session",
+                "--nofork",
+                "--print-address",
+                "--address",
+                address,
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(bus.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+
+        bus
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn reconnect() {
+        let conn = ReconnectingConnection::new_session().unwrap();
+        let old_name = conn.connection().unique_name().unwrap().to_owned();
+        let changes = Arc::new(Mutex::new(vec![]));
+        {
+            let changes = changes.clone();
+            conn.on_unique_name_changed(move |old, new| {
+                changes
+                    .lock()
+                    .unwrap()
+                    .push((old.to_owned(), new.to_owned()));
+            });
+        }
+
+        let rule = "type='signal',interface='org.zbus.Reconnect'";
+        {
+            let c = conn.connection();
+            let proxy = fdo::DBusProxy::new(&c).unwrap();
+            proxy
+                .request_name("org.zbus.Reconnect", RequestNameFlags::DoNotQueue.into())
+                .unwrap();
+            proxy
+                .request_name("org.zbus.Released", BitFlags::empty())
+                .unwrap();
+            c.call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "ReleaseName",
+                &"org.zbus.Released",
+            )
+            .unwrap();
+            proxy.add_match(rule).unwrap();
+        }
+
+        conn.reconnect().unwrap();
+        assert!(!conn.connection().is_closed());
+        let new_name = conn.connection().unique_name().unwrap().to_owned();
+        assert_ne!(old_name, new_name);
+        assert_eq!(*changes.lock().unwrap(), vec![(old_name, new_name.clone())]);
+
+        let other = Connection::new_session().unwrap();
+        let proxy = fdo::DBusProxy::new(&other).unwrap();
+        assert_eq!(
+            proxy.get_name_owner("org.zbus.Reconnect").unwrap(),
+            new_name
+        );
+        assert!(!proxy.name_has_owner("org.zbus.Released").unwrap());
+
+        // The match rule is in place again, so the broadcast signal is received
+        other
+            .emit_signal(
+                None,
+                "/org/zbus/Reconnect",
+                "org.zbus.Reconnect",
+                "Test",
+                &(),
+            )
+            .unwrap();
+        loop {
+            let msg = conn.receive_message().unwrap();
+            if msg.header().unwrap().interface().unwrap() == Some("org.zbus.Reconnect") {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    #[timeout(10000)]
+    fn bus_restart() {
+        // An abstract socket goes away with the bus, so that it can be restarted at once
+        let address = format!("unix:abstract=/tmp/zbus-reconnect-{}", std::process::id());
+        let mut bus = spawn_bus(&address);
+        let conn = ReconnectingConnection::new_for_address(&address)
+            .unwrap()
+            .set_retry_delay(Duration::from_millis(10));
+
+        let rule = "type='signal',interface='org.zbus.Restart'";
+        let other = Connection::new_for_address(&address, true).unwrap();
+        fdo::DBusProxy::new(&other)
+            .unwrap()
+            .request_name("org.zbus.Taken", RequestNameFlags::DoNotQueue.into())
+            .unwrap();
+        {
+            let c = conn.connection();
+            let proxy = fdo::DBusProxy::new(&c).unwrap();
+            proxy
+                .request_name("org.zbus.Restart", BitFlags::empty())
+                .unwrap();
+            // Not recorded, since the name isn't acquired
+            assert_eq!(
+                proxy
+                    .request_name("org.zbus.Taken", RequestNameFlags::DoNotQueue.into())
+                    .unwrap(),
+                RequestNameReply::Exists
+            );
+            proxy.add_match(rule).unwrap();
+            // Not recorded, since the call fails
+            assert!(proxy.add_match("type='invalid'").is_err());
+        }
+
+        bus.kill().unwrap();
+        bus.wait().unwrap();
+        let mut bus = spawn_bus(&address);
+
+        let conn = Arc::new(conn);
+        let receiver = {
+            let conn = conn.clone();
+            thread::spawn(move || loop {
+                let msg = conn.receive_message().unwrap();
+                if msg.header().unwrap().interface().unwrap() == Some("org.zbus.Restart") {
+                    break;
+                }
+            })
+        };
+
+        let other = Connection::new_for_address(&address, true).unwrap();
+        let proxy = fdo::DBusProxy::new(&other).unwrap();
+        while !proxy.name_has_owner("org.zbus.Restart").unwrap() {
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            proxy.get_name_owner("org.zbus.Restart").unwrap(),
+            conn.connection().unique_name().unwrap()
+        );
+
+        // The match rule is in place again, so the broadcast signal is received
+        other
+            .emit_signal(None, "/org/zbus/Restart", "org.zbus.Restart", "Test", &())
+            .unwrap();
+        receiver.join().unwrap();
+        // The rules are added again after the names are requested, so they all were by now
+        assert!(!proxy.name_has_owner("org.zbus.Taken").unwrap());
+
+        bus.kill().unwrap();
+        bus.wait().unwrap();
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::Uid;
+
+use crate::handshake::{AuthPolicy, Authenticated, DefaultAuthPolicy, ServerHandshake};
+use crate::utils::poll_timeout;
+use crate::{Address, Connection, Error, Guid, Listener, Message, ObjectServer, Result};
+
+// The default authentication timeout of the reference implementation.
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A peer-to-peer D-Bus server, accepting clients on a listening socket.
+///
+/// The server binds an address, accepts the clients connecting to it and authenticates them, all
+/// with a single GUID. The handshakes are done in non-blocking mode, side by side, so that a slow
+/// or misbehaving client can not stall the others: clients failing the handshake, or not completing
+/// it within the [handshake timeout], are simply dropped.
+///
+/// Only the clients running as the same user as the server are allowed by default, like with the
+/// reference implementation. Use [`set_auth_policy`] to let others connect.
+///
+/// The authenticated clients can either be taken one by one with [`accept`], or be served a
+/// shared object tree with [`try_handle_next`].
+///
+/// # Examples
+///
+/// Serving the same objects to all clients:
+///
+/// ```no_run
+///# use std::error::Error;
+///# use std::convert::TryInto;
+/// use zbus::{dbus_interface, ObjectServer, Server};
+///
+/// struct Counter(u32);
+///
+/// #[dbus_interface(name = "org.zbus.Counter")]
+/// impl Counter {
+///     fn next(&mut self) -> u32 {
+///         self.0 += 1;
+///         self.0
+///     }
+/// }
+///
+/// let mut server = Server::bind("unix:tmpdir=/tmp")?;
+/// println!("Listening on {}", server.address());
+///
+/// let first = server.accept()?;
+/// let mut object_server = ObjectServer::new(&first);
+/// object_server.at(&"/org/zbus/Counter".try_into()?, Counter(0))?;
+/// server.serve(first)?;
+///
+/// loop {
+///     if let Err(err) = server.try_handle_next(&mut object_server) {
+///         eprintln!("{}", err);
+///     }
+/// }
+///# Ok::<_, Box<dyn Error + Send + Sync>>(())
+/// ```
+///
+/// [handshake timeout]: struct.Server.html#method.set_handshake_timeout
+/// [`set_auth_policy`]: struct.Server.html#method.set_auth_policy
+/// [`accept`]: struct.Server.html#method.accept
+/// [`try_handle_next`]: struct.Server.html#method.try_handle_next
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct Server {
+    listener: Listener,
+    guid: Guid,
+    #[derivative(Debug = "ignore")]
+    policy: Arc<dyn AuthPolicy>,
+    handshake_timeout: Duration,
+    // The handshakes in progress, along with their deadline
+    handshakes: Vec<(ServerHandshake<UnixStream>, Instant)>,
+    // Authenticated clients, not yet taken by `accept`
+    accepted: VecDeque<Authenticated<UnixStream>>,
+    // Clients served by `try_handle_next`
+    clients: Vec<Connection>,
+}
+
+impl Server {
+    /// Bind a server on the given [D-Bus address].
+    ///
+    /// The same addresses as for [`Address::listen`] are supported. If the address has no `guid`
+    /// option, a new GUID is generated for the server, and added to its [`address`].
+    ///
+    /// [D-Bus address]: https://dbus.freedesktop.org/doc/dbus-specification.html#addresses
+    /// [`Address::listen`]: struct.Address.html#method.listen
+    /// [`address`]: struct.Server.html#method.address
+    pub fn bind(address: &str) -> Result<Self> {
+        let mut address = Address::from_str(address)?;
+        let guid = match address.guid()? {
+            Some(guid) => guid,
+            None => {
+                let guid = Guid::generate();
+                address = address
+                    .options()
+                    .fold(Address::builder(address.transport()), |builder, (k, v)| {
+                        builder.set(k, v)
+                    })
+                    .guid(&guid)
+                    .build()?;
+
+                guid
+            }
+        };
+        let listener = address.listen()?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Server {
+            listener,
+            guid,
+            policy: Arc::new(DefaultAuthPolicy::new().allow_uid(Uid::current().as_raw())),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshakes: vec![],
+            accepted: VecDeque::new(),
+            clients: vec![],
+        })
+    }
+
+    /// The address clients can connect to, including the GUID of the server.
+    pub fn address(&self) -> &Address {
+        self.listener.address()
+    }
+
+    /// The GUID of the server.
+    pub fn guid(&self) -> &Guid {
+        &self.guid
+    }
+
+    /// Set the policy deciding which clients are allowed to connect.
+    ///
+    /// By default, a [`DefaultAuthPolicy`] only allowing the uid of the server is used, since the
+    /// server socket may be reachable by other users. To let any user connect, set a
+    /// `DefaultAuthPolicy::new()`; to allow more users, add their uid or gid to the policy. The
+    /// policy applies to the clients accepted from now on.
+    ///
+    /// [`DefaultAuthPolicy`]: handshake/struct.DefaultAuthPolicy.html
+    pub fn set_auth_policy(&mut self, policy: Arc<dyn AuthPolicy>) {
+        self.policy = policy;
+    }
+
+    /// Set how long clients have to complete the handshake before being dropped.
+    ///
+    /// The default is 30 seconds, like with the reference implementation. The timeout applies to
+    /// the clients accepted from now on.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Wait for the next client to be authenticated, and return the connection to it.
+    ///
+    /// Clients connecting in the meantime are authenticated as well, so they may be returned by
+    /// the next calls right away. The returned connection is in blocking mode.
+    pub fn accept(&mut self) -> Result<Connection> {
+        loop {
+            if let Some(auth) = self.accepted.pop_front() {
+                return connect(auth, false);
+            }
+            self.poll(false)?;
+        }
+    }
+
+    /// Add `conn` to the connections served by [`try_handle_next`].
+    ///
+    /// This is typically used for a connection returned by [`accept`], that was needed to create
+    /// the [`ObjectServer`]. The connection is switched to non-blocking mode.
+    ///
+    /// [`try_handle_next`]: struct.Server.html#method.try_handle_next
+    /// [`accept`]: struct.Server.html#method.accept
+    /// [`ObjectServer`]: struct.ObjectServer.html
+    pub fn serve(&mut self, conn: Connection) -> Result<()> {
+        let fd = conn.as_raw_fd();
+        let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+        fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+        self.clients.push(conn);
+
+        Ok(())
+    }
+
+    /// Accept clients, then receive and handle the next message from any of them.
+    ///
+    /// Newly authenticated clients are served along the others, as well as those not yet taken by
+    /// [`accept`]. Method calls are dispatched to `object_server`, and replied to on the
+    /// connection of the caller. If the message was handled by an interface, it returns `Ok(None)`.
+    /// If not, it returns the received message, along with the connection it was received on.
+    ///
+    /// The served connections are in non-blocking mode: messages are only handled once they were
+    /// completely received, and replies are written as the clients read them, so that a slow
+    /// client can not stall the others.
+    ///
+    /// Clients that closed the connection are no longer served, once the local `Disconnected`
+    /// signal of their connection has been returned. Clients whose connection could not be set up
+    /// are dropped, without affecting the others.
+    ///
+    /// Returns an error if the message is malformed or an error occured. The connection to the
+    /// client the message was received from is dropped if receiving it failed.
+    ///
+    /// [`accept`]: struct.Server.html#method.accept
+    pub fn try_handle_next(
+        &mut self,
+        object_server: &mut ObjectServer<'_>,
+    ) -> Result<Option<(Connection, Message)>> {
+        let (conn, msg) = loop {
+            while let Some(auth) = self.accepted.pop_front() {
+                // Only the failing client is dropped, as for the handshakes
+                if let Ok(conn) = connect(auth, true) {
+                    self.clients.push(conn);
+                }
+            }
+
+            // Messages already read from the socket wouldn't wake up `poll`
+            let ready = match self
+                .clients
+                .iter()
+                .position(Connection::has_queued_messages)
+            {
+                Some(i) => vec![i],
+                None => self.poll(true)?,
+            };
+            if let Some(received) = self.receive(&ready)? {
+                break received;
+            }
+        };
+
+        if !object_server.dispatch_message_on(&conn, &msg)? {
+            return Ok(Some((conn, msg)));
+        }
+
+        Ok(None)
+    }
+
+    // Receive the next message from the first of the `ready` served clients that has a complete
+    // one, flushing their pending replies on the way.
+    fn receive(&mut self, ready: &[usize]) -> Result<Option<(Connection, Message)>> {
+        for &i in ready {
+            let conn = &self.clients[i];
+            // Errors other than `WouldBlock` close the connection, and are reported when receiving
+            let _ = conn.flush();
+            match conn.receive_message() {
+                Ok(msg) => {
+                    // Move the client to the back, so that a busy client can not starve the others
+                    let conn = self.clients.remove(i);
+                    if !conn.is_closed() {
+                        self.clients.push(conn.clone());
+                    }
+
+                    return Ok(Some((conn, msg)));
+                }
+                // Only part of a message has been received so far
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => {
+                    self.clients.remove(i);
+
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Wait for activity on the listener, the handshakes and, if `serve` is set, on the served
+    // clients. New clients are started a handshake, authenticated clients are added to `accepted`,
+    // handshakes past their deadline are dropped, and the indices of the served clients that are
+    // ready are returned.
+    fn poll(&mut self, serve: bool) -> Result<Vec<usize>> {
+        let mut fds = vec![PollFd::new(self.listener.as_raw_fd(), PollFlags::POLLIN)];
+        fds.extend(
+            self.handshakes
+                .iter()
+                .map(|(h, _)| PollFd::new(h.socket().as_raw_fd(), h.wait_flags())),
+        );
+        if serve {
+            fds.extend(self.clients.iter().map(|c| {
+                let mut flags = PollFlags::POLLIN;
+                if c.has_pending_output() {
+                    flags |= PollFlags::POLLOUT;
+                }
+
+                PollFd::new(c.as_raw_fd(), flags)
+            }));
+        }
+        // Wake up in time to drop the first handshake to expire
+        let deadline = self.handshakes.iter().map(|(_, deadline)| *deadline).min();
+        let timeout = poll_timeout(deadline);
+        loop {
+            match poll(&mut fds, timeout) {
+                Ok(_) => break,
+                Err(nix::Error::Sys(Errno::EINTR)) | Err(nix::Error::Sys(Errno::EAGAIN)) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let is_ready = |fd: &PollFd| fd.revents().is_some_and(|r| !r.is_empty());
+
+        let now = Instant::now();
+        let handshakes = std::mem::take(&mut self.handshakes);
+        let n_handshakes = handshakes.len();
+        for ((mut handshake, deadline), fd) in handshakes.into_iter().zip(&fds[1..]) {
+            // The client took too long, drop it
+            if deadline <= now {
+                continue;
+            }
+            if !is_ready(fd) {
+                self.handshakes.push((handshake, deadline));
+                continue;
+            }
+            match handshake.advance_handshake() {
+                Ok(()) => {
+                    if let Ok(auth) = handshake.try_finish() {
+                        self.accepted.push_back(auth);
+                    }
+                }
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.handshakes.push((handshake, deadline));
+                }
+                // The client failed the handshake, drop it
+                Err(_) => (),
+            }
+        }
+
+        if is_ready(&fds[0]) {
+            loop {
+                let stream = match self.listener.accept() {
+                    Ok(stream) => stream,
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                };
+                // Drop the client if its socket can't be set up
+                if stream.set_nonblocking(true).is_err() {
+                    continue;
+                }
+                let mut handshake =
+                    ServerHandshake::with_peer_credentials(stream, self.guid.clone());
+                handshake.set_auth_policy(self.policy.clone());
+                self.handshakes
+                    .push((handshake, now + self.handshake_timeout));
+            }
+        }
+
+        Ok(fds[1 + n_handshakes..]
+            .iter()
+            .enumerate()
+            .filter(|(_, fd)| is_ready(fd))
+            .map(|(i, _)| i)
+            .collect())
+    }
+}
+
+// Turn an authenticated client into a connection, in blocking mode unless `nonblocking` is set
+fn connect(auth: Authenticated<UnixStream>, nonblocking: bool) -> Result<Connection> {
+    auth.conn.socket().set_nonblocking(nonblocking)?;
+
+    Ok(Connection::new_authenticated_unix(auth))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use ntest::timeout;
+
+    use crate::{dbus_interface, ConnectionBuilder, Message, ObjectServer, Server};
+
+    struct Counter(u32);
+
+    #[dbus_interface(name = "org.zbus.Counter")]
+    impl Counter {
+        fn next(&mut self) -> u32 {
+            self.0 += 1;
+            self.0
+        }
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn accept() {
+        let mut server = Server::bind("unix:dir=/tmp").unwrap();
+        server.set_handshake_timeout(Duration::from_millis(100));
+        let address = server.address().to_string();
+        assert_eq!(
+            server.address().guid().unwrap().as_ref(),
+            Some(server.guid())
+        );
+
+        let path = server.address().get("path").unwrap().to_owned();
+        let client_thread = thread::spawn(move || {
+            // A client stalling in the middle of the handshake must not block the others
+            let mut stalled = UnixStream::connect(path).unwrap();
+            stalled.write_all(b"\0AUTH").unwrap();
+
+            let conn = ConnectionBuilder::address(&address).p2p().build().unwrap();
+            let reply = conn
+                .call_method(None, "/", Some("org.zbus.Test"), "Ping", &())
+                .unwrap();
+            assert_eq!(reply.body::<&str>().unwrap(), "Pong");
+
+            // The stalled client gets dropped once its handshake timed out
+            assert_eq!(stalled.read(&mut [0; 1]).unwrap(), 0);
+            ConnectionBuilder::address(&address).p2p().build().unwrap();
+        });
+
+        let conn = server.accept().unwrap();
+        assert_eq!(conn.server_guid(), server.guid().as_str());
+        let call = conn.receive_message().unwrap();
+        conn.reply(&call, &"Pong").unwrap();
+        server.accept().unwrap();
+        assert!(server.handshakes.is_empty());
+
+        client_thread.join().expect("failed to join client thread");
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn serve() {
+        let mut server = Server::bind("unix:tmpdir=/tmp").unwrap();
+        let address = server.address().to_string();
+
+        // A client sending half a message must not stall the others, until they are done
+        let (done_tx, done_rx) = mpsc::channel();
+        let stalled_thread = {
+            let address = address.clone();
+            thread::spawn(move || {
+                let conn = ConnectionBuilder::address(&address).p2p().build().unwrap();
+                let msg = Message::method(
+                    None,
+                    None,
+                    "/org/zbus/Counter",
+                    Some("org.zbus.Counter"),
+                    "Next",
+                    &(),
+                )
+                .unwrap();
+                let bytes = msg.as_bytes();
+                nix::unistd::write(conn.as_raw_fd(), &bytes[..bytes.len() / 2]).unwrap();
+                for _ in 0..3 {
+                    done_rx.recv().unwrap();
+                }
+            })
+        };
+
+        let client_threads: Vec<_> = (0..3)
+            .map(|_| {
+                let address = address.clone();
+                let done_tx = done_tx.clone();
+                thread::spawn(move || {
+                    let conn = ConnectionBuilder::address(&address).p2p().build().unwrap();
+                    let proxy = crate::Proxy::new(
+                        &conn,
+                        "org.zbus.Counter",
+                        "/org/zbus/Counter",
+                        "org.zbus.Counter",
+                    )
+                    .unwrap();
+                    let a: u32 = proxy.call("Next", &()).unwrap();
+                    let b: u32 = proxy.call("Next", &()).unwrap();
+                    assert!(a < b);
+                    done_tx.send(()).unwrap();
+                })
+            })
+            .collect();
+
+        let first = server.accept().unwrap();
+        let mut object_server = ObjectServer::new(&first);
+        object_server
+            .at(&"/org/zbus/Counter".try_into().unwrap(), Counter(0))
+            .unwrap();
+        server.serve(first).unwrap();
+
+        // Each client calls `Next` twice, then disconnects, followed by the stalled client
+        let mut calls = 0;
+        let mut disconnections = 0;
+        while disconnections < 4 {
+            match server.try_handle_next(&mut object_server).unwrap() {
+                None => calls += 1,
+                Some((conn, msg)) => {
+                    assert!(conn.is_closed());
+                    assert_eq!(
+                        msg.header().unwrap().member().unwrap(),
+                        Some("Disconnected")
+                    );
+                    disconnections += 1;
+                }
+            }
+        }
+        assert_eq!(calls, 6);
+        object_server
+            .with(&"/org/zbus/Counter".try_into().unwrap(), |c: &Counter| {
+                assert_eq!(c.0, 6);
+                Ok(())
+            })
+            .unwrap();
+
+        stalled_thread.join().expect("failed to join client thread");
+        for t in client_threads {
+            t.join().expect("failed to join client thread");
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use nix::errno::Errno;
 use nix::poll::{poll, PollFd, PollFlags};
+use std::convert::TryFrom;
 use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
 
 pub(crate) const FDS_MAX: usize = 1024; // this is hardcoded in sdbus - nothing in the spec
 
@@ -15,10 +17,39 @@ pub(crate) fn padding_for_n_bytes(value: usize, align: usize) -> usize {
 }
 
 pub(crate) fn wait_on(fd: RawFd, flags: PollFlags) -> std::io::Result<()> {
+    poll_fd(fd, flags, None).map(|_| ())
+}
+
+// Like `wait_on`, but giving up after `timeout`. Returns whether `fd` is ready.
+pub(crate) fn wait_on_timeout(
+    fd: RawFd,
+    flags: PollFlags,
+    timeout: Duration,
+) -> std::io::Result<bool> {
+    // Wait forever if the deadline is out of reach
+    poll_fd(fd, flags, Instant::now().checked_add(timeout))
+}
+
+// The `poll` timeout to wake up at `deadline` (or never if `None`), rounded up to the millisecond
+pub(crate) fn poll_timeout(deadline: Option<Instant>) -> i32 {
+    match deadline {
+        Some(deadline) => {
+            let us = deadline
+                .saturating_duration_since(Instant::now())
+                .as_micros()
+                + 999;
+
+            i32::try_from(us / 1000).unwrap_or(i32::MAX)
+        }
+        None => -1,
+    }
+}
+
+fn poll_fd(fd: RawFd, flags: PollFlags, deadline: Option<Instant>) -> std::io::Result<bool> {
     let pollfd = PollFd::new(fd, flags);
     loop {
-        match poll(&mut [pollfd], -1) {
-            Ok(_) => break,
+        match poll(&mut [pollfd], poll_timeout(deadline)) {
+            Ok(n) => return Ok(n > 0),
             Err(nix::Error::Sys(e)) => {
                 if e == Errno::EAGAIN || e == Errno::EINTR {
                     // we got interupted, try polling again
@@ -35,5 +66,4 @@ pub(crate) fn wait_on(fd: RawFd, flags: PollFlags) -> std::io::Result<()> {
             }
         }
     }
-    Ok(())
 }